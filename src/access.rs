@@ -0,0 +1,186 @@
+//! Access list deciding which clients may connect and which paths they may
+//! request.
+//!
+//! One rule per line, `#` starts a comment:
+//!
+//! ```text
+//! deny  198.51.100.0/24
+//! allow 203.0.113.0/24 /admin
+//! deny  all            /admin
+//! ```
+//!
+//! Rules are tried in order and the first one matching the client (and the
+//! path, when the rule has one) decides. Clients no rule matches are allowed.
+
+use std::net::IpAddr;
+
+use crate::cidr::Cidr;
+use crate::fileutils::{normalize_path, path_matches};
+
+
+#[derive(Debug, Clone)]
+struct Rule
+{
+    allow: bool,
+    /// `None` for `all`.
+    cidr: Option<Cidr>,
+    /// Path and everything below it, or a glob when it contains `*`. `None`
+    /// for every path.
+    path: Option<String>,
+}
+
+impl Rule
+{
+    fn covers(&self, address: IpAddr) -> bool
+    {
+        self.cidr.is_none_or(|cidr| cidr.contains(address))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessList
+{
+    rules: Vec<Rule>,
+}
+
+impl AccessList
+{
+    /// Parse the rules in `source`, errors come with their line number.
+    pub fn parse(source: &str) -> Result<AccessList, (usize, String)>
+    {
+        let mut rules = Vec::new();
+
+        for (index, line) in source.lines().enumerate()
+        {
+            let line_number = index + 1;
+            let line = match line.find('#')
+            {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (action, address, path) = match fields.as_slice()
+            {
+                [] => continue,
+                [action, address] => (*action, *address, None),
+                [action, address, path] => (*action, *address, Some(path.to_string())),
+                _ => return Err((line_number, String::from("expected \"allow|deny ADDRESS [PATH]\""))),
+            };
+
+            let allow = match action
+            {
+                "allow" => true,
+                "deny" => false,
+                _ => return Err((line_number, format!("unknown action \"{}\", expected \"allow\" or \"deny\"", action))),
+            };
+
+            let cidr = match address
+            {
+                "all" => None,
+                _ => Some(Cidr::parse(address).map_err(|e| (line_number, e))?),
+            };
+
+            if path.as_ref().is_some_and(|path| !path.starts_with('/'))
+            {
+                return Err((line_number, String::from("path must start with \"/\"")));
+            }
+
+            rules.push(Rule { allow, cidr, path });
+        }
+
+        Ok(AccessList { rules })
+    }
+
+    /// Whether `address` may connect at all, only rules without a path apply.
+    pub fn allows_connection(&self, address: IpAddr) -> bool
+    {
+        self.rules.iter()
+            .find(|rule| rule.path.is_none() && rule.covers(address))
+            .is_none_or(|rule| rule.allow)
+    }
+
+    /// Whether `address` may request `path`.
+    pub fn allows(&self, address: IpAddr, path: &str) -> bool
+    {
+        // "/%61dmin" and "/x/../admin" are "/admin" as well
+        let path = normalize_path(path).unwrap_or_else(|| path.to_string());
+
+        self.rules.iter()
+            .find(|rule| rule.covers(address) && rule.path.as_ref().is_none_or(|pattern| path_matches(pattern, &path)))
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+
+    fn error(source: &str) -> (usize, String)
+    {
+        AccessList::parse(source).unwrap_err()
+    }
+
+    #[test]
+    fn malformed_rules_are_refused_with_their_line()
+    {
+        assert_eq!(error("# comment\n\nallow\n"), (3, String::from("expected \"allow|deny ADDRESS [PATH]\"")));
+        assert_eq!(error("allow all /a /b"), (1, String::from("expected \"allow|deny ADDRESS [PATH]\"")));
+        assert_eq!(error("allow all\npermit all\n"), (2, String::from("unknown action \"permit\", expected \"allow\" or \"deny\"")));
+        assert_eq!(error("deny 10.0.0.0/33").0, 1);
+        assert_eq!(error("deny example.com").0, 1);
+        assert_eq!(error("deny all admin"), (1, String::from("path must start with \"/\"")));
+        assert!(AccessList::parse("deny all # everyone\n   \n").is_ok());
+    }
+
+    #[test]
+    fn the_first_matching_rule_decides()
+    {
+        let access = AccessList::parse("allow 192.0.2.1\ndeny 192.0.2.0/24\n").unwrap();
+        assert!(access.allows_connection(CLIENT));
+        assert!(!access.allows_connection(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))));
+        assert!(access.allows_connection(OTHER));
+
+        let access = AccessList::parse("deny 192.0.2.0/24\nallow 192.0.2.1\n").unwrap();
+        assert!(!access.allows_connection(CLIENT));
+
+        let access = AccessList::parse("allow 192.0.2.1\ndeny all\n").unwrap();
+        assert!(access.allows(CLIENT, "/"));
+        assert!(!access.allows(OTHER, "/"));
+    }
+
+    #[test]
+    fn ipv6_and_mapped_addresses_are_matched()
+    {
+        let access = AccessList::parse("deny 2001:db8::/32\ndeny 192.0.2.1\n").unwrap();
+        assert!(!access.allows_connection(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 3, 4, 5, 6))));
+        assert!(access.allows_connection(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb9, 0, 0, 0, 0, 0, 1))));
+        assert!(!access.allows_connection(IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped())));
+        assert!(access.allows_connection(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+
+    #[test]
+    fn path_rules_apply_to_their_paths_only()
+    {
+        let access = AccessList::parse("allow 192.0.2.0/24 /admin\ndeny all /admin\ndeny all /private/*.txt\n").unwrap();
+
+        // Path rules never refuse a connection
+        assert!(access.allows_connection(OTHER));
+
+        assert!(access.allows(CLIENT, "/admin/panel"));
+        assert!(!access.allows(OTHER, "/admin"));
+        assert!(!access.allows(OTHER, "/admin/panel"));
+        assert!(access.allows(OTHER, "/administrator"));
+        assert!(!access.allows(OTHER, "/%61dmin/panel"));
+        assert!(!access.allows(OTHER, "/public/../admin"));
+
+        assert!(!access.allows(CLIENT, "/private/notes.txt"));
+        assert!(access.allows(CLIENT, "/private/notes.html"));
+    }
+}
@@ -0,0 +1,457 @@
+//! Temporary bans for clients that keep misbehaving.
+//!
+//! Every offense is a strike. A client collecting `strikes` of them within
+//! `find_time` is banned for `ban_time`, each further ban lasts twice as long
+//! as the previous one, up to `max_ban_time`. `SIGUSR2` lifts every ban.
+//!
+//! Bans can be written to a file, one `ADDRESS UNTIL BANS` line each (`UNTIL`
+//! in seconds since the epoch), so they survive a restart. Editing that file
+//! and sending `SIGHUP` lifts bans.
+
+use std::
+{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt,
+    fs,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::cidr;
+use crate::config::BanConfig;
+use crate::logger::Logger;
+use crate::ratelimit::{Capacity, Table};
+
+
+/// Clients with strikes tracked, past it the least recent ones are forgotten.
+const MAX_TRACKED: usize = 4096;
+
+/// Longest a ban lasts whatever `max_ban_time` says, which keeps ban times
+/// far from the limits of `SystemTime`.
+const LONGEST_BAN: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Offense
+{
+    RateLimited,
+    /// A path climbing above the served root.
+    PathTraversal,
+    MalformedRequest,
+}
+
+impl fmt::Display for Offense
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Offense::RateLimited => write!(f, "exceeding the rate limit"),
+            Offense::PathTraversal => write!(f, "path traversal"),
+            Offense::MalformedRequest => write!(f, "malformed requests"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ban
+{
+    until: SystemTime,
+    /// Bans of the client so far, this one included.
+    count: u32,
+}
+
+struct State
+{
+    settings: BanConfig,
+    ipv6_prefix: u8,
+    bans: HashMap<IpAddr, Ban>,
+    strikes: Table<Vec<Instant>>,
+    /// Whether the ban file holds `bans`, false after writing it failed.
+    saved: bool,
+}
+
+pub struct BanList
+{
+    state: Mutex<State>,
+}
+
+impl BanList
+{
+    pub fn new(settings: &BanConfig, ipv6_prefix: u8) -> BanList
+    {
+        let mut state = State { settings: settings.clone(), ipv6_prefix, bans: HashMap::new(), strikes: Table::new(strikes_capacity(settings)), saved: true };
+
+        // Bans are off, like after a reload that turns them off
+        if settings.strikes == 0
+        {
+            return BanList { state: Mutex::new(state) };
+        }
+
+        if let Some(bans) = settings.file.as_deref().and_then(|path| load(path, settings, ipv6_prefix))
+        {
+            state.bans = bans;
+        }
+        BanList { state: Mutex::new(state) }
+    }
+
+    /// Apply new settings and read the ban file again, bans removed from it
+    /// are lifted.
+    ///
+    /// Bans the file misses because writing it failed, or because it is not
+    /// the same file, are kept and written to it.
+    pub fn reconfigure(&self, settings: &BanConfig, ipv6_prefix: u8)
+    {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        let same_file = state.settings.file == settings.file;
+        state.settings = settings.clone();
+        state.ipv6_prefix = ipv6_prefix;
+        state.strikes.set_capacity(strikes_capacity(settings));
+
+        if settings.strikes == 0
+        {
+            state.bans.clear();
+            state.strikes.clear();
+            return;
+        }
+
+        let path = match &settings.file
+        {
+            Some(path) => path,
+            None => return,
+        };
+        let mut loaded = match load(path, settings, ipv6_prefix)
+        {
+            Some(loaded) => loaded,
+            None => return,
+        };
+
+        if same_file && state.saved
+        {
+            state.bans = loaded;
+            return;
+        }
+
+        for (address, ban) in state.bans.drain()
+        {
+            let address = cidr::client_key(address, ipv6_prefix);
+            if loaded.get(&address).is_none_or(|existing| existing.until < ban.until)
+            {
+                loaded.insert(address, ban);
+            }
+        }
+        state.bans = loaded;
+        state.saved = save(path, &state.bans);
+    }
+
+    /// Lift every ban and forget the strikes, returns how many bans were
+    /// still running.
+    pub fn lift_all(&self) -> usize
+    {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        let now = SystemTime::now();
+        let lifted = state.bans.values().filter(|ban| ban.until > now).count();
+        state.bans.clear();
+        state.strikes.clear();
+
+        if let Some(path) = state.settings.file.clone()
+        {
+            state.saved = save(&path, &state.bans);
+        }
+        lifted
+    }
+
+    /// Time left on the ban of `address`, if it is banned.
+    pub fn banned_for(&self, address: IpAddr) -> Option<Duration>
+    {
+        let state = self.state.lock().expect("Mutex poisoned");
+        let ban = state.bans.get(&cidr::client_key(address, state.ipv6_prefix))?;
+        ban.until.duration_since(SystemTime::now()).ok()
+    }
+
+    /// Record an offense of `address`, banning it when it has collected
+    /// enough of them.
+    pub fn offense(&self, address: IpAddr, offense: Offense)
+    {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        if state.settings.strikes == 0
+        {
+            return;
+        }
+
+        let key = cidr::client_key(address, state.ipv6_prefix);
+        let now = Instant::now();
+        let settings = state.settings.clone();
+        let max_ban_time = settings.max_ban_time.min(LONGEST_BAN);
+
+        let strikes = state.strikes.touch(key, now, Vec::new);
+        strikes.retain(|strike| now.duration_since(*strike) < settings.find_time);
+        strikes.push(now);
+        if strikes.len() < settings.strikes as usize
+        {
+            return;
+        }
+        state.strikes.remove(key);
+
+        // Bans escalate, unless the previous one is long over
+        let system_now = SystemTime::now();
+        let previous = match state.bans.get(&key)
+        {
+            Some(ban) if still_counts(ban, max_ban_time, system_now) => ban.count,
+            _ => 0,
+        };
+        let duration = settings.ban_time.saturating_mul(2u32.saturating_pow(previous)).min(max_ban_time);
+        let until = match system_now.checked_add(duration)
+        {
+            Some(until) => until,
+            None => return,
+        };
+        state.bans.insert(key, Ban { until, count: previous + 1 });
+
+        Logger::printmsg(Logger::Info, format!("Banned {} for {}s for {} (ban #{})", key, duration.as_secs(), offense, previous + 1));
+
+        state.bans.retain(|_, ban| still_counts(ban, max_ban_time, system_now));
+        if let Some(path) = &settings.file
+        {
+            state.saved = save(path, &state.bans);
+        }
+    }
+
+    /// Active bans with their remaining time, longest first.
+    pub fn active(&self) -> Vec<(IpAddr, Duration)>
+    {
+        let state = self.state.lock().expect("Mutex poisoned");
+        let now = SystemTime::now();
+        let mut active: Vec<(IpAddr, Duration)> = state.bans.iter()
+            .filter_map(|(address, ban)| ban.until.duration_since(now).ok().map(|left| (*address, left)))
+            .collect();
+        active.sort_by_key(|(_, left)| Reverse(*left));
+        active
+    }
+}
+
+/// Whether `ban` is running or recent enough for the next one to escalate.
+fn still_counts(ban: &Ban, max_ban_time: Duration, now: SystemTime) -> bool
+{
+    ban.until.checked_add(max_ban_time).is_none_or(|end| end > now)
+}
+
+/// Strikes are forgotten once none is left within `find_time`.
+fn strikes_capacity(settings: &BanConfig) -> Capacity
+{
+    Capacity { max_clients: MAX_TRACKED, idle: settings.find_time }
+}
+
+/// Bans in the file at `path`, `None` when it cannot be read.
+///
+/// Addresses are grouped like the bans taken at runtime, and bans running
+/// longer than `max_ban_time` from now are cut down to it.
+fn load(path: &str, settings: &BanConfig, ipv6_prefix: u8) -> Option<HashMap<IpAddr, Ban>>
+{
+    let latest = SystemTime::now().checked_add(settings.max_ban_time.min(LONGEST_BAN));
+
+    let source = match fs::read_to_string(path)
+    {
+        Ok(source) => source,
+        Err(e) =>
+        {
+            // Missing until the first ban
+            if e.kind() == std::io::ErrorKind::NotFound
+            {
+                return Some(HashMap::new());
+            }
+            Logger::printmsg(Logger::InfoErr, format!("Cannot read the ban file \"{}\": {}", path, e));
+            return None;
+        }
+    };
+
+    let mut bans = HashMap::new();
+    for (index, line) in source.lines().enumerate()
+    {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match fields.as_slice()
+        {
+            [] => continue,
+            [address, until, count] => address.parse::<IpAddr>().ok()
+                .zip(until.parse::<u64>().ok().and_then(|until| UNIX_EPOCH.checked_add(Duration::from_secs(until))))
+                .zip(count.parse::<u32>().ok()),
+            _ => None,
+        };
+
+        match parsed
+        {
+            Some(((address, until), count)) =>
+            {
+                let until = latest.map_or(until, |latest| until.min(latest));
+                let address = cidr::client_key(address, ipv6_prefix);
+                if bans.get(&address).is_none_or(|existing: &Ban| existing.until < until)
+                {
+                    bans.insert(address, Ban { until, count });
+                }
+            },
+            None => Logger::printmsg(Logger::InfoErr, format!("Ignoring line {} of the ban file \"{}\"", index + 1, path)),
+        }
+    }
+    Some(bans)
+}
+
+/// Write the bans through a temporary file, so a crash never leaves a
+/// truncated one. Returns whether it worked.
+fn save(path: &str, bans: &HashMap<IpAddr, Ban>) -> bool
+{
+    let mut contents = String::new();
+    for (address, ban) in bans.iter()
+    {
+        let until = ban.until.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        contents.push_str(&format!("{} {} {}\n", address, until, ban.count));
+    }
+
+    let temporary = format!("{}.tmp", path);
+    match fs::write(&temporary, contents).and_then(|_| fs::rename(&temporary, path))
+    {
+        Ok(()) => true,
+        Err(e) =>
+        {
+            Logger::printmsg(Logger::InfoErr, format!("Cannot write the ban file \"{}\": {}", path, e));
+            false
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn settings(strikes: u32) -> BanConfig
+    {
+        BanConfig
+        {
+            strikes,
+            find_time: Duration::from_secs(60),
+            ban_time: Duration::from_secs(60),
+            max_ban_time: Duration::from_secs(3600),
+            file: None,
+        }
+    }
+
+    #[test]
+    fn strikes_get_a_client_banned()
+    {
+        let bans = BanList::new(&settings(2), 128);
+        bans.offense(CLIENT, Offense::PathTraversal);
+        assert!(bans.banned_for(CLIENT).is_none());
+
+        bans.offense(CLIENT, Offense::PathTraversal);
+        assert!(bans.banned_for(CLIENT).is_some());
+    }
+
+    #[test]
+    fn rotating_addresses_do_not_grow_the_strikes()
+    {
+        let bans = BanList::new(&settings(2), 128);
+        for address in 0..MAX_TRACKED as u32 * 2
+        {
+            bans.offense(IpAddr::V4(Ipv4Addr::from(address)), Offense::MalformedRequest);
+        }
+
+        let state = bans.state.lock().unwrap();
+        assert_eq!(state.strikes.tracked(), MAX_TRACKED);
+        assert!(state.bans.is_empty());
+    }
+
+    #[test]
+    fn bans_are_lifted_at_runtime()
+    {
+        let bans = BanList::new(&settings(1), 128);
+        bans.offense(CLIENT, Offense::RateLimited);
+        assert!(bans.banned_for(CLIENT).is_some());
+
+        assert_eq!(bans.lift_all(), 1);
+        assert!(bans.banned_for(CLIENT).is_none());
+    }
+
+    #[test]
+    fn reloading_keeps_bans_the_file_could_not_take()
+    {
+        let directory = std::env::temp_dir().join(format!("webserver-bans-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let mut settings = settings(1);
+        settings.file = Some(directory.join("bans.txt").to_string_lossy().into_owned());
+
+        // The directory is missing, so the ban cannot be written
+        let bans = BanList::new(&settings, 128);
+        bans.offense(CLIENT, Offense::RateLimited);
+
+        fs::create_dir_all(&directory).unwrap();
+        bans.reconfigure(&settings, 128);
+        assert!(bans.banned_for(CLIENT).is_some());
+        assert!(load(settings.file.as_ref().unwrap(), &settings, 128).unwrap().contains_key(&CLIENT));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn unreadable_ban_times_are_skipped()
+    {
+        let path = std::env::temp_dir().join(format!("webserver-ban-file-{}", std::process::id()));
+        fs::write(&path, format!("192.0.2.1 {} 1\n192.0.2.2 1 1\n", u64::MAX)).unwrap();
+
+        let loaded = load(path.to_str().unwrap(), &settings(1), 128).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), [&IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))]);
+    }
+
+    #[test]
+    fn file_bans_are_clamped_and_grouped()
+    {
+        let path = std::env::temp_dir().join(format!("webserver-ban-clamp-{}", std::process::id()));
+        let far = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 10 * 365 * 24 * 3600;
+        fs::write(&path, format!("2001:db8::1 {} 1\n2001:db8::2 {} 2\n::ffff:192.0.2.1 {} 1\n", far, far - 60, far)).unwrap();
+
+        let mut settings = settings(1);
+        settings.max_ban_time = Duration::from_secs(u64::MAX);
+        settings.file = Some(path.to_string_lossy().into_owned());
+        let bans = BanList::new(&settings, 64);
+        fs::remove_file(&path).unwrap();
+
+        // Both addresses fall in the same /64, the mapped one is plain IPv4
+        let prefix: IpAddr = "2001:db8::".parse().unwrap();
+        let state = bans.state.lock().unwrap();
+        assert_eq!(state.bans.len(), 2);
+        assert_eq!(state.bans[&prefix].count, 1);
+        assert!(state.bans.contains_key(&CLIENT));
+        drop(state);
+
+        // A huge max_ban_time neither overflows nor poisons the lock
+        bans.offense(CLIENT, Offense::RateLimited);
+        assert!(bans.banned_for(CLIENT).unwrap() <= LONGEST_BAN);
+        assert!(bans.banned_for("2001:db8::ff".parse().unwrap()).is_some());
+    }
+
+    #[test]
+    fn file_bans_are_ignored_while_bans_are_off()
+    {
+        let path = std::env::temp_dir().join(format!("webserver-ban-off-{}", std::process::id()));
+        fs::write(&path, "192.0.2.1 99999999999 1\n").unwrap();
+
+        let mut settings = settings(0);
+        settings.file = Some(path.to_string_lossy().into_owned());
+        let bans = BanList::new(&settings, 128);
+        assert!(bans.banned_for(CLIENT).is_none());
+
+        settings.strikes = 1;
+        bans.reconfigure(&settings, 128);
+        assert!(bans.banned_for(CLIENT).is_some());
+
+        settings.strikes = 0;
+        bans.reconfigure(&settings, 128);
+        assert!(bans.banned_for(CLIENT).is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::
+{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+
+/// Block of addresses such as `10.0.0.0/8` or `2001:db8::/32`.
+///
+/// IPv4 addresses seen as `::ffff:a.b.c.d` match IPv4 blocks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr
+{
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr
+{
+    /// Parse `address/prefix`, a bare address covers only itself.
+    pub fn parse(value: &str) -> Result<Cidr, String>
+    {
+        let (address, prefix) = match value.split_once('/')
+        {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+
+        let address: IpAddr = match address.trim().parse()
+        {
+            Ok(address) => address,
+            Err(_) => return Err(format!("invalid address in \"{}\"", value)),
+        };
+        let address = address.to_canonical();
+
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.map(|prefix| prefix.trim().parse::<u8>())
+        {
+            None => max,
+            Some(Ok(prefix)) if prefix <= max => prefix,
+            Some(_) => return Err(format!("invalid prefix length in \"{}\", expected 0 to {}", value, max)),
+        };
+
+        Ok(Cidr { network: mask(address, prefix), prefix })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool
+    {
+        let address = address.to_canonical();
+        address.is_ipv4() == self.network.is_ipv4() && mask(address, self.prefix) == self.network
+    }
+}
+
+impl fmt::Display for Cidr
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Keep the first `prefix` bits of `address`.
+pub fn mask(address: IpAddr, prefix: u8) -> IpAddr
+{
+    match address
+    {
+        IpAddr::V4(v4) =>
+        {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        },
+        IpAddr::V6(v6) =>
+        {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        },
+    }
+}
+
+/// Address a client is tracked under by the limiter, the bans and the
+/// connection caps.
+///
+/// IPv4 clients seen through a dual-stack socket (`::ffff:a.b.c.d`) are
+/// keyed on their IPv4 address, other IPv6 clients on their prefix.
+pub fn client_key(address: IpAddr, ipv6_prefix: u8) -> IpAddr
+{
+    let address = address.to_canonical();
+    match address
+    {
+        IpAddr::V4(_) => address,
+        IpAddr::V6(_) => mask(address, ipv6_prefix),
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn address(value: &str) -> IpAddr
+    {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn blocks_contain_their_addresses()
+    {
+        let block = Cidr::parse("192.0.2.0/24").unwrap();
+        assert!(block.contains(address("192.0.2.255")));
+        assert!(block.contains(address("::ffff:192.0.2.7")));
+        assert!(!block.contains(address("192.0.3.0")));
+        assert!(!block.contains(address("2001:db8::1")));
+
+        let block = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(block.contains(address("2001:db8:ffff::1")));
+        assert!(!block.contains(address("2001:db9::1")));
+    }
+
+    #[test]
+    fn blocks_are_parsed_and_masked()
+    {
+        assert_eq!(Cidr::parse("192.0.2.77/24").unwrap().to_string(), "192.0.2.0/24");
+        assert_eq!(Cidr::parse("192.0.2.1").unwrap().to_string(), "192.0.2.1/32");
+        assert_eq!(Cidr::parse("::ffff:10.1.2.3/8").unwrap().to_string(), "10.0.0.0/8");
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(address("203.0.113.9")));
+
+        assert!(Cidr::parse("192.0.2.0/33").is_err());
+        assert!(Cidr::parse("2001:db8::/129").is_err());
+        assert!(Cidr::parse("192.0.2/24").is_err());
+        assert!(Cidr::parse("192.0.2.0/x").is_err());
+    }
+
+    #[test]
+    fn ipv6_clients_are_keyed_on_their_prefix()
+    {
+        assert_eq!(client_key(address("2001:db8:1:2:3:4:5:6"), 64), address("2001:db8:1:2::"));
+        assert_eq!(client_key(address("2001:db8:1:2:3:4:5:6"), 128), address("2001:db8:1:2:3:4:5:6"));
+        assert_eq!(client_key(address("2001:db8:1:2:3:4:5:6"), 0), address("::"));
+        assert_eq!(client_key(address("::ffff:192.0.2.1"), 64), address("192.0.2.1"));
+        assert_eq!(client_key(address("192.0.2.1"), 0), address("192.0.2.1"));
+    }
+}
@@ -1,32 +1,1123 @@
-use std::env;
+use std::
+{
+    collections::HashMap,
+    env,
+    fmt,
+    fs,
+    net::ToSocketAddrs,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::access::AccessList;
+use crate::cidr::Cidr;
+use crate::configfile::{Document, Entry, Section, Value};
+use crate::fileutils::SymlinkPolicy;
+use crate::logger::LogLevel;
+use crate::ratelimit::Algorithm;
+use crate::request::{Limits, Method};
+
+
+const DEFAULT_HOST: &str = "::";
+const DEFAULT_PORT: u16 = 7878;
+const DEFAULT_THREADS: usize = 20;
+const DEFAULT_QUEUE_CAPACITY: usize = 256;
+const DEFAULT_WORKER_KEEP_ALIVE: Duration = Duration::from_secs(60);
+const DEFAULT_INDEX: &str = "index.html";
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+// Slow client protection defaults
+const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_BODY_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_PROCESS_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_KEEP_ALIVE_REQUESTS: u32 = 100;
+const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024;
+const DEFAULT_MAX_HEADER_BYTES: usize = 16 * 1024;
+const DEFAULT_MAX_HEADERS: usize = 100;
+
+// Limiter defaults
+const DEFAULT_MAX_REQUESTS: u32 = 100;
+const DEFAULT_WINDOW: Duration = Duration::from_secs(3600);
+const DEFAULT_CLEAN_DELAY: Duration = Duration::from_secs(3600);
+const DEFAULT_CLEAN_ELAPSED: Duration = Duration::from_secs(3600);
+const DEFAULT_CLEAN_MAXSIZE: usize = 150;
+const DEFAULT_MAX_CLIENTS: usize = 10_000;
+const DEFAULT_IPV6_PREFIX: u8 = 128;
+
+// Ban defaults, banning is off until strikes is set
+const DEFAULT_FIND_TIME: Duration = Duration::from_secs(600);
+const DEFAULT_BAN_TIME: Duration = Duration::from_secs(600);
+const DEFAULT_MAX_BAN_TIME: Duration = Duration::from_secs(24 * 3600);
+
+// Connection caps are off until a maximum is set
+const DEFAULT_CONNECTION_QUEUE_SIZE: usize = 64;
+const DEFAULT_CONNECTION_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const USAGE: &str = "\
+Usage: webserver [OPTIONS] [DIR]
+
+Serve static files from DIR (defaults to the current directory).
+
+Options:
+  -c, --config <FILE>         Read settings from a configuration file
+  -r, --root <DIR>            Directory to serve
+  -H, --host <ADDR>           Address to bind to, :: covers IPv4 and IPv6 [default: ::]
+  -p, --port <PORT>           Port to listen on [default: 7878]
+      --backend <NAME>        Connection handling: threads or epoll [default: threads]
+  -t, --threads <N>           Maximum number of worker threads [default: 20]
+      --min-threads <N>       Worker threads kept when idle [default: same as --threads]
+      --worker-keep-alive <SECS>
+                              Idle time before an extra worker exits [default: 60]
+      --queue-capacity <N>    Connections waiting for a worker before 503 is sent [default: 256]
+      --algorithm <NAME>      Rate limiting algorithm: fixed_window, token_bucket,
+                              sliding_log, sliding_window or gcra [default: fixed_window]
+      --max-requests <N>      Requests allowed per client and window [default: 100]
+      --window <SECS>         Rate limiter window [default: 3600]
+      --clean-delay <SECS>    Delay between limiter cleanups [default: 3600]
+      --clean-elapsed <SECS>  Age after which limiter entries are dropped [default: 3600]
+      --clean-max-size <N>    Limiter size that triggers a cleanup [default: 150]
+      --max-clients <N>       Clients each limiter tracks, the least recent are dropped [default: 10000]
+      --ipv6-prefix <BITS>    IPv6 clients in the same prefix share a quota [default: 128]
+      --header-timeout <SECS> Time allowed to receive a request head [default: 10]
+      --body-timeout <SECS>   Time allowed to receive a request body [default: 30]
+      --write-timeout <SECS>  Time allowed to send a response [default: 30]
+      --process-timeout <SECS>
+                              Time allowed to build a response, epoll backend [default: 60]
+      --keep-alive-timeout <SECS>
+                              Idle time before a persistent connection is closed [default: 5]
+      --keep-alive-requests <N>
+                              Requests answered on a persistent connection [default: 100]
+      --max-request-line <N>  Longest request line in bytes [default: 8192]
+      --max-header-bytes <N>  Largest header section in bytes [default: 16384]
+      --max-headers <N>       Most header lines in a request [default: 100]
+      --max-connections <N>   Connections open at once, 0 for no limit [default: 0]
+      --max-connections-per-client <N>
+                              Connections open at once per client, 0 for no limit [default: 0]
+      --connection-policy <NAME>
+                              Over a connection cap: reject or queue [default: reject]
+      --bandwidth-per-connection <BYTES>
+                              Bytes per second sent for each response, 0 for no limit [default: 0]
+      --bandwidth-per-client <BYTES>
+                              Bytes per second sent to each client, 0 for no limit [default: 0]
+      --bandwidth-total <BYTES>
+                              Bytes per second sent overall, 0 for no limit [default: 0]
+      --ban-strikes <N>       Offenses that get a client banned, 0 disables bans [default: 0]
+      --ban-time <SECS>       Length of a first ban, doubled for every further one [default: 600]
+      --ban-file <FILE>       Keep bans in FILE across restarts
+      --access-file <FILE>    Allow and deny rules for client addresses and paths
+      --trusted-proxies <CIDRS>
+                              Comma-separated proxies whose forwarding headers are believed
+      --proxy-protocol        Expect a PROXY protocol header on every connection
+      --follow-symlinks       Serve symlinks pointing outside of the root
+      --shutdown-grace <SECS> Time given to running requests on shutdown [default: 30]
+  -h, --help                  Print this help
+  -V, --version               Print version
 
+Command-line options take precedence over the configuration file.";
+
+
+/// How connections are handled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend
+{
+    /// Every connection occupies a worker until it is closed.
+    Threads,
+    /// Sockets are multiplexed on an event loop, workers only build responses.
+    Epoll,
+}
+
+impl Backend
+{
+    pub fn parse(name: &str) -> Option<Backend>
+    {
+        match name
+        {
+            "threads" => Some(Backend::Threads),
+            "epoll" => Some(Backend::Epoll),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to a connection over a cap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionPolicy
+{
+    /// Answer with 429 (client cap) or 503 (total cap) and close.
+    Reject,
+    /// Wait for a slot, up to `queue_timeout`.
+    Queue,
+}
+
+impl ConnectionPolicy
+{
+    pub fn parse(name: &str) -> Option<ConnectionPolicy>
+    {
+        match name
+        {
+            "reject" => Some(ConnectionPolicy::Reject),
+            "queue" => Some(ConnectionPolicy::Queue),
+            _ => None,
+        }
+    }
+}
+
+/// Deadlines protecting workers from slow clients.
+#[derive(Debug, Clone)]
+pub struct TimeoutConfig
+{
+    /// From the first byte of a request to the end of its head.
+    pub header: Duration,
+    /// From the end of the head to the end of the body.
+    pub body: Duration,
+    /// For a whole response to be sent.
+    pub write: Duration,
+    /// For a worker to build a response, with the epoll backend.
+    pub process: Duration,
+    /// Between two requests on a persistent connection.
+    pub keep_alive: Duration,
+    /// Requests answered on a connection before it is closed.
+    pub keep_alive_requests: u32,
+}
+
+/// Banning clients that keep exceeding the rate limit or sending bad requests.
+#[derive(Debug, Clone)]
+pub struct BanConfig
+{
+    /// Offenses within `find_time` that get a client banned, 0 disables bans.
+    pub strikes: u32,
+    pub find_time: Duration,
+    /// Length of the first ban, every further one is twice as long.
+    pub ban_time: Duration,
+    pub max_ban_time: Duration,
+    /// Where bans are kept across restarts.
+    pub file: Option<String>,
+}
+
+/// Caps on simultaneous connections, 0 for no cap.
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig
+{
+    /// Per client, trusted proxies are not capped on their own.
+    pub max_per_client: usize,
+    pub max_total: usize,
+    pub policy: ConnectionPolicy,
+    /// Connections that may wait for a slot, more are refused.
+    pub queue_size: usize,
+    pub queue_timeout: Duration,
+}
 
+/// Outgoing bandwidth caps in bytes per second, 0 for no cap.
+#[derive(Debug, Clone)]
+pub struct BandwidthConfig
+{
+    /// For each response, unless a rule matches its path.
+    pub per_connection: u64,
+    /// Shared by the responses to a client.
+    pub per_client: u64,
+    pub total: u64,
+    /// `[bandwidth.NAME]` sections, in the order of the file.
+    pub rules: Vec<BandwidthRule>,
+}
+
+/// Rate of the responses to the requests matching a path.
+#[derive(Debug, Clone)]
+pub struct BandwidthRule
+{
+    /// Path and everything below it, or a glob when it contains `*`.
+    pub path: String,
+    /// Replaces `per_connection`, 0 for no cap.
+    pub per_connection: u64,
+}
+
+/// Proxies allowed to tell who the client is.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig
+{
+    /// Peers whose `Forwarded` / `X-Forwarded-For` headers and PROXY headers
+    /// are believed.
+    pub trusted: Vec<Cidr>,
+    /// Connections start with a PROXY protocol header, peers that are not
+    /// trusted are refused.
+    pub protocol: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimiterConfig
+{
+    pub algorithm: Algorithm,
+    pub max_requests: u32,
+    pub window: Duration,
+    pub clean_delay: Duration,
+    pub clean_elapsed: Duration,
+    pub clean_max_size: usize,
+    /// Clients tracked by each limiter, the least recently seen ones are
+    /// dropped past it.
+    pub max_clients: usize,
+    /// IPv6 clients are grouped by this prefix length (64 for a /64).
+    pub ipv6_prefix: u8,
+    /// `[limiter.NAME]` sections, in the order of the file.
+    pub rules: Vec<RuleConfig>,
+}
+
+/// Rate limit for the requests matching a path and some methods.
+#[derive(Debug, Clone)]
+pub struct RuleConfig
+{
+    pub name: String,
+    /// Path and everything below it, or a glob when it contains `*`.
+    pub path: String,
+    /// Methods the rule applies to, every method when empty.
+    pub methods: Vec<Method>,
+    /// `None` when the matching requests are not limited.
+    pub limit: Option<RuleLimit>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RuleLimit
+{
+    /// The `[limiter]` algorithm when not set.
+    pub algorithm: Option<Algorithm>,
+    pub max_requests: u32,
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone)]
 pub struct Config
 {
     pub file_path: String,
+    pub index: String,
+    pub symlinks: SymlinkPolicy,
+    pub listen: Vec<String>,
+    pub backend: Backend,
+    /// Maximum number of workers.
+    pub threads: usize,
+    /// Workers kept alive when idle, `threads` when not set.
+    pub min_threads: Option<usize>,
+    /// How long a worker above `min_threads` may stay idle.
+    pub worker_keep_alive: Duration,
+    /// Connections that may wait for a free worker, more are answered with 503.
+    pub queue_capacity: usize,
+    /// How long running and queued connections may take to finish on shutdown.
+    pub shutdown_grace: Duration,
+    pub timeouts: TimeoutConfig,
+    pub request_limits: Limits,
+    pub limiter: LimiterConfig,
+    pub bans: BanConfig,
+    pub connections: ConnectionConfig,
+    pub bandwidth: BandwidthConfig,
+    pub proxy: ProxyConfig,
+    /// File the access list was read from.
+    pub access_file: Option<String>,
+    pub access: AccessList,
+    pub log_level: LogLevel,
+    /// Extra headers added to every response.
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError
+{
+    /// `--help` was given, not an actual failure.
+    HelpRequested,
+    /// `--version` was given, not an actual failure.
+    VersionRequested,
+    UnknownArgument(String),
+    MissingValue(String),
+    InvalidValue { option: String, value: String, reason: String },
+    InvalidRoot(String),
+    /// The configuration file could not be read.
+    Io { path: String, reason: String },
+    /// The configuration file is malformed or holds an invalid setting.
+    File { path: String, line: usize, message: String },
+}
+
+impl fmt::Display for ConfigError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            ConfigError::HelpRequested => write!(f, "{}", USAGE),
+            ConfigError::VersionRequested => write!(f, "{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            ConfigError::UnknownArgument(arg) => write!(f, "unknown argument \"{}\"", arg),
+            ConfigError::MissingValue(option) => write!(f, "option \"{}\" requires a value", option),
+            ConfigError::InvalidValue { option, value, reason } => write!(f, "invalid value \"{}\" for \"{}\": {}", value, option, reason),
+            ConfigError::InvalidRoot(reason) => write!(f, "invalid root directory: {}", reason),
+            ConfigError::Io { path, reason } => write!(f, "cannot read \"{}\": {}", path, reason),
+            ConfigError::File { path, line, message } => write!(f, "{}:{}: {}", path, line, message),
+        }
+    }
 }
 
 impl Config
 {
-    pub fn build(args: &[String]) -> Config
+    fn defaults() -> Config
+    {
+        Config
+        {
+            file_path: String::new(),
+            index: DEFAULT_INDEX.to_string(),
+            symlinks: SymlinkPolicy::WithinRoot,
+            listen: vec![bind_address(DEFAULT_HOST, DEFAULT_PORT)],
+            backend: Backend::Threads,
+            threads: DEFAULT_THREADS,
+            min_threads: None,
+            worker_keep_alive: DEFAULT_WORKER_KEEP_ALIVE,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            timeouts: TimeoutConfig
+            {
+                header: DEFAULT_HEADER_TIMEOUT,
+                body: DEFAULT_BODY_TIMEOUT,
+                write: DEFAULT_WRITE_TIMEOUT,
+                process: DEFAULT_PROCESS_TIMEOUT,
+                keep_alive: DEFAULT_KEEP_ALIVE_TIMEOUT,
+                keep_alive_requests: DEFAULT_KEEP_ALIVE_REQUESTS,
+            },
+            request_limits: Limits
+            {
+                max_request_line: DEFAULT_MAX_REQUEST_LINE,
+                max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+                max_headers: DEFAULT_MAX_HEADERS,
+            },
+            limiter: LimiterConfig
+            {
+                algorithm: Algorithm::FixedWindow,
+                max_requests: DEFAULT_MAX_REQUESTS,
+                window: DEFAULT_WINDOW,
+                clean_delay: DEFAULT_CLEAN_DELAY,
+                clean_elapsed: DEFAULT_CLEAN_ELAPSED,
+                clean_max_size: DEFAULT_CLEAN_MAXSIZE,
+                max_clients: DEFAULT_MAX_CLIENTS,
+                ipv6_prefix: DEFAULT_IPV6_PREFIX,
+                rules: Vec::new(),
+            },
+            bans: BanConfig
+            {
+                strikes: 0,
+                find_time: DEFAULT_FIND_TIME,
+                ban_time: DEFAULT_BAN_TIME,
+                max_ban_time: DEFAULT_MAX_BAN_TIME,
+                file: None,
+            },
+            connections: ConnectionConfig
+            {
+                max_per_client: 0,
+                max_total: 0,
+                policy: ConnectionPolicy::Reject,
+                queue_size: DEFAULT_CONNECTION_QUEUE_SIZE,
+                queue_timeout: DEFAULT_CONNECTION_QUEUE_TIMEOUT,
+            },
+            bandwidth: BandwidthConfig { per_connection: 0, per_client: 0, total: 0, rules: Vec::new() },
+            proxy: ProxyConfig { trusted: Vec::new(), protocol: false },
+            access_file: None,
+            access: AccessList::default(),
+            log_level: LogLevel::Debug,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn build(args: &[String]) -> Result<Config, ConfigError>
     {
-        let mut path = String::from(env::current_dir().unwrap().into_os_string().into_string().unwrap());
-        path.push_str("/");
+        let mut config = Config::defaults();
+
+        let mut root: Option<String> = None;
+        let mut root_base: Option<String> = None;
+        let mut config_path: Option<String> = None;
+        let mut host: Option<String> = None;
+        let mut port: Option<u16> = None;
+
+        let mut args = args.iter().skip(1);
+        let mut overrides: Vec<(String, String)> = Vec::new();
 
-        if args.len() < 2
+        while let Some(arg) = args.next()
         {
-            println!("Using default targer dir: {}", path);
-            return Config { file_path: path };
+            if !arg.starts_with('-') || arg == "-"
+            {
+                if root.is_some()
+                {
+                    return Err(ConfigError::UnknownArgument(arg.clone()));
+                }
+                root = Some(arg.clone());
+                continue;
+            }
+
+            // Both "--port 80" and "--port=80" are accepted
+            let (option, inline_value) = match arg.split_once('=')
+            {
+                Some((option, value)) if arg.starts_with("--") => (option, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+
+            let mut value = || match inline_value.clone().or_else(|| args.next().cloned())
+            {
+                Some(value) => Ok(value),
+                None => Err(ConfigError::MissingValue(option.to_string())),
+            };
+
+            match option
+            {
+                "-h" | "--help" => return Err(ConfigError::HelpRequested),
+                "-V" | "--version" => return Err(ConfigError::VersionRequested),
+                "-c" | "--config" => config_path = Some(value()?),
+                "-r" | "--root" => root = Some(value()?),
+                "-H" | "--host" => host = Some(value()?),
+                "-p" | "--port" => port = Some(parse_number(option, &value()?)?),
+                "--follow-symlinks" | "--proxy-protocol" => overrides.push((option.to_string(), String::new())),
+                "--backend" | "-t" | "--threads" | "--min-threads" | "--worker-keep-alive" | "--queue-capacity" | "--shutdown-grace" | "--header-timeout" | "--body-timeout" | "--write-timeout" | "--process-timeout"
+                | "--keep-alive-timeout" | "--keep-alive-requests"
+                | "--max-request-line" | "--max-header-bytes" | "--max-headers" | "--algorithm" | "--max-requests" | "--window" | "--clean-delay" | "--clean-elapsed" | "--clean-max-size" | "--max-clients" | "--ipv6-prefix" | "--trusted-proxies" | "--access-file"
+                | "--ban-strikes" | "--ban-time" | "--ban-file" | "--max-connections" | "--max-connections-per-client" | "--connection-policy"
+                | "--bandwidth-per-connection" | "--bandwidth-per-client" | "--bandwidth-total" =>
+                {
+                    overrides.push((option.to_string(), value()?));
+                },
+                _ => return Err(ConfigError::UnknownArgument(arg.clone())),
+            }
         }
 
-        let mut file_path = args[1].clone();
+        // The file is applied first so that command-line options win
+        let mut file_lines = HashMap::new();
+        if let Some(path) = &config_path
+        {
+            let (file_root, base) = config.load_file(path, &mut file_lines)?;
+            if root.is_none()
+            {
+                root = file_root;
+                root_base = base;
+            }
+        }
 
-        if file_path.chars().last().expect("Cannot read argument") != '/'
+        for (option, value) in overrides.iter()
         {
-           file_path.push_str("/");
+            let option = option.as_str();
+            match option
+            {
+                "--backend" => config.backend = match Backend::parse(value)
+                {
+                    Some(backend) => backend,
+                    None => return Err(invalid_value(option, value, "expected \"threads\" or \"epoll\"")),
+                },
+                "-t" | "--threads" => config.threads = parse_number(option, value)?,
+                "--min-threads" => config.min_threads = Some(parse_number(option, value)?),
+                "--worker-keep-alive" => config.worker_keep_alive = parse_seconds(option, value)?,
+                "--queue-capacity" => config.queue_capacity = parse_number(option, value)?,
+                "--shutdown-grace" => config.shutdown_grace = parse_seconds(option, value)?,
+                "--header-timeout" => config.timeouts.header = parse_seconds(option, value)?,
+                "--body-timeout" => config.timeouts.body = parse_seconds(option, value)?,
+                "--write-timeout" => config.timeouts.write = parse_seconds(option, value)?,
+                "--process-timeout" => config.timeouts.process = parse_seconds(option, value)?,
+                "--keep-alive-timeout" => config.timeouts.keep_alive = parse_seconds(option, value)?,
+                "--keep-alive-requests" => config.timeouts.keep_alive_requests = parse_number(option, value)?,
+                "--max-request-line" => config.request_limits.max_request_line = parse_number(option, value)?,
+                "--max-header-bytes" => config.request_limits.max_header_bytes = parse_number(option, value)?,
+                "--max-headers" => config.request_limits.max_headers = parse_number(option, value)?,
+                "--algorithm" => config.limiter.algorithm = match Algorithm::parse(value)
+                {
+                    Some(algorithm) => algorithm,
+                    None => return Err(invalid_value(option, value, &format!("expected {}", Algorithm::NAMES))),
+                },
+                "--max-requests" => config.limiter.max_requests = parse_number(option, value)?,
+                "--window" => config.limiter.window = parse_seconds(option, value)?,
+                "--clean-delay" => config.limiter.clean_delay = parse_seconds(option, value)?,
+                "--clean-elapsed" => config.limiter.clean_elapsed = parse_seconds(option, value)?,
+                "--clean-max-size" => config.limiter.clean_max_size = parse_number(option, value)?,
+                "--max-clients" => config.limiter.max_clients = parse_number(option, value)?,
+                "--ipv6-prefix" => config.limiter.ipv6_prefix = parse_number(option, value)?,
+                "--trusted-proxies" =>
+                {
+                    let trusted: Result<Vec<Cidr>, String> = value.split(',').filter(|cidr| !cidr.trim().is_empty()).map(Cidr::parse).collect();
+                    config.proxy.trusted = trusted.map_err(|e| invalid_value(option, value, &e))?;
+                },
+                "--proxy-protocol" => config.proxy.protocol = true,
+                "--access-file" => config.access_file = Some(value.clone()),
+                "--ban-strikes" => config.bans.strikes = parse_number(option, value)?,
+                "--ban-time" => config.bans.ban_time = parse_seconds(option, value)?,
+                "--ban-file" => config.bans.file = Some(value.clone()),
+                "--max-connections" => config.connections.max_total = parse_number(option, value)?,
+                "--max-connections-per-client" => config.connections.max_per_client = parse_number(option, value)?,
+                "--connection-policy" => config.connections.policy = match ConnectionPolicy::parse(value)
+                {
+                    Some(policy) => policy,
+                    None => return Err(invalid_value(option, value, "expected \"reject\" or \"queue\"")),
+                },
+                "--bandwidth-per-connection" => config.bandwidth.per_connection = parse_number(option, value)?,
+                "--bandwidth-per-client" => config.bandwidth.per_client = parse_number(option, value)?,
+                "--bandwidth-total" => config.bandwidth.total = parse_number(option, value)?,
+                "--follow-symlinks" => config.symlinks = SymlinkPolicy::Follow,
+                _ => unreachable!(),
+            }
         }
 
-        if file_path.chars().next().unwrap() != '/'
+        if host.is_some() || port.is_some()
+        {
+            let host = host.unwrap_or_else(|| DEFAULT_HOST.to_string());
+            config.listen = vec![bind_address(&host, port.unwrap_or(DEFAULT_PORT))];
+        }
+
+        // Point at the file line when the faulty value came from the file
+        let invalid = |option: &str, key: &str, value: &str, reason: &str|
+        {
+            let overridden = overrides.iter().any(|(given, _)| given == option || (given == "-t" && option == "--threads"));
+            match (&config_path, file_lines.get(key))
+            {
+                (Some(path), Some(&line)) if !overridden =>
+                {
+                    let name = key.rsplit('.').next().unwrap_or(key);
+                    ConfigError::File { path: path.clone(), line, message: format!("invalid value \"{}\" for \"{}\": {}", value, name, reason) }
+                },
+                _ => invalid_value(option, value, reason),
+            }
+        };
+
+        if config.threads == 0
+        {
+            return Err(invalid("--threads", "server.threads", "0", "at least one worker is required"));
+        }
+
+        if config.min_threads() > config.threads
+        {
+            let min_threads = config.min_threads().to_string();
+            return Err(invalid("--min-threads", "server.min_threads", &min_threads, "must not be above the number of threads"));
+        }
+
+        if config.worker_keep_alive.is_zero()
+        {
+            return Err(invalid("--worker-keep-alive", "server.worker_keep_alive", "0", "must be greater than zero"));
+        }
+
+        if config.queue_capacity == 0
+        {
+            return Err(invalid("--queue-capacity", "server.queue_capacity", "0", "must be greater than zero"));
+        }
+
+        let timeouts =
+        [
+            ("--header-timeout", "timeouts.header", config.timeouts.header),
+            ("--body-timeout", "timeouts.body", config.timeouts.body),
+            ("--write-timeout", "timeouts.write", config.timeouts.write),
+            ("--process-timeout", "timeouts.process", config.timeouts.process),
+            ("--keep-alive-timeout", "timeouts.keep_alive", config.timeouts.keep_alive),
+        ];
+        for (option, key, timeout) in timeouts
+        {
+            if timeout.is_zero()
+            {
+                return Err(invalid(option, key, "0", "must be greater than zero"));
+            }
+        }
+
+        if config.timeouts.keep_alive_requests == 0
+        {
+            return Err(invalid("--keep-alive-requests", "timeouts.keep_alive_requests", "0", "must be greater than zero"));
+        }
+
+        let request_limits = &config.request_limits;
+        let limits =
+        [
+            ("--max-request-line", "limits.max_request_line", request_limits.max_request_line),
+            ("--max-header-bytes", "limits.max_header_bytes", request_limits.max_header_bytes),
+            ("--max-headers", "limits.max_headers", request_limits.max_headers),
+        ];
+        for (option, key, limit) in limits
+        {
+            if limit == 0
+            {
+                return Err(invalid(option, key, "0", "must be greater than zero"));
+            }
+        }
+
+        if config.limiter.max_requests == 0
+        {
+            return Err(invalid("--max-requests", "limiter.max_requests", "0", "must be greater than zero"));
+        }
+
+        if config.proxy.protocol && config.proxy.trusted.is_empty()
+        {
+            return Err(invalid("--proxy-protocol", "proxy.protocol", "true", "every connection would be refused without trusted proxies"));
+        }
+
+        if config.limiter.max_clients == 0
+        {
+            return Err(invalid("--max-clients", "limiter.max_clients", "0", "must be greater than zero"));
+        }
+
+        if config.limiter.window.is_zero()
+        {
+            return Err(invalid("--window", "limiter.window", "0", "must be greater than zero"));
+        }
+
+        // A zero delay would have the cleaner spin
+        if config.limiter.clean_delay.is_zero()
+        {
+            return Err(invalid("--clean-delay", "limiter.clean_delay", "0", "must be greater than zero"));
+        }
+
+        if !(1..=128).contains(&config.limiter.ipv6_prefix)
+        {
+            let prefix = config.limiter.ipv6_prefix.to_string();
+            return Err(invalid("--ipv6-prefix", "limiter.ipv6_prefix", &prefix, "must be between 1 and 128"));
+        }
+
+        if config.bans.strikes > 0 && (config.bans.ban_time.is_zero() || config.bans.find_time.is_zero())
+        {
+            return Err(invalid("--ban-time", "bans.ban_time", "0", "must be greater than zero"));
+        }
+
+        if config.connections.policy == ConnectionPolicy::Queue && config.connections.queue_timeout.is_zero()
+        {
+            return Err(invalid("queue_timeout", "connections.queue_timeout", "0", "must be greater than zero"));
+        }
+
+        if let Some(path) = &config.access_file
+        {
+            config.access = load_access_list(path)?;
+        }
+
+        config.file_path = build_root(root, root_base)?;
+
+        Ok(config)
+    }
+
+    /// Number of workers the pool keeps when idle.
+    pub fn min_threads(&self) -> usize
+    {
+        self.min_threads.unwrap_or(self.threads)
+    }
+
+    /// Apply the settings of a configuration file.
+    ///
+    /// Returns the `root` setting (if any) and the directory it is relative to,
+    /// so the command line can still override it. The line of every setting
+    /// is kept in `lines` under "section.key".
+    fn load_file(&mut self, path: &str, lines: &mut HashMap<String, usize>) -> Result<(Option<String>, Option<String>), ConfigError>
+    {
+        let source = match fs::read_to_string(path)
+        {
+            Ok(source) => source,
+            Err(e) => return Err(ConfigError::Io { path: path.to_string(), reason: e.to_string() }),
+        };
+
+        let document = match Document::parse(&source)
+        {
+            Ok(document) => document,
+            Err(e) => return Err(ConfigError::File { path: path.to_string(), line: e.line, message: e.message }),
+        };
+
+        let error = |line: usize, message: String| ConfigError::File { path: path.to_string(), line, message };
+        let mut root = None;
+
+        for section in document.sections.iter()
+        {
+            for entry in section.entries.iter()
+            {
+                lines.insert(format!("{}.{}", section.name, entry.key), entry.line);
+            }
+
+            match section.name.as_str()
+            {
+                "server" => for entry in section.entries.iter()
+                {
+                    match entry.key.as_str()
+                    {
+                        "listen" =>
+                        {
+                            let listen = get_string_list(entry).map_err(|e| error(entry.line, e))?;
+                            if listen.is_empty()
+                            {
+                                return Err(error(entry.line, String::from("at least one listener is required")));
+                            }
+                            for address in listen.iter()
+                            {
+                                if let Err(e) = address.to_socket_addrs()
+                                {
+                                    return Err(error(entry.line, format!("invalid listen address \"{}\": {}", address, e)));
+                                }
+                            }
+                            self.listen = listen;
+                        },
+                        "root" => root = Some(get_string(entry).map_err(|e| error(entry.line, e))?),
+                        "index" =>
+                        {
+                            let index = get_string(entry).map_err(|e| error(entry.line, e))?;
+                            if index.is_empty() || index.contains('/')
+                            {
+                                return Err(error(entry.line, format!("invalid index file name \"{}\"", index)));
+                            }
+                            self.index = index;
+                        },
+                        "backend" =>
+                        {
+                            let name = get_string(entry).map_err(|e| error(entry.line, e))?;
+                            self.backend = match Backend::parse(&name)
+                            {
+                                Some(backend) => backend,
+                                None => return Err(error(entry.line, format!("unknown backend \"{}\", expected \"threads\" or \"epoll\"", name))),
+                            };
+                        },
+                        "threads" => self.threads = get_number(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "min_threads" => self.min_threads = Some(get_number(entry, 0).map_err(|e| error(entry.line, e))?),
+                        "worker_keep_alive" => self.worker_keep_alive = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "queue_capacity" => self.queue_capacity = get_number(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "shutdown_grace" => self.shutdown_grace = get_seconds(entry, 0).map_err(|e| error(entry.line, e))?,
+                        "follow_symlinks" =>
+                        {
+                            self.symlinks = match get_bool(entry).map_err(|e| error(entry.line, e))?
+                            {
+                                true => SymlinkPolicy::Follow,
+                                false => SymlinkPolicy::WithinRoot,
+                            };
+                        },
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [server]", entry.key))),
+                    }
+                },
+
+                "limiter" => for entry in section.entries.iter()
+                {
+                    let limiter = &mut self.limiter;
+                    match entry.key.as_str()
+                    {
+                        "algorithm" =>
+                        {
+                            let name = get_string(entry).map_err(|e| error(entry.line, e))?;
+                            limiter.algorithm = match Algorithm::parse(&name)
+                            {
+                                Some(algorithm) => algorithm,
+                                None => return Err(error(entry.line, format!("unknown algorithm \"{}\", expected {}", name, Algorithm::NAMES))),
+                            };
+                        },
+                        "max_requests" => limiter.max_requests = get_number(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "window" => limiter.window = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "clean_delay" => limiter.clean_delay = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "clean_elapsed" => limiter.clean_elapsed = get_seconds(entry, 0).map_err(|e| error(entry.line, e))?,
+                        "clean_max_size" => limiter.clean_max_size = get_number(entry, 0).map_err(|e| error(entry.line, e))?,
+                        "max_clients" => limiter.max_clients = get_number(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "ipv6_prefix" => limiter.ipv6_prefix = get_number(entry, 1).map_err(|e| error(entry.line, e))?,
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [limiter]", entry.key))),
+                    }
+                },
+
+                "timeouts" => for entry in section.entries.iter()
+                {
+                    let timeouts = &mut self.timeouts;
+                    match entry.key.as_str()
+                    {
+                        "header" => timeouts.header = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "body" => timeouts.body = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "write" => timeouts.write = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "process" => timeouts.process = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "keep_alive" => timeouts.keep_alive = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "keep_alive_requests" => timeouts.keep_alive_requests = get_number(entry, 1).map_err(|e| error(entry.line, e))?,
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [timeouts]", entry.key))),
+                    }
+                },
+
+                "limits" => for entry in section.entries.iter()
+                {
+                    let limits = &mut self.request_limits;
+                    match entry.key.as_str()
+                    {
+                        "max_request_line" => limits.max_request_line = get_number(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "max_header_bytes" => limits.max_header_bytes = get_number(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "max_headers" => limits.max_headers = get_number(entry, 1).map_err(|e| error(entry.line, e))?,
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [limits]", entry.key))),
+                    }
+                },
+
+                "proxy" => for entry in section.entries.iter()
+                {
+                    match entry.key.as_str()
+                    {
+                        "trusted" =>
+                        {
+                            let trusted: Result<Vec<Cidr>, String> = get_string_list(entry).and_then(|list| list.iter().map(|cidr| Cidr::parse(cidr)).collect());
+                            self.proxy.trusted = trusted.map_err(|e| error(entry.line, e))?;
+                        },
+                        "protocol" => self.proxy.protocol = get_bool(entry).map_err(|e| error(entry.line, e))?,
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [proxy]", entry.key))),
+                    }
+                },
+
+                "bans" => for entry in section.entries.iter()
+                {
+                    let bans = &mut self.bans;
+                    match entry.key.as_str()
+                    {
+                        "strikes" => bans.strikes = get_number(entry, 0).map_err(|e| error(entry.line, e))?,
+                        "find_time" => bans.find_time = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "ban_time" => bans.ban_time = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "max_ban_time" => bans.max_ban_time = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        "file" =>
+                        {
+                            let file = get_string(entry).map_err(|e| error(entry.line, e))?;
+                            let base = Path::new(path).parent().unwrap_or(Path::new(""));
+                            bans.file = Some(base.join(file).to_string_lossy().into_owned());
+                        },
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [bans]", entry.key))),
+                    }
+                },
+
+                "connections" => for entry in section.entries.iter()
+                {
+                    let connections = &mut self.connections;
+                    match entry.key.as_str()
+                    {
+                        "max_per_client" => connections.max_per_client = get_number(entry, 0).map_err(|e| error(entry.line, e))?,
+                        "max_total" => connections.max_total = get_number(entry, 0).map_err(|e| error(entry.line, e))?,
+                        "policy" =>
+                        {
+                            let name = get_string(entry).map_err(|e| error(entry.line, e))?;
+                            connections.policy = match ConnectionPolicy::parse(&name)
+                            {
+                                Some(policy) => policy,
+                                None => return Err(error(entry.line, format!("unknown policy \"{}\", expected \"reject\" or \"queue\"", name))),
+                            };
+                        },
+                        "queue_size" => connections.queue_size = get_number(entry, 0).map_err(|e| error(entry.line, e))?,
+                        "queue_timeout" => connections.queue_timeout = get_seconds(entry, 1).map_err(|e| error(entry.line, e))?,
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [connections]", entry.key))),
+                    }
+                },
+
+                "bandwidth" => for entry in section.entries.iter()
+                {
+                    let bandwidth = &mut self.bandwidth;
+                    match entry.key.as_str()
+                    {
+                        "per_connection" => bandwidth.per_connection = get_number(entry, 0).map_err(|e| error(entry.line, e))?,
+                        "per_client" => bandwidth.per_client = get_number(entry, 0).map_err(|e| error(entry.line, e))?,
+                        "total" => bandwidth.total = get_number(entry, 0).map_err(|e| error(entry.line, e))?,
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [bandwidth]", entry.key))),
+                    }
+                },
+
+                "access" => for entry in section.entries.iter()
+                {
+                    match entry.key.as_str()
+                    {
+                        "file" =>
+                        {
+                            // Relative to the configuration file, like root
+                            let file = get_string(entry).map_err(|e| error(entry.line, e))?;
+                            let base = Path::new(path).parent().unwrap_or(Path::new(""));
+                            self.access_file = Some(base.join(file).to_string_lossy().into_owned());
+                        },
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [access]", entry.key))),
+                    }
+                },
+
+                "logging" => for entry in section.entries.iter()
+                {
+                    match entry.key.as_str()
+                    {
+                        "level" =>
+                        {
+                            let level = get_string(entry).map_err(|e| error(entry.line, e))?;
+                            self.log_level = match LogLevel::parse(&level)
+                            {
+                                Some(level) => level,
+                                None => return Err(error(entry.line, format!("unknown log level \"{}\", expected \"error\", \"info\" or \"debug\"", level))),
+                            };
+                        },
+                        _ => return Err(error(entry.line, format!("unknown key \"{}\" in [logging]", entry.key))),
+                    }
+                },
+
+                "headers" =>
+                {
+                    self.headers.clear();
+                    for entry in section.entries.iter()
+                    {
+                        let value = get_string(entry).map_err(|e| error(entry.line, e))?;
+                        if !entry.key.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+                        {
+                            return Err(error(entry.line, format!("invalid header name \"{}\"", entry.key)));
+                        }
+                        if value.bytes().any(|b| b.is_ascii_control() && b != b'\t')
+                        {
+                            return Err(error(entry.line, format!("invalid value for header \"{}\"", entry.key)));
+                        }
+                        self.headers.push((entry.key.clone(), value));
+                    }
+                },
+
+                name if name.starts_with("limiter.") =>
+                {
+                    let rule = parse_rule(&name["limiter.".len()..], section).map_err(|(line, e)| error(line, e))?;
+                    self.limiter.rules.push(rule);
+                },
+
+                name if name.starts_with("bandwidth.") =>
+                {
+                    let rule = parse_bandwidth_rule(section).map_err(|(line, e)| error(line, e))?;
+                    self.bandwidth.rules.push(rule);
+                },
+
+                "" => if let Some(entry) = section.entries.first()
+                {
+                    return Err(error(entry.line, format!("key \"{}\" must be inside a section", entry.key)));
+                },
+
+                name => return Err(error(section.line, format!("unknown section [{}]", name))),
+            }
+        }
+
+        // A relative root is resolved against the directory of the file
+        let base = Path::new(path).parent().map(|dir| dir.to_string_lossy().into_owned());
+
+        Ok((root, base))
+    }
+}
+
+fn load_access_list(path: &str) -> Result<AccessList, ConfigError>
+{
+    let source = match fs::read_to_string(path)
+    {
+        Ok(source) => source,
+        Err(e) => return Err(ConfigError::Io { path: path.to_string(), reason: e.to_string() }),
+    };
+
+    AccessList::parse(&source).map_err(|(line, message)| ConfigError::File { path: path.to_string(), line, message })
+}
+
+/// Read a `[limiter.NAME]` section.
+fn parse_rule(name: &str, section: &Section) -> Result<RuleConfig, (usize, String)>
+{
+    let mut path = None;
+    let mut methods = Vec::new();
+    let mut unlimited = false;
+    let mut algorithm = None;
+    let mut max_requests = None;
+    let mut window = None;
+
+    for entry in section.entries.iter()
+    {
+        let line = entry.line;
+        match entry.key.as_str()
+        {
+            "path" =>
+            {
+                let value = get_string(entry).map_err(|e| (line, e))?;
+                if !value.starts_with('/')
+                {
+                    return Err((line, format!("path \"{}\" must start with \"/\"", value)));
+                }
+                path = Some(value);
+            },
+            "methods" => for method in get_string_list(entry).map_err(|e| (line, e))?
+            {
+                match Method::parse(&method)
+                {
+                    Some(method) => methods.push(method),
+                    None => return Err((line, format!("unknown method \"{}\"", method))),
+                }
+            },
+            "unlimited" => unlimited = get_bool(entry).map_err(|e| (line, e))?,
+            "algorithm" =>
+            {
+                let value = get_string(entry).map_err(|e| (line, e))?;
+                algorithm = match Algorithm::parse(&value)
+                {
+                    Some(algorithm) => Some(algorithm),
+                    None => return Err((line, format!("unknown algorithm \"{}\", expected {}", value, Algorithm::NAMES))),
+                };
+            },
+            "max_requests" => max_requests = Some(get_number(entry, 1).map_err(|e| (line, e))?),
+            "window" => window = Some(get_seconds(entry, 1).map_err(|e| (line, e))?),
+            _ => return Err((line, format!("unknown key \"{}\" in [{}]", entry.key, section.name))),
+        }
+    }
+
+    let path = match path
+    {
+        Some(path) => path,
+        None => return Err((section.line, format!("[{}] needs a path", section.name))),
+    };
+
+    let limit = match (unlimited, max_requests, window)
+    {
+        (true, None, None) if algorithm.is_none() => None,
+        (true, _, _) => return Err((section.line, format!("[{}] is unlimited, it takes no algorithm, max_requests or window", section.name))),
+        (false, Some(max_requests), Some(window)) => Some(RuleLimit { algorithm, max_requests, window }),
+        (false, _, _) => return Err((section.line, format!("[{}] needs max_requests and window, or unlimited = true", section.name))),
+    };
+
+    Ok(RuleConfig { name: name.to_string(), path, methods, limit })
+}
+
+/// Read a `[bandwidth.NAME]` section.
+fn parse_bandwidth_rule(section: &Section) -> Result<BandwidthRule, (usize, String)>
+{
+    let mut path = None;
+    let mut per_connection = None;
+
+    for entry in section.entries.iter()
+    {
+        let line = entry.line;
+        match entry.key.as_str()
+        {
+            "path" =>
+            {
+                let value = get_string(entry).map_err(|e| (line, e))?;
+                if !value.starts_with('/')
+                {
+                    return Err((line, format!("path \"{}\" must start with \"/\"", value)));
+                }
+                path = Some(value);
+            },
+            "per_connection" => per_connection = Some(get_number(entry, 0).map_err(|e| (line, e))?),
+            _ => return Err((line, format!("unknown key \"{}\" in [{}]", entry.key, section.name))),
+        }
+    }
+
+    match (path, per_connection)
+    {
+        (Some(path), Some(per_connection)) => Ok(BandwidthRule { path, per_connection }),
+        _ => Err((section.line, format!("[{}] needs a path and per_connection", section.name))),
+    }
+}
+
+fn bind_address(host: &str, port: u16) -> String
+{
+    if host.contains(':')
+    {
+        format!("[{}]:{}", host, port)
+    }
+    else
+    {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Turn the root setting into an absolute directory path ending with '/'.
+fn build_root(root: Option<String>, base: Option<String>) -> Result<String, ConfigError>
+{
+    let mut path = match base
+    {
+        Some(base) if base.starts_with('/') => base,
+        _ => match env::current_dir()
+        {
+            Ok(dir) => dir.to_string_lossy().into_owned(),
+            Err(e) => return Err(ConfigError::InvalidRoot(format!("cannot read current directory: {}", e))),
+        },
+    };
+    if !path.ends_with('/')
+    {
+        path.push('/');
+    }
+
+    if let Some(mut file_path) = root
+    {
+        if !file_path.ends_with('/')
+        {
+            file_path.push('/');
+        }
+
+        if !file_path.starts_with('/')
         {
             path.push_str(&file_path);
         }
@@ -34,7 +1125,283 @@ impl Config
         {
             path = file_path;
         }
+    }
+
+    if !Path::new(&path).is_dir()
+    {
+        return Err(ConfigError::InvalidRoot(format!("\"{}\" is not a directory", path)));
+    }
+
+    Ok(path)
+}
+
+fn invalid_value(option: &str, value: &str, reason: &str) -> ConfigError
+{
+    ConfigError::InvalidValue { option: option.to_string(), value: value.to_string(), reason: reason.to_string() }
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    match value.parse::<T>()
+    {
+        Ok(number) => Ok(number),
+        Err(e) => Err(invalid_value(option, value, &e.to_string())),
+    }
+}
+
+fn parse_seconds(option: &str, value: &str) -> Result<Duration, ConfigError>
+{
+    parse_number::<u64>(option, value).map(Duration::from_secs)
+}
+
+fn type_error(entry: &Entry, expected: &str) -> String
+{
+    format!("\"{}\" must be {}, found {}", entry.key, expected, entry.value.type_name())
+}
+
+fn get_string(entry: &Entry) -> Result<String, String>
+{
+    match &entry.value
+    {
+        Value::String(value) => Ok(value.clone()),
+        _ => Err(type_error(entry, "a string")),
+    }
+}
+
+fn get_bool(entry: &Entry) -> Result<bool, String>
+{
+    match &entry.value
+    {
+        Value::Boolean(value) => Ok(*value),
+        _ => Err(type_error(entry, "a boolean")),
+    }
+}
+
+fn get_string_list(entry: &Entry) -> Result<Vec<String>, String>
+{
+    match &entry.value
+    {
+        Value::String(value) => Ok(vec![value.clone()]),
+        Value::Array(items) => items.iter()
+            .map(|item| match item
+            {
+                Value::String(value) => Ok(value.clone()),
+                _ => Err(type_error(entry, "an array of strings")),
+            })
+            .collect(),
+        _ => Err(type_error(entry, "an array of strings")),
+    }
+}
+
+/// Read an integer setting that must be at least `min`.
+fn get_number<T: TryFrom<i64>>(entry: &Entry, min: i64) -> Result<T, String>
+{
+    match &entry.value
+    {
+        Value::Integer(value) if *value < min => Err(format!("\"{}\" must be at least {}, found {}", entry.key, min, value)),
+        Value::Integer(value) => match T::try_from(*value)
+        {
+            Ok(value) => Ok(value),
+            Err(_) => Err(format!("\"{}\" is out of range", entry.key)),
+        },
+        _ => Err(type_error(entry, "an integer")),
+    }
+}
+
+fn get_seconds(entry: &Entry, min: i64) -> Result<Duration, String>
+{
+    get_number::<u64>(entry, min).map(Duration::from_secs)
+}
+
+
+/// Configuration shared between threads.
+///
+/// A reload swaps the whole `Config` at once, users holding the previous
+/// `Arc` keep working with it until they drop it.
+pub struct SharedConfig
+{
+    current: RwLock<Arc<Config>>,
+}
+
+impl SharedConfig
+{
+    pub fn new(config: Config) -> SharedConfig
+    {
+        SharedConfig { current: RwLock::new(Arc::new(config)) }
+    }
+
+    pub fn get(&self) -> Arc<Config>
+    {
+        Arc::clone(&self.current.read().expect("RwLock poisoned"))
+    }
+
+    pub fn replace(&self, config: Config)
+    {
+        *self.current.write().expect("RwLock poisoned") = Arc::new(config);
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn build(args: &[&str]) -> Result<Config, ConfigError>
+    {
+        let args: Vec<String> = std::iter::once("webserver").chain(args.iter().copied()).map(String::from).collect();
+        Config::build(&args)
+    }
+
+    fn invalid_option(args: &[&str]) -> String
+    {
+        match build(args)
+        {
+            Err(ConfigError::InvalidValue { option, .. }) => option,
+            other => panic!("expected an invalid value, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn defaults_apply_without_options()
+    {
+        let config = build(&[]).unwrap();
+        assert_eq!(config.listen, ["[::]:7878"]);
+        assert_eq!(config.threads, DEFAULT_THREADS);
+        assert_eq!(config.min_threads(), DEFAULT_THREADS);
+        assert_eq!(config.backend, Backend::Threads);
+        assert!(config.file_path.ends_with('/'));
+    }
+
+    #[test]
+    fn options_are_applied()
+    {
+        let config = build(&["-H", "127.0.0.1", "--port=8080", "-t", "8", "--min-threads", "2", "--backend", "epoll",
+            "--algorithm", "gcra", "--window=30", "--clean-delay", "5", "--ipv6-prefix", "64", "--trusted-proxies", "10.0.0.0/8, ::1",
+            "--connection-policy", "queue", "--bandwidth-total", "1000", "--follow-symlinks", "."]).unwrap();
+
+        assert_eq!(config.listen, ["127.0.0.1:8080"]);
+        assert_eq!((config.threads, config.min_threads()), (8, 2));
+        assert_eq!(config.backend, Backend::Epoll);
+        assert_eq!(config.limiter.algorithm, Algorithm::Gcra);
+        assert_eq!(config.limiter.window, Duration::from_secs(30));
+        assert_eq!(config.limiter.clean_delay, Duration::from_secs(5));
+        assert_eq!(config.limiter.ipv6_prefix, 64);
+        assert_eq!(config.proxy.trusted.len(), 2);
+        assert_eq!(config.connections.policy, ConnectionPolicy::Queue);
+        assert_eq!(config.bandwidth.total, 1000);
+        assert_eq!(config.symlinks, SymlinkPolicy::Follow);
+    }
+
+    #[test]
+    fn ipv6_hosts_are_bracketed()
+    {
+        assert_eq!(build(&["-H", "::1", "-p", "80"]).unwrap().listen, ["[::1]:80"]);
+    }
+
+    #[test]
+    fn malformed_arguments_are_refused()
+    {
+        assert!(matches!(build(&["--bogus"]), Err(ConfigError::UnknownArgument(_))));
+        assert!(matches!(build(&[".", "extra"]), Err(ConfigError::UnknownArgument(_))));
+        assert!(matches!(build(&["--port"]), Err(ConfigError::MissingValue(_))));
+        assert!(matches!(build(&["--help"]), Err(ConfigError::HelpRequested)));
+        assert!(matches!(build(&["-V"]), Err(ConfigError::VersionRequested)));
+        assert!(matches!(build(&["/nonexistent/webserver-root"]), Err(ConfigError::InvalidRoot(_))));
+        assert_eq!(invalid_option(&["--port", "99999"]), "--port");
+        assert_eq!(invalid_option(&["--threads", "many"]), "--threads");
+        assert_eq!(invalid_option(&["--backend", "kqueue"]), "--backend");
+        assert_eq!(invalid_option(&["--trusted-proxies", "10.0.0.0/40"]), "--trusted-proxies");
+    }
+
+    #[test]
+    fn out_of_range_values_are_refused()
+    {
+        assert_eq!(invalid_option(&["--threads", "0"]), "--threads");
+        assert_eq!(invalid_option(&["-t", "2", "--min-threads", "3"]), "--min-threads");
+        assert_eq!(invalid_option(&["--header-timeout", "0"]), "--header-timeout");
+        assert_eq!(invalid_option(&["--process-timeout", "0"]), "--process-timeout");
+        assert_eq!(invalid_option(&["--keep-alive-timeout", "0"]), "--keep-alive-timeout");
+        assert_eq!(invalid_option(&["--keep-alive-requests", "0"]), "--keep-alive-requests");
+        assert_eq!(invalid_option(&["--max-headers", "0"]), "--max-headers");
+        assert_eq!(invalid_option(&["--window", "0"]), "--window");
+        assert_eq!(invalid_option(&["--clean-delay", "0"]), "--clean-delay");
+        assert_eq!(invalid_option(&["--ipv6-prefix", "129"]), "--ipv6-prefix");
+        assert_eq!(invalid_option(&["--max-clients", "0"]), "--max-clients");
+        assert_eq!(invalid_option(&["--proxy-protocol"]), "--proxy-protocol");
+    }
+
+    /// Build from a configuration file holding `source`, plus `options`.
+    fn build_with_file(name: &str, source: &str, options: &[&str]) -> Result<Config, ConfigError>
+    {
+        let path = std::env::temp_dir().join(format!("webserver-config-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, source).unwrap();
+        let mut args = vec!["-c", path.to_str().unwrap(), "."];
+        args.extend_from_slice(options);
+
+        let result = build(&args);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn file_error(name: &str, source: &str) -> (usize, String)
+    {
+        match build_with_file(name, source, &[])
+        {
+            Err(ConfigError::File { line, message, .. }) => (line, message),
+            other => panic!("expected a file error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn file_settings_are_applied()
+    {
+        let source = "[server]\nlisten = [\n  \"127.0.0.1:8080\",\n  \"[::1]:8080\",\n]\nthreads = 4\nmin_threads = 1\n\n[timeouts]\nheader = 5\nkeep_alive = 2\nkeep_alive_requests = 3\n\n[limiter.api]\npath = \"/api\"\nmethods = [\"POST\"]\nmax_requests = 5\nwindow = 60\n";
+        let config = build_with_file("applied", source, &[]).unwrap();
 
-        Config { file_path: path }
+        assert_eq!(config.listen, ["127.0.0.1:8080", "[::1]:8080"]);
+        assert_eq!((config.threads, config.min_threads()), (4, 1));
+        assert_eq!(config.timeouts.header, Duration::from_secs(5));
+        assert_eq!((config.timeouts.keep_alive, config.timeouts.keep_alive_requests), (Duration::from_secs(2), 3));
+        assert_eq!(config.limiter.rules.len(), 1);
+        assert_eq!(config.limiter.rules[0].name, "api");
+
+        // The command line wins over the file
+        let config = build_with_file("override", source, &["--threads", "8", "--header-timeout", "7"]).unwrap();
+        assert_eq!((config.threads, config.timeouts.header), (8, Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn file_errors_carry_their_line()
+    {
+        assert_eq!(file_error("syntax", "[server]\nthreads = 4\nthreads = 5\n"), (3, String::from("key \"threads\" is defined twice")));
+        assert_eq!(file_error("section", "[server]\n[server]\n"), (2, String::from("section \"server\" is defined twice")));
+        assert_eq!(file_error("unknown-key", "[server]\nthreads = 4\nthread = 5\n"), (3, String::from("unknown key \"thread\" in [server]")));
+        assert_eq!(file_error("unknown-section", "\n[servers]\n"), (2, String::from("unknown section [servers]")));
+        assert_eq!(file_error("outside", "threads = 4\n"), (1, String::from("key \"threads\" must be inside a section")));
+        assert_eq!(file_error("type", "[server]\nthreads = \"4\"\n"), (2, String::from("\"threads\" must be an integer, found string")));
+        assert_eq!(file_error("list-type", "[proxy]\ntrusted = [1]\n"), (2, String::from("\"trusted\" must be an array of strings, found array")));
+        assert_eq!(file_error("bool-type", "[proxy]\nprotocol = 1\n"), (2, String::from("\"protocol\" must be a boolean, found integer")));
+        assert_eq!(file_error("minimum", "[timeouts]\nheader = 0\n"), (2, String::from("\"header\" must be at least 1, found 0")));
+        assert_eq!(file_error("range", "[limiter]\nipv6_prefix = 300\n"), (2, String::from("\"ipv6_prefix\" is out of range")));
+        assert_eq!(file_error("rule", "[limiter.api]\npath = \"api\"\n").0, 2);
+    }
+
+    #[test]
+    fn cross_field_errors_point_at_the_file()
+    {
+        let (line, message) = file_error("min-threads", "[server]\nthreads = 2\nmin_threads = 3\n");
+        assert_eq!(line, 3);
+        assert_eq!(message, "invalid value \"3\" for \"min_threads\": must not be above the number of threads");
+
+        assert_eq!(file_error("prefix", "[limiter]\n\nipv6_prefix = 129\n").0, 3);
+        assert_eq!(file_error("protocol", "[proxy]\nprotocol = true\n").0, 2);
+
+        // Given on the command line, the value is blamed on the option
+        let result = build_with_file("min-threads-option", "[server]\nthreads = 2\nmin_threads = 1\n", &["--min-threads", "3"]);
+        assert!(matches!(result, Err(ConfigError::InvalidValue { option, .. }) if option == "--min-threads"));
     }
+
 }
@@ -0,0 +1,417 @@
+//! Minimal parser for the TOML subset used by the configuration file.
+//!
+//! Supported: `[section]` / `[section.name]` headers, `key = value` pairs with bare
+//! or quoted keys, basic strings, integers, booleans and (possibly multi-line)
+//! arrays of those. Every value remembers the line it was defined on, so
+//! validation errors can point at it.
+
+use std::fmt;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value
+{
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value
+{
+    pub fn type_name(&self) -> &'static str
+    {
+        match self
+        {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry
+{
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct Section
+{
+    /// Empty for keys defined before the first section header.
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+pub struct Document
+{
+    pub sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+pub struct ParseError
+{
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+
+impl Document
+{
+    pub fn parse(source: &str) -> Result<Document, ParseError>
+    {
+        let mut sections = vec![Section { name: String::new(), line: 0, entries: Vec::new() }];
+        let mut lines = source.lines().enumerate().map(|(i, line)| (i + 1, line));
+
+        while let Some((line_number, line)) = lines.next()
+        {
+            let error = |message: String| ParseError { line: line_number, message };
+            let trimmed = strip_comment(line).trim();
+
+            if trimmed.is_empty()
+            {
+                continue;
+            }
+
+            if let Some(header) = trimmed.strip_prefix('[')
+            {
+                let name = match header.strip_suffix(']')
+                {
+                    Some(name) => name.trim(),
+                    None => return Err(error(String::from("unterminated section header"))),
+                };
+
+                if name.is_empty() || !name.split('.').all(|part| !part.is_empty() && part.chars().all(is_bare_key_char))
+                {
+                    return Err(error(format!("invalid section name \"{}\"", name)));
+                }
+
+                if sections.iter().any(|section| section.name == name)
+                {
+                    return Err(error(format!("section \"{}\" is defined twice", name)));
+                }
+
+                sections.push(Section { name: name.to_string(), line: line_number, entries: Vec::new() });
+                continue;
+            }
+
+            let (key, rest) = parse_key(trimmed).map_err(error)?;
+
+            let rest = match rest.trim_start().strip_prefix('=')
+            {
+                Some(rest) => rest.trim(),
+                None => return Err(ParseError { line: line_number, message: format!("expected \"=\" after key \"{}\"", key) }),
+            };
+
+            // Arrays may span several lines, keep reading until the brackets balance
+            let mut value_source = rest.to_string();
+            while value_source.starts_with('[') && !brackets_closed(&value_source)
+            {
+                match lines.next()
+                {
+                    Some((_, next)) =>
+                    {
+                        value_source.push(' ');
+                        value_source.push_str(strip_comment(next).trim());
+                    },
+                    None => return Err(ParseError { line: line_number, message: String::from("unterminated array") }),
+                }
+            }
+
+            let mut cursor = Cursor { chars: value_source.chars().collect(), pos: 0 };
+            let value = cursor.parse_value().map_err(|message| ParseError { line: line_number, message })?;
+            cursor.skip_whitespace();
+            if !cursor.is_done()
+            {
+                return Err(ParseError { line: line_number, message: format!("unexpected characters after value of \"{}\"", key) });
+            }
+
+            let section = sections.last_mut().unwrap();
+            if section.entries.iter().any(|entry| entry.key == key)
+            {
+                return Err(ParseError { line: line_number, message: format!("key \"{}\" is defined twice", key) });
+            }
+            section.entries.push(Entry { key, value, line: line_number });
+        }
+
+        Ok(Document { sections })
+    }
+}
+
+
+fn is_bare_key_char(c: char) -> bool
+{
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Cut a `#` comment, ignoring `#` inside strings.
+fn strip_comment(line: &str) -> &str
+{
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices()
+    {
+        match c
+        {
+            '\\' if in_string && !escaped => { escaped = true; continue; },
+            '"' if !escaped => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+        escaped = false;
+    }
+
+    line
+}
+
+fn brackets_closed(source: &str) -> bool
+{
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in source.chars()
+    {
+        match c
+        {
+            '\\' if in_string && !escaped => { escaped = true; continue; },
+            '"' if !escaped => in_string = !in_string,
+            '[' if !in_string => depth += 1,
+            ']' if !in_string => depth -= 1,
+            _ => (),
+        }
+        escaped = false;
+    }
+
+    depth <= 0
+}
+
+fn parse_key(line: &str) -> Result<(String, &str), String>
+{
+    if line.starts_with('"')
+    {
+        let mut cursor = Cursor { chars: line.chars().collect(), pos: 0 };
+        let key = cursor.parse_string()?;
+        let consumed: usize = cursor.chars[..cursor.pos].iter().map(|c| c.len_utf8()).sum();
+        return Ok((key, &line[consumed..]));
+    }
+
+    let end = line.find(|c: char| !is_bare_key_char(c)).unwrap_or(line.len());
+    if end == 0
+    {
+        return Err(format!("expected a key, found \"{}\"", line));
+    }
+
+    Ok((line[..end].to_string(), &line[end..]))
+}
+
+
+struct Cursor
+{
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Cursor
+{
+    fn is_done(&self) -> bool
+    {
+        self.pos >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char>
+    {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self)
+    {
+        while matches!(self.peek(), Some(c) if c.is_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String>
+    {
+        self.skip_whitespace();
+
+        match self.peek()
+        {
+            Some('"') => Ok(Value::String(self.parse_string()?)),
+            Some('[') => self.parse_array(),
+            Some(_) =>
+            {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+')
+                {
+                    self.pos += 1;
+                }
+                let word: String = self.chars[start..self.pos].iter().collect();
+
+                match word.as_str()
+                {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    "" => Err(format!("unexpected character '{}'", self.chars[start])),
+                    _ => match word.replace('_', "").parse::<i64>()
+                    {
+                        Ok(number) => Ok(Value::Integer(number)),
+                        Err(_) => Err(format!("invalid value \"{}\" (strings must be quoted)", word)),
+                    },
+                }
+            },
+            None => Err(String::from("missing value")),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String>
+    {
+        // Skip the opening quote
+        self.pos += 1;
+        let mut result = String::new();
+
+        loop
+        {
+            let c = match self.peek()
+            {
+                Some(c) => c,
+                None => return Err(String::from("unterminated string")),
+            };
+            self.pos += 1;
+
+            match c
+            {
+                '"' => return Ok(result),
+                '\\' =>
+                {
+                    let escaped = match self.peek()
+                    {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some(other) => return Err(format!("unknown escape sequence \"\\{}\"", other)),
+                        None => return Err(String::from("unterminated string")),
+                    };
+                    self.pos += 1;
+                    result.push(escaped);
+                },
+                _ => result.push(c),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, String>
+    {
+        // Skip the opening bracket
+        self.pos += 1;
+        let mut items = Vec::new();
+
+        loop
+        {
+            self.skip_whitespace();
+            if self.peek() == Some(']')
+            {
+                self.pos += 1;
+                return Ok(Value::Array(items));
+            }
+
+            items.push(self.parse_value()?);
+
+            self.skip_whitespace();
+            match self.peek()
+            {
+                Some(',') => self.pos += 1,
+                Some(']') => (),
+                Some(c) => return Err(format!("expected \",\" or \"]\" in array, found '{}'", c)),
+                None => return Err(String::from("unterminated array")),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn error(source: &str) -> (usize, String)
+    {
+        let e = Document::parse(source).unwrap_err();
+        (e.line, e.message)
+    }
+
+    #[test]
+    fn values_are_parsed_with_their_line()
+    {
+        let source = "# comment\n[server]\nthreads = 1_000  # workers\n\"quoted key\" = \"a # b\\n\"\n\n[limiter.api]\nflag = false\n";
+        let document = Document::parse(source).unwrap();
+
+        assert_eq!(document.sections.len(), 3);
+        let server = &document.sections[1];
+        assert_eq!((server.name.as_str(), server.line), ("server", 2));
+        assert_eq!((server.entries[0].key.as_str(), &server.entries[0].value, server.entries[0].line), ("threads", &Value::Integer(1000), 3));
+        assert_eq!((server.entries[1].key.as_str(), &server.entries[1].value), ("quoted key", &Value::String(String::from("a # b\n"))));
+        assert_eq!(document.sections[2].name, "limiter.api");
+        assert_eq!(document.sections[2].entries[0].value, Value::Boolean(false));
+    }
+
+    #[test]
+    fn arrays_may_span_several_lines()
+    {
+        let source = "[proxy]\ntrusted = [\n  \"10.0.0.0/8\",  # inner\n  \"::1\",\n]\nprotocol = true\n";
+        let document = Document::parse(source).unwrap();
+        let entries = &document.sections[1].entries;
+
+        let expected = Value::Array(vec![Value::String(String::from("10.0.0.0/8")), Value::String(String::from("::1"))]);
+        assert_eq!((&entries[0].value, entries[0].line), (&expected, 2));
+        assert_eq!(entries[1].line, 6);
+
+        assert_eq!(error("[proxy]\ntrusted = [\n\"a\",\n"), (2, String::from("unterminated array")));
+        assert_eq!(error("a = [1 2]").0, 1);
+        assert_eq!(Document::parse("a = [[1], []]").unwrap().sections[0].entries[0].value, Value::Array(vec![Value::Array(vec![Value::Integer(1)]), Value::Array(Vec::new())]));
+    }
+
+    #[test]
+    fn duplicates_are_refused()
+    {
+        assert_eq!(error("[server]\nthreads = 1\n\nthreads = 2\n"), (4, String::from("key \"threads\" is defined twice")));
+        assert_eq!(error("[server]\n[limits]\n[server]\n"), (3, String::from("section \"server\" is defined twice")));
+
+        // The same key in different sections is fine
+        assert!(Document::parse("[a]\nkey = 1\n[b]\nkey = 1\n").is_ok());
+    }
+
+    #[test]
+    fn malformed_lines_are_refused_with_their_number()
+    {
+        assert_eq!(error("\n[server\n"), (2, String::from("unterminated section header")));
+        assert_eq!(error("[bad name]"), (1, String::from("invalid section name \"bad name\"")));
+        assert_eq!(error("[a..b]").0, 1);
+        assert_eq!(error("[a]\nkey 1\n"), (2, String::from("expected \"=\" after key \"key\"")));
+        assert_eq!(error("[a]\nkey =\n"), (2, String::from("missing value")));
+        assert_eq!(error("[a]\nkey = bare\n"), (2, String::from("invalid value \"bare\" (strings must be quoted)")));
+        assert_eq!(error("[a]\nkey = \"open\n"), (2, String::from("unterminated string")));
+        assert_eq!(error("[a]\nkey = \"\\x\"\n"), (2, String::from("unknown escape sequence \"\\x\"")));
+        assert_eq!(error("[a]\nkey = 1 2\n"), (2, String::from("unexpected characters after value of \"key\"")));
+        assert_eq!(error("[a]\n= 1\n").0, 2);
+    }
+}
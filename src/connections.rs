@@ -0,0 +1,398 @@
+//! Caps on the connections open at the same time, per client and overall.
+//!
+//! Every admitted connection holds a `ConnectionGuard`, its slot is given back
+//! when the guard is dropped. Connections over a cap are refused right away,
+//! or with `policy = "queue"` wait for a slot until `queue_timeout`.
+
+use std::
+{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Instant,
+};
+
+use crate::cidr;
+use crate::config::{ConnectionConfig, ConnectionPolicy};
+use crate::logger::Logger;
+
+
+/// Called once the connection gets a slot, or is refused.
+type Start = Box<dyn FnOnce(Result<ConnectionGuard, Refusal>) + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refusal
+{
+    /// The client has `max_per_client` connections open.
+    TooManyFromClient,
+    /// `max_total` connections are open.
+    TooManyConnections,
+}
+
+impl fmt::Display for Refusal
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Refusal::TooManyFromClient => write!(f, "too many connections from the client"),
+            Refusal::TooManyConnections => write!(f, "too many connections"),
+        }
+    }
+}
+
+struct Waiting
+{
+    /// `None` when the client is not capped.
+    key: Option<IpAddr>,
+    since: Instant,
+    start: Start,
+}
+
+struct State
+{
+    settings: ConnectionConfig,
+    ipv6_prefix: u8,
+    total: usize,
+    per_client: HashMap<IpAddr, usize>,
+    waiting: VecDeque<Waiting>,
+    /// Set when the sweeper is stopped.
+    stopped: bool,
+}
+
+impl State
+{
+    /// When the connection waiting the longest is refused.
+    fn next_timeout(&self) -> Option<Instant>
+    {
+        self.waiting.front().map(|waiting| waiting.since + self.settings.queue_timeout)
+    }
+
+    /// Why a connection from `key` cannot be opened now, if it cannot.
+    fn refusal(&self, key: Option<IpAddr>) -> Option<Refusal>
+    {
+        let settings = &self.settings;
+        if settings.max_total > 0 && self.total >= settings.max_total
+        {
+            return Some(Refusal::TooManyConnections);
+        }
+
+        let open = key.and_then(|key| self.per_client.get(&key)).copied().unwrap_or(0);
+        if settings.max_per_client > 0 && open >= settings.max_per_client
+        {
+            return Some(Refusal::TooManyFromClient);
+        }
+
+        None
+    }
+
+    fn take(&mut self, key: Option<IpAddr>)
+    {
+        self.total += 1;
+        if let Some(key) = key
+        {
+            *self.per_client.entry(key).or_insert(0) += 1;
+        }
+    }
+
+    fn give_back(&mut self, key: Option<IpAddr>)
+    {
+        self.total = self.total.saturating_sub(1);
+        if let Some(key) = key
+        {
+            if let Some(open) = self.per_client.get_mut(&key)
+            {
+                *open -= 1;
+                if *open == 0
+                {
+                    self.per_client.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+pub struct ConnectionLimits
+{
+    state: Mutex<State>,
+    /// Wakes the sweeper when a connection starts waiting, the settings
+    /// change or it is stopped.
+    changed: Condvar,
+}
+
+impl ConnectionLimits
+{
+    /// `ipv6_prefix` groups IPv6 clients the way the rate limiter does.
+    pub fn new(settings: &ConnectionConfig, ipv6_prefix: u8) -> ConnectionLimits
+    {
+        let state = State
+        {
+            settings: settings.clone(),
+            ipv6_prefix,
+            total: 0,
+            per_client: HashMap::new(),
+            waiting: VecDeque::new(),
+            stopped: false,
+        };
+        ConnectionLimits { state: Mutex::new(state), changed: Condvar::new() }
+    }
+
+    /// Apply new caps, waiting connections they let in are started.
+    pub fn reconfigure(self: &Arc<Self>, settings: &ConnectionConfig, ipv6_prefix: u8)
+    {
+        {
+            let mut state = self.state.lock().expect("Mutex poisoned");
+            state.settings = settings.clone();
+            state.ipv6_prefix = ipv6_prefix;
+        }
+        self.changed.notify_all();
+        self.expire();
+    }
+
+    /// Open connections and connections waiting for a slot.
+    pub fn counts(&self) -> (usize, usize)
+    {
+        let state = self.state.lock().expect("Mutex poisoned");
+        (state.total, state.waiting.len())
+    }
+
+    /// Take a slot for a new connection from `client` and call `start` with
+    /// its guard.
+    ///
+    /// `client` is `None` for peers that are not capped on their own (trusted
+    /// proxies). Without a free slot, `start` is called with the refusal, or
+    /// later on, from the thread that frees a slot or times the wait out.
+    pub fn admit<F>(self: &Arc<Self>, client: Option<IpAddr>, start: F)
+    where
+        F: FnOnce(Result<ConnectionGuard, Refusal>) + Send + 'static,
+    {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        let key = client.map(|client| cidr::client_key(client, state.ipv6_prefix));
+
+        let result = match state.refusal(key)
+        {
+            None =>
+            {
+                state.take(key);
+                Ok(ConnectionGuard { limits: Arc::clone(self), key })
+            },
+            Some(_) if state.settings.policy == ConnectionPolicy::Queue && state.waiting.len() < state.settings.queue_size =>
+            {
+                state.waiting.push_back(Waiting { key, since: Instant::now(), start: Box::new(start) });
+                self.changed.notify_all();
+                return;
+            },
+            Some(refusal) => Err(refusal),
+        };
+
+        // Starting may drop the guard, which locks again
+        drop(state);
+        start(result);
+    }
+
+    /// Refuse the connections that waited for `queue_timeout` on a thread of
+    /// its own, until the returned `Sweeper` is stopped.
+    ///
+    /// Freed slots already start the waiting connections, this only times out
+    /// the ones no slot is freed for.
+    pub fn run_sweeper(self: &Arc<Self>) -> Sweeper
+    {
+        let limits = Arc::clone(self);
+        let thread = thread::spawn(move || loop
+        {
+            let mut state = limits.state.lock().expect("Mutex poisoned");
+            loop
+            {
+                if state.stopped
+                {
+                    return;
+                }
+
+                let now = Instant::now();
+                state = match state.next_timeout()
+                {
+                    Some(timeout) if timeout <= now => break,
+                    Some(timeout) => limits.changed.wait_timeout(state, timeout - now).expect("Mutex poisoned").0,
+                    None => limits.changed.wait(state).expect("Mutex poisoned"),
+                };
+            }
+
+            drop(state);
+            limits.expire();
+        });
+
+        Sweeper { limits: Arc::clone(self), thread: Some(thread) }
+    }
+
+    /// Start the waiting connections that fit, refuse the ones that waited
+    /// for too long.
+    fn expire(self: &Arc<Self>)
+    {
+        let mut ready = Vec::new();
+        {
+            let mut state = self.state.lock().expect("Mutex poisoned");
+            let now = Instant::now();
+
+            let mut index = 0;
+            while index < state.waiting.len()
+            {
+                let waiting = &state.waiting[index];
+                let outcome = match state.refusal(waiting.key)
+                {
+                    None => None,
+                    Some(refusal) if state.settings.policy == ConnectionPolicy::Reject || now.duration_since(waiting.since) >= state.settings.queue_timeout => Some(refusal),
+                    Some(_) =>
+                    {
+                        index += 1;
+                        continue;
+                    }
+                };
+
+                let waiting = state.waiting.remove(index).expect("index is in range");
+                match outcome
+                {
+                    None =>
+                    {
+                        state.take(waiting.key);
+                        ready.push((waiting.start, Ok(ConnectionGuard { limits: Arc::clone(self), key: waiting.key })));
+                    },
+                    Some(refusal) => ready.push((waiting.start, Err(refusal))),
+                }
+            }
+        }
+
+        for (start, result) in ready
+        {
+            start(result);
+        }
+    }
+
+    fn release(self: &Arc<Self>, key: Option<IpAddr>)
+    {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        state.give_back(key);
+        let waiting = !state.waiting.is_empty();
+        drop(state);
+
+        if waiting
+        {
+            self.expire();
+        }
+    }
+}
+
+/// Slot of an open connection, given back when dropped.
+pub struct ConnectionGuard
+{
+    limits: Arc<ConnectionLimits>,
+    key: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard
+{
+    fn drop(&mut self)
+    {
+        self.limits.release(self.key);
+    }
+}
+
+/// Handle of the thread started by `ConnectionLimits::run_sweeper`, stopping
+/// it when dropped.
+pub struct Sweeper
+{
+    limits: Arc<ConnectionLimits>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Sweeper
+{
+    /// Stop the thread and wait for it.
+    pub fn stop(self)
+    {
+        drop(self);
+    }
+}
+
+impl Drop for Sweeper
+{
+    fn drop(&mut self)
+    {
+        self.limits.state.lock().expect("Mutex poisoned").stopped = true;
+        self.limits.changed.notify_all();
+
+        if let Some(thread) = self.thread.take()
+        {
+            if thread.join().is_err()
+            {
+                Logger::printmsg(Logger::ThreadErr, String::from("Connection queue sweeper has panicked"));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{net::Ipv4Addr, sync::mpsc, time::Duration};
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn queueing(max_total: usize, queue_timeout: Duration) -> Arc<ConnectionLimits>
+    {
+        let settings = ConnectionConfig
+        {
+            max_per_client: 0,
+            max_total,
+            policy: ConnectionPolicy::Queue,
+            queue_size: 8,
+            queue_timeout,
+        };
+        Arc::new(ConnectionLimits::new(&settings, 128))
+    }
+
+    /// Admit a connection from `CLIENT`, its outcome is sent on the returned
+    /// channel.
+    fn admit(limits: &Arc<ConnectionLimits>) -> mpsc::Receiver<Result<ConnectionGuard, Refusal>>
+    {
+        let (sender, receiver) = mpsc::channel();
+        limits.admit(Some(CLIENT), move |slot|
+        {
+            let _ = sender.send(slot);
+        });
+        receiver
+    }
+
+    #[test]
+    fn freed_slots_start_waiting_connections()
+    {
+        let limits = queueing(1, Duration::from_secs(60));
+        let first = admit(&limits).try_recv().unwrap().unwrap();
+
+        let second = admit(&limits);
+        assert!(second.try_recv().is_err());
+        assert_eq!(limits.counts(), (1, 1));
+
+        drop(first);
+        let _second = second.try_recv().unwrap().expect("the freed slot is taken");
+        assert_eq!(limits.counts(), (1, 0));
+    }
+
+    #[test]
+    fn waiting_connections_time_out_without_a_freed_slot()
+    {
+        let limits = queueing(1, Duration::from_millis(100));
+        let sweeper = limits.run_sweeper();
+        let _first = admit(&limits).try_recv().unwrap().unwrap();
+
+        let second = admit(&limits);
+        let outcome = second.recv_timeout(Duration::from_secs(5)).expect("the wait is timed out");
+        assert_eq!(outcome.err(), Some(Refusal::TooManyConnections));
+        assert_eq!(limits.counts(), (1, 0));
+
+        sweeper.stop();
+    }
+}
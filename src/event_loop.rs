@@ -0,0 +1,1304 @@
+//! Event-driven connection handling, used with `backend = "epoll"`.
+//!
+//! A single thread multiplexes every socket with mio (epoll on Linux) and only
+//! hands complete requests to the pool, so an idle keep-alive connection costs
+//! a buffer instead of a worker. Workers send the responses back through a
+//! channel and wake the loop up to write them.
+
+use std::
+{
+    collections::HashMap,
+    io::{self, BufRead, ErrorKind, Read, Write},
+    net::{self, IpAddr, SocketAddr},
+    sync::{mpsc, Arc, atomic::Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use mio::{Events, Interest, Poll, Token, Waker, net::{TcpListener, TcpStream}};
+
+use webserver::ThreadPool;
+use webserver::logger::Logger;
+
+use crate::config::SharedConfig;
+use crate::ban::Offense;
+use crate::connections::{ConnectionGuard, ConnectionLimits, Refusal};
+use crate::fileutils::{escapes_root, served_path};
+use crate::limiter::Policies;
+use crate::proxy::{self, Header};
+use crate::request::{self, Framing, Limits, ParseError, Request, Source, MAX_BODY_SIZE};
+use crate::response::{self, FileBody, Response};
+use crate::status::StatusCode;
+use crate::throttle::{Bandwidth, Pacer};
+use crate::{admit, banned, capped_client, forbidden, overloaded, refused, respond, too_many_requests, with_headers, with_rate_limit, SHUTTING_DOWN};
+
+
+const WAKER: Token = Token(usize::MAX);
+
+/// How often timeouts and the shutdown flag are checked when nothing happens.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Most bytes buffered for one connection, a request bigger than that can
+/// never be complete.
+const MAX_BUFFERED: usize = MAX_BODY_SIZE as usize + 64 * 1024;
+
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Bytes of a file body read at once.
+const FILE_CHUNK: u64 = 64 * 1024;
+
+
+#[derive(PartialEq)]
+enum State
+{
+    /// Waiting for a complete request.
+    Reading,
+    /// A worker is building the response.
+    Processing,
+    /// The response is being sent.
+    Writing,
+}
+
+struct Connection
+{
+    stream: TcpStream,
+    peer: String,
+    client: IpAddr,
+    /// The PROXY header that starts the connection has not been read yet.
+    awaiting_proxy_header: bool,
+    incoming: Incoming,
+    outgoing: Vec<u8>,
+    written: usize,
+    /// The part of a file body not read into `outgoing` yet.
+    file: Option<FileBody>,
+    state: State,
+    served: u32,
+    close_after_write: bool,
+    peer_closed: bool,
+    last_active: Instant,
+    /// When the first byte of the pending request arrived.
+    head_since: Option<Instant>,
+    /// When the head of the pending request was complete.
+    body_since: Option<Instant>,
+    /// When the request was handed to the pool.
+    process_since: Instant,
+    /// When the response started to be sent.
+    write_since: Instant,
+    /// Logged once the response is fully sent.
+    summary: Option<String>,
+    /// Paces the response being built or sent, when it is capped.
+    pacer: Option<Pacer>,
+    /// Given back to the connection caps when the connection is dropped.
+    _slot: ConnectionGuard,
+}
+
+/// A response built by a worker.
+struct Finished
+{
+    token: Token,
+    response: Response,
+    keep_alive: bool,
+    summary: Option<String>,
+}
+
+/// A new connection that got its slot, or was refused one.
+struct Admitted
+{
+    stream: TcpStream,
+    address: SocketAddr,
+    slot: Result<ConnectionGuard, Refusal>,
+}
+
+/// Hands the result of a job back to the loop.
+///
+/// Answers with 500 when the job panics before finishing, instead of leaving
+/// the client hanging.
+struct Completion
+{
+    token: Token,
+    sender: mpsc::Sender<Finished>,
+    waker: Arc<Waker>,
+}
+
+impl Completion
+{
+    fn send(&self, response: Response, keep_alive: bool, summary: Option<String>)
+    {
+        let finished = Finished { token: self.token, response, keep_alive, summary };
+        if self.sender.send(finished).is_ok()
+        {
+            if let Err(e) = self.waker.wake()
+            {
+                Logger::printmsg(Logger::ThreadErr, format!("Cannot wake up the event loop: {}", e));
+            }
+        }
+    }
+}
+
+impl Drop for Completion
+{
+    fn drop(&mut self)
+    {
+        if thread::panicking()
+        {
+            self.send(Response::fallback(StatusCode::InternalServerError).header("Connection", "close"), false, None);
+        }
+    }
+}
+
+
+pub struct EventLoop
+{
+    poll: Poll,
+    listeners: Vec<TcpListener>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    waker: Arc<Waker>,
+    sender: mpsc::Sender<Finished>,
+    reciever: mpsc::Receiver<Finished>,
+    /// Connections admitted by the caps, possibly after waiting for a slot.
+    admitted_sender: mpsc::Sender<Admitted>,
+    admitted: mpsc::Receiver<Admitted>,
+    pool: Arc<ThreadPool>,
+    shared: Arc<SharedConfig>,
+    rate_limiter: Arc<Policies>,
+    connection_limits: Arc<ConnectionLimits>,
+    bandwidth: Arc<Bandwidth>,
+    /// Connections waiting for bandwidth, with when to write again.
+    paused: HashMap<Token, Instant>,
+}
+
+impl EventLoop
+{
+    pub fn new(listeners: Vec<net::TcpListener>, pool: Arc<ThreadPool>, shared: Arc<SharedConfig>, rate_limiter: Arc<Policies>, connection_limits: Arc<ConnectionLimits>, bandwidth: Arc<Bandwidth>) -> io::Result<EventLoop>
+    {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+        // Listeners use the first tokens, connections the ones after them
+        let mut registered = Vec::with_capacity(listeners.len());
+        for (i, listener) in listeners.into_iter().enumerate()
+        {
+            listener.set_nonblocking(true)?;
+            let mut listener = TcpListener::from_std(listener);
+            poll.registry().register(&mut listener, Token(i), Interest::READABLE)?;
+            registered.push(listener);
+        }
+
+        let (sender, reciever) = mpsc::channel();
+        let (admitted_sender, admitted) = mpsc::channel();
+
+        Ok(EventLoop
+        {
+            poll,
+            next_token: registered.len(),
+            listeners: registered,
+            connections: HashMap::new(),
+            waker,
+            sender,
+            reciever,
+            admitted_sender,
+            admitted,
+            pool,
+            shared,
+            rate_limiter,
+            connection_limits,
+            bandwidth,
+            paused: HashMap::new(),
+        })
+    }
+
+    /// Serve connections until shutdown, then until the open ones are done.
+    pub fn run(mut self)
+    {
+        let mut events = Events::with_capacity(1024);
+        let mut last_sweep = Instant::now();
+
+        loop
+        {
+            // Paused writes may need to go on before the next tick
+            let now = Instant::now();
+            let timeout = self.paused.values().min().map_or(TICK, |until| until.saturating_duration_since(now).min(TICK));
+
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout))
+            {
+                if e.kind() == ErrorKind::Interrupted
+                {
+                    continue;
+                }
+                Logger::printmsg(Logger::ThreadErr, format!("Event loop failed: {}", e));
+                return;
+            }
+
+            for event in events.iter()
+            {
+                match event.token()
+                {
+                    // Admitted connections and finished responses are collected below
+                    WAKER => (),
+                    Token(i) if i < self.listeners.len() => self.accept(i),
+                    token =>
+                    {
+                        if event.is_readable() || event.is_read_closed()
+                        {
+                            self.read(token);
+                        }
+                        if event.is_writable()
+                        {
+                            self.flush(token);
+                        }
+                    }
+                }
+            }
+
+            self.collect_admitted();
+            self.collect_finished();
+            self.resume_paused();
+
+            if last_sweep.elapsed() >= TICK
+            {
+                self.expire();
+                last_sweep = Instant::now();
+            }
+
+            if SHUTTING_DOWN.load(Ordering::SeqCst)
+            {
+                if !self.listeners.is_empty()
+                {
+                    self.stop_accepting();
+                }
+
+                if self.connections.is_empty()
+                {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self, index: usize)
+    {
+        loop
+        {
+            let (mut stream, address) = match self.listeners[index].accept()
+            {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) =>
+                {
+                    Logger::printmsg(Logger::ThreadErr, format!("Failed to accept a connection: {}", e));
+                    return;
+                }
+            };
+
+            // Most likely the connection made to wake the loop up
+            if SHUTTING_DOWN.load(Ordering::SeqCst)
+            {
+                continue;
+            }
+
+            // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+            let stream_peer = address.ip().to_canonical();
+
+            // Only a proxy can start with a PROXY header
+            let config = self.shared.get();
+            if config.proxy.protocol && !proxy::is_trusted(&config.proxy.trusted, stream_peer)
+            {
+                Logger::printmsg(Logger::RequestErr, format!("Refusing the connection from {}, it is not a trusted proxy", stream_peer));
+                continue;
+            }
+
+            if let Err(response) = admit(stream_peer, &self.pool, &config, &self.rate_limiter)
+            {
+                // Small enough to fit in the send buffer of a fresh socket
+                if let Err(e) = response.write_to(&mut stream)
+                {
+                    Logger::printmsg(Logger::InfoErr, format!("Writing to stream is failed: {}", e));
+                }
+                continue;
+            }
+
+            // Whether the slot is free now or later, the connection comes
+            // back through the channel
+            let sender = self.admitted_sender.clone();
+            let waker = Arc::clone(&self.waker);
+            self.connection_limits.admit(capped_client(stream_peer, &config), move |slot|
+            {
+                if sender.send(Admitted { stream, address, slot }).is_ok()
+                {
+                    if let Err(e) = waker.wake()
+                    {
+                        Logger::printmsg(Logger::ThreadErr, format!("Cannot wake up the event loop: {}", e));
+                    }
+                }
+            });
+        }
+    }
+
+    /// Start watching the connections that got a slot.
+    fn collect_admitted(&mut self)
+    {
+        while let Ok(admitted) = self.admitted.try_recv()
+        {
+            let Admitted { mut stream, address, slot } = admitted;
+            let stream_peer = address.ip().to_canonical();
+            let config = self.shared.get();
+
+            let slot = match slot
+            {
+                Ok(slot) => slot,
+                Err(refusal) =>
+                {
+                    Logger::printmsg(Logger::Info, format!("Refusing the connection from {}: {}", stream_peer, refusal));
+                    if let Err(e) = refused(refusal, &config).write_to(&mut stream)
+                    {
+                        Logger::printmsg(Logger::InfoErr, format!("Writing to stream is failed: {}", e));
+                    }
+                    continue;
+                }
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)
+            {
+                Logger::printmsg(Logger::ThreadErr, format!("Cannot watch the connection from {}: {}", address, e));
+                continue;
+            }
+
+            self.connections.insert(token, Connection
+            {
+                stream,
+                peer: address.to_string(),
+                client: stream_peer,
+                awaiting_proxy_header: config.proxy.protocol,
+                incoming: Incoming::default(),
+                outgoing: Vec::new(),
+                written: 0,
+                file: None,
+                state: State::Reading,
+                served: 0,
+                close_after_write: false,
+                peer_closed: false,
+                last_active: Instant::now(),
+                head_since: None,
+                body_since: None,
+                process_since: Instant::now(),
+                write_since: Instant::now(),
+                summary: None,
+                pacer: None,
+                _slot: slot,
+            });
+        }
+    }
+
+    /// Read everything available, then try to parse a request.
+    fn read(&mut self, token: Token)
+    {
+        let connection = match self.connections.get_mut(&token)
+        {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let mut buf = [0; READ_CHUNK];
+        while !connection.peer_closed && connection.incoming.received.len() <= MAX_BUFFERED
+        {
+            match connection.stream.read(&mut buf)
+            {
+                Ok(0) => connection.peer_closed = true,
+                Ok(read) =>
+                {
+                    connection.incoming.received.extend_from_slice(&buf[..read]);
+                    connection.last_active = Instant::now();
+                    connection.head_since.get_or_insert(connection.last_active);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) =>
+                {
+                    Logger::printmsg(Logger::RequestErr, format!("Cannot read from {}: {}", connection.peer, e));
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+
+        self.advance(token);
+    }
+
+    fn advance(&mut self, token: Token)
+    {
+        let config = self.shared.get();
+        let connection = match self.connections.get_mut(&token)
+        {
+            Some(connection) if connection.state == State::Reading => connection,
+            _ => return,
+        };
+
+        if connection.awaiting_proxy_header
+        {
+            match proxy::parse_header(&connection.incoming.received)
+            {
+                Ok(Some((header, consumed))) =>
+                {
+                    connection.incoming.received.drain(..consumed);
+                    connection.awaiting_proxy_header = false;
+                    if let Header::Proxied(source) = header
+                    {
+                        connection.client = source.ip().to_canonical();
+                        connection.peer = source.to_string();
+                    }
+                },
+                Ok(None) =>
+                {
+                    if connection.peer_closed
+                    {
+                        self.close(token);
+                    }
+                    return;
+                },
+                Err(e) =>
+                {
+                    Logger::printmsg(Logger::RequestErr, format!("Closing the connection from {}: {}", connection.peer, e));
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+
+        let result = connection.incoming.parse(&config.request_limits);
+        if connection.incoming.head.is_some() && connection.body_since.is_none()
+        {
+            connection.body_since = Some(Instant::now());
+        }
+
+        match result
+        {
+            Ok(request) =>
+            {
+                connection.head_since = None;
+                connection.body_since = None;
+                self.dispatch(token, request);
+            },
+            // The rest of the request has not arrived yet
+            Err(ParseError::Idle) if connection.peer_closed =>
+            {
+                if connection.served == 0
+                {
+                    Logger::printmsg(Logger::RequestErr, String::from("Got zero length request"));
+                }
+                self.close(token);
+            },
+            Err(ParseError::Timeout) if connection.peer_closed =>
+            {
+                Logger::printmsg(Logger::RequestErr, format!("Malformed request from {}: {}", connection.peer, ParseError::UnexpectedEof));
+                self.close(token);
+            },
+            Err(ParseError::Idle | ParseError::Timeout) =>
+            {
+                if connection.incoming.received.len() > MAX_BUFFERED
+                {
+                    Logger::printmsg(Logger::RequestErr, format!("Malformed request from {}: {}", connection.peer, ParseError::PayloadTooLarge));
+                    self.send_error(token, StatusCode::PayloadTooLarge);
+                }
+            },
+            Err(e) =>
+            {
+                Logger::printmsg(Logger::RequestErr, format!("Malformed request from {}: {}", connection.peer, e));
+                if e.is_malformed()
+                {
+                    self.rate_limiter.bans().offense(connection.client, Offense::MalformedRequest);
+                }
+                match e.status()
+                {
+                    Some(status) => self.send_error(token, status),
+                    None => self.close(token),
+                }
+            }
+        }
+    }
+
+    /// Build the response on the pool, the loop goes on meanwhile.
+    fn dispatch(&mut self, token: Token, request: Request)
+    {
+        let config = self.shared.get();
+        let connection = match self.connections.get_mut(&token)
+        {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let client = proxy::forwarded_client(&config.proxy.trusted, connection.client, &request.headers);
+        let peer = if client == connection.client { connection.peer.clone() } else { client.to_string() };
+        let path = served_path(&request, &config.file_path, &config.index, config.symlinks);
+
+        if !config.access.allows(client, &path)
+        {
+            Logger::printmsg(Logger::Info, format!("Request to {} from {} is denied by the access list", path, peer));
+            self.queue(token, forbidden(&config), false, None);
+            return;
+        }
+
+        if let Some(left) = self.rate_limiter.bans().banned_for(client)
+        {
+            Logger::printmsg(Logger::Info, format!("Request to {} from {} is banned", request.path(), peer));
+            self.queue(token, banned(left, &config), false, None);
+            return;
+        }
+
+        if escapes_root(request.path())
+        {
+            self.rate_limiter.bans().offense(client, Offense::PathTraversal);
+        }
+
+        let decision = self.rate_limiter.check(request.method, &path, client);
+        if let Some(decision) = decision.filter(|decision| !decision.allowed)
+        {
+            Logger::printmsg(Logger::Info, format!("Request to {} has been blocked from {}", path, peer));
+            self.rate_limiter.bans().offense(client, Offense::RateLimited);
+            self.queue(token, too_many_requests(&decision, &config), false, None);
+            return;
+        }
+
+        connection.served += 1;
+        let served = connection.served;
+        let keep_alive = request.keep_alive() && served < config.timeouts.keep_alive_requests && !SHUTTING_DOWN.load(Ordering::SeqCst);
+
+        // execute() would block the loop on a full queue
+        if self.pool.is_saturated()
+        {
+            Logger::printmsg(Logger::InfoErr, format!("Job queue is full ({}/{}), refusing request from {}", self.pool.queue_depth(), self.pool.queue_capacity(), connection.peer));
+            self.queue(token, overloaded(&config), false, None);
+            return;
+        }
+
+        connection.state = State::Processing;
+        connection.process_since = Instant::now();
+        connection.pacer = self.bandwidth.pacer(client, &path);
+        let completion = Completion { token, sender: self.sender.clone(), waker: Arc::clone(&self.waker) };
+
+        let job_config = Arc::clone(&config);
+        let result = self.pool.execute(move ||
+        {
+            let response = with_rate_limit(respond(&request, &path, &job_config, keep_alive, served), decision.as_ref());
+            let summary = format!("{} {} from {}, responsed with \"{}\"", request.method, request.target, peer, response.status);
+            completion.send(response, keep_alive, Some(summary));
+        });
+
+        if let Err(e) = result
+        {
+            Logger::printmsg(Logger::InfoErr, format!("Cannot handle the request: {}", e));
+            self.queue(token, overloaded(&config), false, None);
+        }
+    }
+
+    fn collect_finished(&mut self)
+    {
+        while let Ok(finished) = self.reciever.try_recv()
+        {
+            // Answered already when the job timed out
+            if self.connections.get(&finished.token).is_some_and(|connection| connection.state != State::Processing)
+            {
+                continue;
+            }
+            self.queue(finished.token, finished.response, finished.keep_alive, finished.summary);
+        }
+    }
+
+    fn send_error(&mut self, token: Token, status: StatusCode)
+    {
+        let config = self.shared.get();
+        let response = with_headers(Response::error(status, &config.file_path), &config).header("Connection", "close");
+        self.queue(token, response, false, None);
+    }
+
+    /// Start sending `response`.
+    fn queue(&mut self, token: Token, response: Response, keep_alive: bool, summary: Option<String>)
+    {
+        // Gone if the peer reset the connection while the response was built
+        let connection = match self.connections.get_mut(&token)
+        {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let (outgoing, file) = response.into_bytes();
+        connection.outgoing = outgoing;
+        connection.written = 0;
+        connection.file = file;
+        connection.state = State::Writing;
+        connection.close_after_write = !keep_alive;
+        connection.summary = summary;
+        connection.write_since = Instant::now();
+
+        self.flush(token);
+    }
+
+    /// Write as much of the pending response as the socket takes.
+    fn flush(&mut self, token: Token)
+    {
+        let connection = match self.connections.get_mut(&token)
+        {
+            Some(connection) if connection.state == State::Writing => connection,
+            _ => return,
+        };
+
+        loop
+        {
+            if connection.written == connection.outgoing.len()
+            {
+                match connection.refill()
+                {
+                    Ok(true) => (),
+                    Ok(false) => break,
+                    Err(e) =>
+                    {
+                        Logger::printmsg(Logger::InfoErr, format!("Cannot read the response body: {}", e));
+                        self.close(token);
+                        return;
+                    }
+                }
+            }
+
+            let pending = connection.outgoing.len() - connection.written;
+            let allowed = match connection.pacer.as_mut().map(|pacer| pacer.allowance(pending))
+            {
+                None => pending,
+                Some(Ok(allowed)) => allowed,
+                // Waiting for bandwidth is not counted against the write timeout
+                Some(Err(wait)) =>
+                {
+                    connection.write_since += wait;
+                    self.paused.insert(token, Instant::now() + wait);
+                    return;
+                }
+            };
+
+            match connection.stream.write(&connection.outgoing[connection.written..connection.written + allowed])
+            {
+                Ok(0) =>
+                {
+                    self.close(token);
+                    return;
+                },
+                Ok(written) =>
+                {
+                    connection.written += written;
+                    connection.last_active = Instant::now();
+                    if let Some(pacer) = connection.pacer.as_mut()
+                    {
+                        pacer.spend(written);
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) =>
+                {
+                    Logger::printmsg(Logger::InfoErr, format!("Writing to stream is failed: {}", e));
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+
+        if let Some(summary) = connection.summary.take()
+        {
+            Logger::printmsg(Logger::Request, summary);
+        }
+
+        if connection.close_after_write
+        {
+            self.close(token);
+            return;
+        }
+
+        connection.outgoing = Vec::new();
+        connection.written = 0;
+        connection.pacer = None;
+        connection.state = State::Reading;
+
+        // Pipelined bytes waited in the buffer, their timer starts now
+        connection.head_since = match connection.incoming.received.is_empty()
+        {
+            true => None,
+            false => Some(Instant::now()),
+        };
+
+        // Pick up pipelined requests and whatever came in meanwhile
+        self.read(token);
+    }
+
+    /// Close connections that are idle for too long, or too slow to send
+    /// their request or receive their response, and answer the ones whose
+    /// response takes too long to build.
+    fn expire(&mut self)
+    {
+        let config = self.shared.get();
+        let timeouts = &config.timeouts;
+
+        let expired: Vec<Token> = self.connections.iter()
+            .filter(|(_, connection)| match connection.state
+            {
+                State::Reading => match (connection.head_since, connection.body_since)
+                {
+                    (_, Some(since)) => since.elapsed() >= timeouts.body,
+                    (Some(since), None) => since.elapsed() >= timeouts.header,
+                    (None, None) => connection.last_active.elapsed() >= timeouts.keep_alive,
+                },
+                State::Processing => connection.process_since.elapsed() >= timeouts.process,
+                State::Writing => connection.write_since.elapsed() >= timeouts.write,
+            })
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in expired
+        {
+            let connection = &self.connections[&token];
+            if connection.state == State::Processing
+            {
+                Logger::printmsg(Logger::RequestErr, format!("Building the response to {} timed out", connection.peer));
+                self.queue(token, overloaded(&config), false, None);
+            }
+            else if connection.state == State::Writing
+            {
+                Logger::printmsg(Logger::RequestErr, format!("Sending the response to {} timed out", connection.peer));
+                self.close(token);
+            }
+            else if connection.head_since.is_none()
+            {
+                Logger::printmsg(Logger::Request, format!("Connection to {} is idle, closing", connection.peer));
+                self.close(token);
+            }
+            else
+            {
+                Logger::printmsg(Logger::RequestErr, format!("Malformed request from {}: {}", connection.peer, ParseError::Timeout));
+                self.send_error(token, StatusCode::RequestTimeout);
+            }
+        }
+    }
+
+    /// Close the listeners and the connections that are between requests.
+    fn stop_accepting(&mut self)
+    {
+        for mut listener in self.listeners.drain(..)
+        {
+            let _ = self.poll.registry().deregister(&mut listener);
+        }
+
+        let idle: Vec<Token> = self.connections.iter()
+            .filter(|(_, connection)| connection.state == State::Reading && connection.incoming.is_empty())
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in idle
+        {
+            self.close(token);
+        }
+    }
+
+    /// Go on with the writes whose wait for bandwidth is over.
+    fn resume_paused(&mut self)
+    {
+        let now = Instant::now();
+        let due: Vec<Token> = self.paused.iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in due
+        {
+            self.paused.remove(&token);
+            self.flush(token);
+        }
+    }
+
+    fn close(&mut self, token: Token)
+    {
+        self.paused.remove(&token);
+        if let Some(mut connection) = self.connections.remove(&token)
+        {
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+        }
+    }
+}
+
+
+impl Connection
+{
+    /// Read the next chunk of the file body into `outgoing`, returns whether
+    /// there was one.
+    fn refill(&mut self) -> io::Result<bool>
+    {
+        let file = match self.file.as_mut()
+        {
+            Some(file) if file.limit() > 0 => file,
+            _ =>
+            {
+                self.file = None;
+                return Ok(false);
+            }
+        };
+
+        self.outgoing.clear();
+        self.written = 0;
+        if file.take(FILE_CHUNK).read_to_end(&mut self.outgoing)? == 0
+        {
+            return Err(response::shrunk());
+        }
+        Ok(true)
+    }
+}
+
+/// Bytes received on a connection and how far they have been parsed.
+#[derive(Default)]
+struct Incoming
+{
+    /// Bytes received but not parsed yet, pipelined requests included.
+    received: Vec<u8>,
+    /// The head of the pending request once parsed, with how its body ends.
+    head: Option<(Request, Framing)>,
+    /// How far `received` has been looked at for the end of the request.
+    scan: Scan,
+}
+
+impl Incoming
+{
+    /// Whether nothing of a next request has arrived.
+    fn is_empty(&self) -> bool
+    {
+        self.received.is_empty() && self.head.is_none()
+    }
+
+    /// Take the pending request out of `received` once it fully arrived.
+    ///
+    /// Only the bytes received since the last call are looked at, and the
+    /// parser only runs on a complete head, then on a complete body. Fails
+    /// with `Idle` or `Timeout` while the request is not complete, like a
+    /// drained socket would.
+    fn parse(&mut self, limits: &Limits) -> Result<Request, ParseError>
+    {
+        let framing = match &self.head
+        {
+            Some((_, framing)) => *framing,
+            None => self.parse_head(limits)?,
+        };
+
+        let complete = match framing
+        {
+            Framing::Length(length) => self.received.len() as u64 >= length,
+            Framing::Chunked => self.scan.chunks_end(&self.received)?,
+        };
+        if !complete
+        {
+            return Err(ParseError::Timeout);
+        }
+
+        let mut reader = Received { data: &self.received, pos: 0 };
+        let body = request::read_body(&mut reader, framing);
+        let consumed = reader.pos;
+
+        self.received.drain(..consumed);
+        self.scan = Scan::default();
+        let (mut request, _) = self.head.take().expect("the head is parsed");
+        // The scan saw the whole body, running out of bytes means the chunks
+        // are not what they looked like
+        request.body = body.map_err(|e| match e
+        {
+            ParseError::Timeout => ParseError::InvalidChunk,
+            e => e,
+        })?;
+        Ok(request)
+    }
+
+    fn parse_head(&mut self, limits: &Limits) -> Result<Framing, ParseError>
+    {
+        let end = match self.scan.head_end(&self.received)
+        {
+            Some(end) => end,
+            // The parser reports which limit is exceeded
+            None if self.scan.head_len(&self.received) > max_head(limits) => self.received.len(),
+            None if self.scan.head_start.is_none() && self.scan.line_start == self.received.len() => return Err(ParseError::Idle),
+            None => return Err(ParseError::Timeout),
+        };
+
+        let mut reader = Received { data: &self.received[..end], pos: 0 };
+        let request = Request::read_head(&mut reader, limits)?;
+        let consumed = reader.pos;
+
+        self.received.drain(..consumed);
+        self.scan = Scan::default();
+
+        let framing = request.framing()?;
+        self.head = Some((request, framing));
+        Ok(framing)
+    }
+}
+
+/// Most bytes a head within `limits` takes, line endings included.
+fn max_head(limits: &Limits) -> usize
+{
+    limits.max_request_line + limits.max_header_bytes + 2 * (limits.max_headers + 3)
+}
+
+/// Where looking for the end of the pending request stopped, so that each
+/// read only looks at the new bytes.
+#[derive(Default)]
+struct Scan
+{
+    /// Bytes looked at, or where the next chunk starts.
+    pos: usize,
+    /// Start of the line being looked at.
+    line_start: usize,
+    /// Start of the request line, once seen.
+    head_start: Option<usize>,
+    /// Size of the chunks before `pos`.
+    body: u64,
+}
+
+impl Scan
+{
+    /// Where the head ends, after the empty line that follows the request
+    /// line. Empty lines before the request line are skipped like the parser
+    /// does.
+    fn head_end(&mut self, data: &[u8]) -> Option<usize>
+    {
+        while let Some(offset) = data[self.pos..].iter().position(|&b| b == b'\n')
+        {
+            let end = self.pos + offset;
+            let empty = matches!(&data[self.line_start..end], b"" | b"\r");
+            let start = self.line_start;
+            self.pos = end + 1;
+            self.line_start = end + 1;
+
+            match (empty, self.head_start)
+            {
+                (true, Some(_)) => return Some(end + 1),
+                (false, None) => self.head_start = Some(start),
+                _ => {},
+            }
+        }
+
+        self.pos = data.len();
+        None
+    }
+
+    /// Bytes of the head received so far.
+    fn head_len(&self, data: &[u8]) -> usize
+    {
+        data.len() - self.head_start.unwrap_or(self.line_start)
+    }
+
+    /// Whether a chunked body is complete, or malformed enough for the
+    /// decoder to tell.
+    fn chunks_end(&mut self, data: &[u8]) -> Result<bool, ParseError>
+    {
+        loop
+        {
+            let line_end = match data[self.pos..].iter().position(|&b| b == b'\n')
+            {
+                Some(offset) => self.pos + offset,
+                None => return Ok(false),
+            };
+
+            let size = match data[self.pos..line_end].split_last()
+            {
+                Some((b'\r', line)) => line.split(|&b| b == b';').next().unwrap_or_default(),
+                _ => return Ok(true),
+            };
+            let size = match std::str::from_utf8(size).ok().and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+            {
+                Some(size) => size,
+                None => return Ok(true),
+            };
+
+            // The last chunk is followed by an empty line
+            if size == 0
+            {
+                return Ok(data.len() >= line_end + 3);
+            }
+
+            if self.body + size > MAX_BODY_SIZE
+            {
+                return Err(ParseError::PayloadTooLarge);
+            }
+
+            let next = line_end + 1 + size as usize + 2;
+            if next > data.len()
+            {
+                return Ok(false);
+            }
+            self.pos = next;
+            self.body += size;
+        }
+    }
+}
+
+
+/// The bytes received so far on a connection.
+///
+/// Reading past them fails with `WouldBlock` like a drained non-blocking
+/// socket would, which the parser reports as `Idle` or `Timeout`: the request
+/// is not complete yet.
+struct Received<'a>
+{
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Read for Received<'_>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for Received<'_>
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]>
+    {
+        if self.pos >= self.data.len()
+        {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        Ok(&self.data[self.pos..])
+    }
+
+    fn consume(&mut self, amount: usize)
+    {
+        self.pos += amount;
+    }
+}
+
+impl Source for Received<'_> {}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use crate::config::Config;
+
+    const LIMITS: Limits = Limits { max_request_line: 64, max_header_bytes: 128, max_headers: 4 };
+
+    /// Feed `parts` one after the other, parsing after each like the loop does.
+    fn feed(incoming: &mut Incoming, parts: &[&str]) -> Vec<Result<Request, ParseError>>
+    {
+        parts.iter().map(|part|
+        {
+            incoming.received.extend_from_slice(part.as_bytes());
+            incoming.parse(&LIMITS)
+        })
+        .collect()
+    }
+
+    fn root(name: &str) -> PathBuf
+    {
+        let root = std::env::temp_dir().join(format!("webserver-loop-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "hello").unwrap();
+        root
+    }
+
+    /// Run an event loop on `root` in the background, returns its address.
+    fn serve(root: &Path, options: &[&str], pool: Arc<ThreadPool>) -> SocketAddr
+    {
+        let args: Vec<String> = ["webserver", root.to_str().unwrap()].iter().chain(options).map(|arg| arg.to_string()).collect();
+        let config = Config::build(&args).unwrap();
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let rate_limiter = Arc::new(Policies::new(&config.limiter, &config.bans));
+        let connection_limits = Arc::new(ConnectionLimits::new(&config.connections, config.limiter.ipv6_prefix));
+        let bandwidth = Arc::new(Bandwidth::new(&config.bandwidth, config.limiter.ipv6_prefix));
+
+        let event_loop = EventLoop::new(vec![listener], pool, Arc::new(SharedConfig::new(config)), rate_limiter, connection_limits, bandwidth).unwrap();
+        thread::spawn(move || event_loop.run());
+        address
+    }
+
+    /// Send `parts` with a pause between them, returns the status line of
+    /// each response up to the connection being closed.
+    fn exchange(address: SocketAddr, parts: &[&str]) -> Vec<String>
+    {
+        let mut stream = net::TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        for part in parts
+        {
+            stream.write_all(part.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        // Bodies do not end with a line break, a status line may follow one on the same line
+        response.match_indices("HTTP/1.1 ").filter_map(|(at, _)| response[at..].lines().next()).map(String::from).collect()
+    }
+
+    #[test]
+    fn requests_are_parsed_once_complete()
+    {
+        let mut incoming = Incoming::default();
+        let results = feed(&mut incoming, &["\r\nGET / HT", "TP/1.1\r\nHost: a\r", "\n", "\r\nGET /next HTTP/1.1\r\n\r\n"]);
+
+        assert!(matches!(results[0], Err(ParseError::Timeout)));
+        assert!(matches!(results[1], Err(ParseError::Timeout)));
+        assert!(matches!(results[2], Err(ParseError::Timeout)));
+        assert_eq!(results[3].as_ref().unwrap().target, "/");
+        assert_eq!(incoming.parse(&LIMITS).unwrap().target, "/next");
+        assert!(matches!(incoming.parse(&LIMITS), Err(ParseError::Idle)));
+        assert!(incoming.is_empty());
+    }
+
+    #[test]
+    fn bodies_are_parsed_once_complete()
+    {
+        let mut incoming = Incoming::default();
+        let results = feed(&mut incoming, &["POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello", "wor"]);
+        assert!(results.iter().all(|result| matches!(result, Err(ParseError::Timeout))));
+        assert!(incoming.head.is_some());
+        let results = feed(&mut incoming, &["ldGET"]);
+        assert_eq!(results[0].as_ref().unwrap().body.as_deref(), Some(&b"helloworld"[..]));
+        assert_eq!(incoming.received, b"GET");
+
+        let mut incoming = Incoming::default();
+        let results = feed(&mut incoming, &["POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel", "lo\r\n6;ext=1\r\n wor", "ld\r\n0\r", "\n\r\n"]);
+        assert!(results[..3].iter().all(|result| matches!(result, Err(ParseError::Timeout))));
+        assert_eq!(results[3].as_ref().unwrap().body.as_deref(), Some(&b"hello world"[..]));
+        assert!(incoming.is_empty());
+    }
+
+    #[test]
+    fn received_bytes_are_looked_at_once()
+    {
+        let mut incoming = Incoming::default();
+        feed(&mut incoming, &["GET / HTTP/1.1\r\n", "Host: a\r\n"]);
+        assert_eq!(incoming.scan.pos, incoming.received.len());
+        assert_eq!(incoming.scan.head_start, Some(0));
+
+        let mut incoming = Incoming::default();
+        feed(&mut incoming, &["POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n", "3\r\nabc\r\n", "3\r\nabc\r\n4\r\nab", "c"]);
+        assert_eq!((incoming.scan.pos, incoming.scan.body), (16, 6));
+    }
+
+    #[test]
+    fn malformed_requests_are_reported()
+    {
+        let mut incoming = Incoming::default();
+        assert!(matches!(feed(&mut incoming, &["GET / HTTP/1.1\r\nbroken\r\n\r\n"])[0], Err(ParseError::InvalidHeader(_))));
+
+        let mut incoming = Incoming::default();
+        assert!(matches!(feed(&mut incoming, &["POST / HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n"])[0], Err(ParseError::ConflictingLength)));
+
+        // No empty line yet, but more than a head within the limits can take
+        let mut incoming = Incoming::default();
+        let head = format!("GET / HTTP/1.1\r\n{}", "X-Long: value\r\n".repeat(20));
+        assert!(matches!(feed(&mut incoming, &[&head])[0], Err(ParseError::HeadersTooLarge | ParseError::TooManyHeaders)));
+
+        for body in ["5\r\nhelloXX0\r\n\r\n", "5\nhello\r\n0\r\n\r\n", "5\r\nhello\r\n0\r\nXY"]
+        {
+            let mut incoming = Incoming::default();
+            let results = feed(&mut incoming, &["POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n", body]);
+            assert!(matches!(results[1], Err(ParseError::InvalidChunk)), "{:?}", body);
+        }
+
+        let mut incoming = Incoming::default();
+        let chunked = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n", MAX_BODY_SIZE + 1);
+        assert!(matches!(feed(&mut incoming, &[&chunked])[0], Err(ParseError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn split_and_pipelined_requests_are_answered()
+    {
+        let root = root("split");
+        let address = serve(&root, &[], Arc::new(ThreadPool::new(2)));
+
+        let statuses = exchange(address, &["GET / HT", "TP/1.1\r\nHost: a\r\n", "\r\n"]);
+        assert_eq!(statuses, ["HTTP/1.1 200 OK"]);
+
+        let statuses = exchange(address, &["POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel", "lo\r\n0\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n"]);
+        assert_eq!(statuses, ["HTTP/1.1 405 METHOD NOT ALLOWED", "HTTP/1.1 200 OK"]);
+
+        let statuses = exchange(address, &["POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloXX0\r\n\r\n"]);
+        assert_eq!(statuses, ["HTTP/1.1 400 BAD REQUEST"]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn slow_heads_and_stuck_jobs_time_out()
+    {
+        let root = root("timeouts");
+        let pool = Arc::new(ThreadPool::new(1));
+        let address = serve(&root, &["--header-timeout", "1", "--process-timeout", "1"], Arc::clone(&pool));
+
+        assert_eq!(exchange(address, &["GET / HTTP/1.1\r\n"]), ["HTTP/1.1 408 REQUEST TIMEOUT"]);
+
+        // The only worker is busy, the request is never picked up in time
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || { let _ = blocked.recv(); }).unwrap();
+        assert_eq!(exchange(address, &["GET / HTTP/1.1\r\n\r\n"]), ["HTTP/1.1 503 SERVICE UNAVAILABLE"]);
+        drop(release);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn persistent_connections_follow_the_keep_alive_settings()
+    {
+        let root = root("keep-alive");
+        let request = "GET / HTTP/1.1\r\n\r\n";
+
+        // Closed by the server after the second request, well before it is idle for long
+        let address = serve(&root, &["--keep-alive-requests", "2"], Arc::new(ThreadPool::new(2)));
+        let start = Instant::now();
+        assert_eq!(exchange(address, &[request, request]).len(), 2);
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        let address = serve(&root, &["--keep-alive-timeout", "1", "--keep-alive-requests", "7"], Arc::new(ThreadPool::new(2)));
+        let mut stream = net::TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let start = Instant::now();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.contains("Keep-Alive: timeout=1, max=6\r\n"));
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn large_files_are_sent_in_chunks()
+    {
+        let root = root("large");
+        let content: Vec<u8> = (0..FILE_CHUNK as usize * 3 + 1000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(root.join("large.bin"), &content).unwrap();
+
+        for options in [&[][..], &["--bandwidth-per-connection", "1048576"][..]]
+        {
+            let address = serve(&root, options, Arc::new(ThreadPool::new(1)));
+            let mut stream = net::TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            stream.write_all(b"GET /large.bin HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            let head_end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+            let head = String::from_utf8_lossy(&response[..head_end]);
+            assert!(head.starts_with("HTTP/1.1 200 OK"));
+            assert!(head.contains(&format!("Content-Length: {}", content.len())));
+            assert!(response[head_end..] == content[..]);
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
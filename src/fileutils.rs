@@ -1,116 +1,504 @@
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
 use crate::logger::Logger;
+use crate::request::Request;
 
 
-const NOT_FOUND_PAGE_NAME: &str = "404.html";
-const HTTP_OK_RESPONSE: &str = "HTTP/1.1 200 OK";
-const HTTP_NOT_FOUND_RESPONSE: &str = "HTTP/1.1 404 NOT FOUND";
+const DEFAULT_FILETYPE: &str = "application/octet-stream";
 
 
-pub enum FiletypeProcessError
+/// Guess the `Content-Type` from the file extension.
+///
+/// Unknown or missing extensions fall back to `application/octet-stream`.
+pub fn get_filetype(filename: &str) -> &'static str
 {
-    UnsupportedFileType,
-    NoExtensionFound,
+    let extension = match filename.rfind('.')
+    {
+        Some(dot_pos) if !filename[dot_pos..].contains('/') => filename[dot_pos + 1..].to_ascii_lowercase(),
+        _ => return DEFAULT_FILETYPE,
+    };
+
+    match extension.as_str()
+    {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "application/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "xml" => "application/xml",
+        "jpg" | "jpeg" => "image/jpeg",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => DEFAULT_FILETYPE,
+    }
+}
+
+
+#[derive(Debug)]
+pub enum ResolveError
+{
+    NotFound,
+    /// The target points outside of the served root.
+    Forbidden,
+}
+
+/// How symlinks pointing outside of the served root are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy
+{
+    /// Only serve symlinks whose destination stays inside the root.
+    WithinRoot,
+    /// Follow every symlink, wherever it points.
+    Follow,
 }
 
-pub fn get_filetype(filename: &String) -> Result<String, FiletypeProcessError> {
-    if let Some(dot_pos) = filename.rfind('.') {
-        let extension = &filename[dot_pos+1..];
-        match extension {
-            "html" | "htm" => Ok("text/html".to_string()),
-            "jpg" | "jpeg" => Ok("image/jpeg".to_string()),
-            "svg" => Ok("image/svg+xml".to_string()),
-            "png" => Ok("image/png".to_string()),
-            "css" => Ok("text/css".to_string()),
-            "js" => Ok("application/javascript".to_string()),
-            _ => Err(FiletypeProcessError::UnsupportedFileType)
+
+/// Path `request` is served from: its own, or when nothing is found there,
+/// what is left once the directory of its `Referer` is taken out of it.
+///
+/// Access rules and limits are matched against this path rather than the
+/// target, otherwise a made up `Referer` would get around them.
+pub fn served_path(request: &Request, root: &str, index: &str, symlinks: SymlinkPolicy) -> String
+{
+    let target = request.path();
+
+    match resolve_path(root, target, index, symlinks)
+    {
+        Err(ResolveError::NotFound) if target.starts_with('/') => (),
+        _ => return target.to_string(),
+    }
+
+    // handle weird request
+    let referer = match request.referer()
+    {
+        Some(referer) => referer,
+        None => return target.to_string(),
+    };
+
+    match referer_dir(referer)
+    {
+        // Remove the unnecessary address path to get clear path to included
+        // to html files
+        Some(slice) => target.replacen(slice, "", 1),
+        None =>
+        {
+            Logger::printmsg(Logger::ThreadErr, "Failed to process non-exist path".to_string());
+            target.to_string()
         }
-    } else {
-        Err(FiletypeProcessError::NoExtensionFound)
     }
 }
 
+/// File to serve for `path`, as returned by `served_path`.
+pub fn get_filename(path: &str, root: &str, index: &str, symlinks: SymlinkPolicy) -> Result<PathBuf, ResolveError>
+{
+    if !path.starts_with('/')
+    {
+        return Err(ResolveError::NotFound);
+    }
+
+    resolve_path(root, path, index, symlinks)
+}
 
-pub fn get_filename<'a>(request_method: &'a str, request_referer: Option<String>, path: &'a str) -> (&'a str, String)
+/// Map a request path onto a file inside `root`.
+///
+/// The path is percent-decoded and normalized before it touches the file system,
+/// `..` segments that would climb above the root are rejected. The result is then
+/// canonicalized so symlinks can be checked against `symlinks`.
+pub fn resolve_path(root: &str, target: &str, index: &str, symlinks: SymlinkPolicy) -> Result<PathBuf, ResolveError>
 {
-    match request_method.find("/")
+    let decoded = match percent_decode(target)
     {
-        Some(value) =>
-        {
-            let mut file_string_points = Vec::new();
+        Some(decoded) => decoded,
+        None => return Err(ResolveError::NotFound),
+    };
 
-            if request_method.chars().nth(value + 1).unwrap() != ' '
+    if decoded.contains('\0')
+    {
+        return Err(ResolveError::Forbidden);
+    }
+
+    let mut relative = PathBuf::new();
+    for component in Path::new(&decoded).components()
+    {
+        match component
+        {
+            Component::Normal(part) => relative.push(part),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir =>
             {
-                for (i, char) in request_method.chars().enumerate()
+                if !relative.pop()
                 {
-                    if char == ' '
-                    {
-                        if file_string_points.len() < 2
-                        {
-                            file_string_points.push(i);
-                        }
-                    }
+                    return Err(ResolveError::Forbidden);
                 }
-                
-                let mut result = format!("{}{}", path, &request_method[file_string_points[0] + 2..file_string_points[1]]);
+            },
+            Component::Prefix(_) => return Err(ResolveError::Forbidden),
+        }
+    }
 
-                if Path::new(&result).exists()
-                {
-                    (HTTP_OK_RESPONSE, result)
-                }
-                else
-                {
-                    // handle weird request
-                    match request_referer
-                    {
-                        Some(value) =>
-                        {
-                            // find third and last slashes
-                            let mut slash_chars = Vec::new();
-                            for (i, char) in value.chars().enumerate()
-                            {
-                                if char == '/'
-                                {
-                                    slash_chars.push(i);
-                                }
-                            }
-
-                            let slice;
-                            match value.chars().last()
-                            {
-                                Some(_) => 
-                                {
-                                    // Get the unnecessary address part
-                                    slice = &value[slash_chars[2] + 1..*slash_chars.last().unwrap() + 1];
-                                },
-
-                                None => 
-                                {
-                                    Logger::printmsg(Logger::ThreadErr, format!("Failed to process non-exist path"));
-                                    return (HTTP_NOT_FOUND_RESPONSE, format!("{}{}", path, NOT_FOUND_PAGE_NAME));
-                                }
-                            }
-
-                            // Remove the unnecessary address path to get clear path to included
-                            // to html files
-                            result = result.replacen(slice, "", 1);
-                            return (HTTP_OK_RESPONSE, result);
-                        },
-
-                        None => (),
-                    }
-
-                    return (HTTP_NOT_FOUND_RESPONSE, format!("{}{}", path, NOT_FOUND_PAGE_NAME));
-                }
-            }
-            else
+    let root = match Path::new(root).canonicalize()
+    {
+        Ok(root) => root,
+        Err(_) => return Err(ResolveError::NotFound),
+    };
+
+    let mut result = root.join(relative);
+    if result.is_dir()
+    {
+        result.push(index);
+    }
+
+    let canonical = match result.canonicalize()
+    {
+        Ok(canonical) => canonical,
+        Err(_) => return Err(ResolveError::NotFound),
+    };
+
+    if symlinks == SymlinkPolicy::WithinRoot && !canonical.starts_with(&root)
+    {
+        return Err(ResolveError::Forbidden);
+    }
+
+    if !canonical.is_file()
+    {
+        return Err(ResolveError::NotFound);
+    }
+
+    Ok(canonical)
+}
+
+/// Percent-decode `path` and resolve its `.` and `..` segments, so that
+/// different spellings of a path compare equal. A trailing slash is kept.
+///
+/// Returns `None` when the path cannot be decoded.
+pub fn normalize_path(path: &str) -> Option<String>
+{
+    let decoded = percent_decode(path)?;
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/')
+    {
+        match segment
+        {
+            "" | "." => (),
+            ".." => { segments.pop(); },
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if decoded.ends_with('/') && !segments.is_empty()
+    {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Whether the `..` segments of `path` climb above the root.
+pub fn escapes_root(path: &str) -> bool
+{
+    let decoded = match percent_decode(path)
+    {
+        Some(decoded) => decoded,
+        None => return false,
+    };
+
+    let mut depth: usize = 0;
+    for segment in decoded.split(['/', '\\'])
+    {
+        match segment
+        {
+            "" | "." => (),
+            ".." => match depth.checked_sub(1)
             {
-                return (HTTP_NOT_FOUND_RESPONSE, format!("{}{}", path, NOT_FOUND_PAGE_NAME));
-            }
-        },
+                Some(parent) => depth = parent,
+                None => return true,
+            },
+            _ => depth += 1,
+        }
+    }
+    false
+}
 
-        None => (HTTP_NOT_FOUND_RESPONSE, format!("{}{}", path, NOT_FOUND_PAGE_NAME))
+/// Whether `path` matches `pattern`: the path itself and everything below
+/// it, or a glob where `*` stands for any run of characters when it contains
+/// one. "/admin" matches "/admin/panel" but not "/administrator".
+pub fn path_matches(pattern: &str, path: &str) -> bool
+{
+    if !pattern.contains('*')
+    {
+        return match path.strip_prefix(pattern)
+        {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || pattern.ends_with('/'),
+            None => false,
+        };
     }
+
+    let (pattern, path) = (pattern.as_bytes(), path.as_bytes());
+    let (mut p, mut s) = (0, 0);
+    // Position of the last `*` and of the path when it was reached, to
+    // backtrack to when the rest does not match
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < path.len()
+    {
+        if p < pattern.len() && pattern[p] == b'*'
+        {
+            star = Some((p, s));
+            p += 1;
+        }
+        else if p < pattern.len() && pattern[p] == path[s]
+        {
+            p += 1;
+            s += 1;
+        }
+        else if let Some((star_p, star_s)) = star
+        {
+            p = star_p + 1;
+            s = star_s + 1;
+            star = Some((star_p, star_s + 1));
+        }
+        else
+        {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+fn percent_decode(value: &str) -> Option<String>
+{
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len()
+    {
+        if bytes[i] == b'%'
+        {
+            let hex = value.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        }
+        else
+        {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
 }
 
+/// Directory part of the referer path, e.g. `sub/` for `http://host/sub/page.html`.
+fn referer_dir(referer: &str) -> Option<&str>
+{
+    let without_scheme = match referer.find("://")
+    {
+        Some(offset) => &referer[offset + 3..],
+        None => referer,
+    };
+
+    let path_start = without_scheme.find('/')?;
+    let path = &without_scheme[path_start + 1..];
+
+    match path.rfind('/')
+    {
+        Some(offset) => Some(&path[..offset + 1]),
+        None => Some(""),
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::access::AccessList;
+    use crate::request::{Headers, Method, Version};
+    use std::{fs, net::{IpAddr, Ipv4Addr}};
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    /// Directory to serve from, removed when dropped.
+    struct Root
+    {
+        path: PathBuf,
+    }
+
+    impl Root
+    {
+        fn new(name: &str, files: &[&str]) -> Root
+        {
+            let path = std::env::temp_dir().join(format!("webserver-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            for file in files
+            {
+                let file = path.join(file);
+                fs::create_dir_all(file.parent().unwrap()).unwrap();
+                fs::write(file, "content").unwrap();
+            }
+            Root { path }
+        }
+
+        fn as_str(&self) -> &str
+        {
+            self.path.to_str().unwrap()
+        }
+    }
+
+    impl Drop for Root
+    {
+        fn drop(&mut self)
+        {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn get(target: &str, referer: Option<&str>) -> Request
+    {
+        let mut headers = Headers::new();
+        if let Some(referer) = referer
+        {
+            headers.insert("Referer", referer);
+        }
+        Request { method: Method::Get, target: target.to_string(), version: Version::Http11, headers, body: None }
+    }
+
+    fn served(root: &Root, request: &Request) -> String
+    {
+        served_path(request, root.as_str(), "index.html", SymlinkPolicy::WithinRoot)
+    }
+
+    fn resolve(root: &Root, target: &str, symlinks: SymlinkPolicy) -> Result<PathBuf, ResolveError>
+    {
+        resolve_path(root.as_str(), target, "index.html", symlinks)
+    }
+
+    #[test]
+    fn paths_resolve_inside_the_root()
+    {
+        let root = Root::new("resolve", &["index.html", "sub/index.html", "sub/page.html"]);
+        let base = root.path.canonicalize().unwrap();
+
+        assert_eq!(resolve(&root, "/", SymlinkPolicy::WithinRoot).unwrap(), base.join("index.html"));
+        assert_eq!(resolve(&root, "/sub/", SymlinkPolicy::WithinRoot).unwrap(), base.join("sub/index.html"));
+        assert_eq!(resolve(&root, "/sub/./x/../page.html", SymlinkPolicy::WithinRoot).unwrap(), base.join("sub/page.html"));
+        assert_eq!(resolve(&root, "/s%75b/page%2Ehtml", SymlinkPolicy::WithinRoot).unwrap(), base.join("sub/page.html"));
+        assert!(matches!(resolve(&root, "/missing.html", SymlinkPolicy::WithinRoot), Err(ResolveError::NotFound)));
+        assert!(matches!(resolve(&root, "/%zz", SymlinkPolicy::WithinRoot), Err(ResolveError::NotFound)));
+    }
+
+    #[test]
+    fn parent_segments_cannot_leave_the_root()
+    {
+        let root = Root::new("escape", &["index.html"]);
+
+        for target in ["/..", "/../etc/passwd", "/sub/../../etc/passwd", "/%2e%2e/etc/passwd", "/%2E%2E%2Fetc/passwd", "/.%2e/x"]
+        {
+            assert!(matches!(resolve(&root, target, SymlinkPolicy::WithinRoot), Err(ResolveError::Forbidden)), "{}", target);
+            assert!(escapes_root(target), "{}", target);
+        }
+
+        assert!(matches!(resolve(&root, "/index.html%00.png", SymlinkPolicy::WithinRoot), Err(ResolveError::Forbidden)));
+        assert!(!escapes_root("/sub/../index.html"));
+        assert!(!escapes_root("/..%2e/x"));
+        assert!(escapes_root("/..\\x"));
+    }
+
+    #[test]
+    fn symlinks_leaving_the_root_follow_the_policy()
+    {
+        let outside = Root::new("outside", &["secret.html"]);
+        let root = Root::new("symlinks", &["page.html"]);
+        std::os::unix::fs::symlink(outside.path.join("secret.html"), root.path.join("secret.html")).unwrap();
+        std::os::unix::fs::symlink(root.path.join("page.html"), root.path.join("alias.html")).unwrap();
+
+        assert!(matches!(resolve(&root, "/secret.html", SymlinkPolicy::WithinRoot), Err(ResolveError::Forbidden)));
+        assert!(resolve(&root, "/secret.html", SymlinkPolicy::Follow).is_ok());
+        assert!(resolve(&root, "/alias.html", SymlinkPolicy::WithinRoot).is_ok());
+    }
+
+    #[test]
+    fn paths_normalize_to_one_spelling()
+    {
+        assert_eq!(normalize_path("/%61dmin").as_deref(), Some("/admin"));
+        assert_eq!(normalize_path("/x/../admin/./panel").as_deref(), Some("/admin/panel"));
+        assert_eq!(normalize_path("//admin//").as_deref(), Some("/admin/"));
+        assert_eq!(normalize_path("/../..").as_deref(), Some("/"));
+        assert_eq!(normalize_path("/%").as_deref(), None);
+        assert_eq!(normalize_path("/%ff").as_deref(), None);
+    }
+
+    #[test]
+    fn paths_match_on_segment_boundaries()
+    {
+        assert!(path_matches("/admin", "/admin"));
+        assert!(path_matches("/admin", "/admin/"));
+        assert!(path_matches("/admin", "/admin/panel"));
+        assert!(!path_matches("/admin", "/administrator"));
+        assert!(!path_matches("/admin", "/adm"));
+        assert!(path_matches("/admin/", "/admin/panel"));
+        assert!(!path_matches("/admin/", "/admin"));
+        assert!(path_matches("/", "/anything"));
+
+        assert!(path_matches("/api/*", "/api/v1/users"));
+        assert!(!path_matches("/api/*", "/apis"));
+        assert!(path_matches("*.zip", "/downloads/file.zip"));
+        assert!(path_matches("/a*c", "/abbc"));
+        assert!(!path_matches("/a*c", "/abcd"));
+    }
+
+    #[test]
+    fn percent_decoding_needs_two_hex_digits()
+    {
+        assert_eq!(percent_decode("/a%20b%2F").as_deref(), Some("/a b/"));
+        assert_eq!(percent_decode("/a%2").as_deref(), None);
+        assert_eq!(percent_decode("/a%g0").as_deref(), None);
+        assert_eq!(percent_decode("/%e2%9c%93").as_deref(), Some("/\u{2713}"));
+    }
+
+    #[test]
+    fn referer_rewrites_missing_paths_only()
+    {
+        let root = Root::new("referer", &["page.html", "x/page.html", "style.css"]);
+
+        assert_eq!(served(&root, &get("/x/page.html", Some("http://host/x/"))), "/x/page.html");
+        assert_eq!(served(&root, &get("/x/style.css", Some("http://host/x/page.html"))), "/style.css");
+        assert_eq!(served(&root, &get("/x/style.css", None)), "/x/style.css");
+    }
+
+    #[test]
+    fn access_rules_apply_to_the_path_served_through_the_referer()
+    {
+        let root = Root::new("referer-acl", &["admin/secret.html"]);
+        let access = AccessList::parse("deny all /admin").unwrap();
+
+        let request = get("/x/admin/secret.html", Some("http://host/x/"));
+        let path = served(&root, &request);
+        assert!(get_filename(&path, root.as_str(), "index.html", SymlinkPolicy::WithinRoot).is_ok());
+        assert!(access.allows(CLIENT, request.path()));
+        assert!(!access.allows(CLIENT, &path));
+    }
+}
@@ -1,17 +1,55 @@
-use std::{thread, sync::{mpsc, Arc, Mutex}};
+use std::
+{
+    any::Any,
+    error::Error,
+    fmt,
+    io,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    thread,
+    time::{Duration, Instant},
+    sync::{mpsc, Arc, Mutex, RwLock, atomic::{AtomicBool, AtomicUsize, Ordering}},
+};
 
-mod logger;
+pub mod logger;
 use logger::Logger;
 
 
+/// Queue capacity used by `ThreadPool::new`.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// How long a worker above the minimum stays idle before it exits.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+
 pub struct ThreadPool
 {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
+    /// `None` once the pool is shutting down.
+    sender: RwLock<Option<mpsc::SyncSender<Job>>>,
+    queue_capacity: usize,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// State shared by the pool and its workers.
+struct Shared
+{
+    reciever: Mutex<mpsc::Receiver<Job>>,
+    workers: Mutex<Vec<Worker>>,
+    shutting_down: AtomicBool,
+    /// Jobs sent but not picked up by a worker yet.
+    queued: AtomicUsize,
+    min_workers: AtomicUsize,
+    max_workers: AtomicUsize,
+    /// Running workers, counted as soon as one is about to be spawned.
+    live: AtomicUsize,
+    /// Workers waiting for a job.
+    idle: AtomicUsize,
+    next_id: AtomicUsize,
+    keep_alive: Duration,
+}
+
 impl ThreadPool
 {
     /// Create a new ThreadPool.
@@ -23,28 +61,179 @@ impl ThreadPool
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> ThreadPool
     {
-        assert!(size > 0);
+        ThreadPool::with_queue(size, DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Create a new ThreadPool whose job queue holds at most `queue_capacity` jobs.
+    ///
+    /// Once the queue is full `execute` blocks until a worker takes a job.
+    ///
+    /// # Panics
+    ///
+    /// The `with_queue` function will panic if the size or the capacity is zero.
+    pub fn with_queue(size: usize, queue_capacity: usize) -> ThreadPool
+    {
+        match ThreadPool::build_with_queue(size, queue_capacity)
+        {
+            Ok(pool) => pool,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Create a new ThreadPool, returning an error instead of panicking.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError>
+    {
+        ThreadPool::build_with_queue(size, DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Fallible version of `with_queue`.
+    pub fn build_with_queue(size: usize, queue_capacity: usize) -> Result<ThreadPool, PoolCreationError>
+    {
+        ThreadPool::build_resizable(size, size, queue_capacity, DEFAULT_KEEP_ALIVE)
+    }
+
+    /// Create a pool that starts with `min` workers and grows up to `max` when
+    /// jobs arrive while every worker is busy.
+    ///
+    /// Workers above `min` exit after being idle for `keep_alive`.
+    pub fn build_resizable(min: usize, max: usize, queue_capacity: usize, keep_alive: Duration) -> Result<ThreadPool, PoolCreationError>
+    {
+        check_bounds(min, max)?;
+
+        if queue_capacity == 0
+        {
+            return Err(PoolCreationError::ZeroQueueCapacity);
+        }
+
+        let (sender, reciever) = mpsc::sync_channel(queue_capacity);
+        let shared = Arc::new(Shared
+        {
+            reciever: Mutex::new(reciever),
+            workers: Mutex::new(Vec::with_capacity(max)),
+            shutting_down: AtomicBool::new(false),
+            queued: AtomicUsize::new(0),
+            min_workers: AtomicUsize::new(min),
+            max_workers: AtomicUsize::new(max),
+            live: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            next_id: AtomicUsize::new(0),
+            keep_alive,
+        });
+
+        let pool = ThreadPool { shared, sender: RwLock::new(Some(sender)), queue_capacity };
+
+        // On failure the pool is dropped, which stops the workers spawned so far
+        pool.grow_to(min)?;
+
+        Ok(pool)
+    }
+
+    /// Change the worker bounds at runtime.
+    ///
+    /// Missing workers are spawned right away, extra ones exit once they finish
+    /// their current job.
+    pub fn resize(&self, min: usize, max: usize) -> Result<(), PoolCreationError>
+    {
+        check_bounds(min, max)?;
+
+        self.shared.min_workers.store(min, Ordering::SeqCst);
+        self.shared.max_workers.store(max, Ordering::SeqCst);
+        Logger::printmsg(Logger::Worker, format!("Resizing pool to {}..{} workers", min, max));
+
+        self.grow_to(min)
+    }
 
-        let (sender, reciever) = mpsc::channel();
-        let reciever = Arc::new(Mutex::new(reciever));
+    /// Number of running workers.
+    pub fn size(&self) -> usize
+    {
+        self.shared.live.load(Ordering::SeqCst)
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    /// Number of workers waiting for a job.
+    pub fn idle_workers(&self) -> usize
+    {
+        self.shared.idle.load(Ordering::SeqCst)
+    }
 
-        for id in 0..size
+    fn grow_to(&self, target: usize) -> Result<(), PoolCreationError>
+    {
+        while self.shared.live.load(Ordering::SeqCst) < target
         {
-            workers.push(Worker::new(id, Arc::clone(&reciever)));
+            match Worker::spawn(&self.shared)
+            {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(e) => return Err(PoolCreationError::Spawn(e)),
+            }
         }
 
-        ThreadPool { workers, sender: Some(sender) }
+        Ok(())
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Queue `f` to run on a worker.
+    ///
+    /// The returned handle gives access to the value `f` returns. Fails once
+    /// `shutdown` was called.
+    pub fn execute<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
     {
-        let job = Box::new(f);
+        // Cloned so the lock is not held while blocking on a full queue
+        let sender = match self.sender.read().unwrap().as_ref()
+        {
+            Some(sender) => sender.clone(),
+            None => return Err(ExecuteError::ShuttingDown),
+        };
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        let (result_sender, result_reciever) = mpsc::channel();
+        let job: Job = Box::new(move ||
+        {
+            // The handle may be gone already, nobody is interested in the result then
+            let _ = result_sender.send(f());
+        });
+
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+        if sender.send(job).is_err()
+        {
+            self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(ExecuteError::ShuttingDown);
+        }
+
+        // Every worker is busy, add one if the maximum allows it
+        if self.shared.idle.load(Ordering::SeqCst) == 0
+        {
+            if let Err(e) = Worker::spawn(&self.shared)
+            {
+                Logger::printmsg(Logger::WorkerErr, format!("Cannot spawn an extra worker: {}", e));
+            }
+        }
+
+        Ok(JobHandle { reciever: result_reciever })
+    }
+
+    /// Stop accepting jobs. Queued jobs still run, `execute` fails from now on.
+    pub fn shutdown(&self)
+    {
+        self.shared.shutting_down.store(true, Ordering::SeqCst);
+        drop(self.sender.write().unwrap().take());
+    }
+
+    /// Number of jobs waiting for a free worker.
+    pub fn queue_depth(&self) -> usize
+    {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn queue_capacity(&self) -> usize
+    {
+        self.queue_capacity
+    }
+
+    /// Whether `execute` would block because the queue is full.
+    pub fn is_saturated(&self) -> bool
+    {
+        self.queue_depth() >= self.queue_capacity
     }
 }
 
@@ -52,15 +241,21 @@ impl Drop for ThreadPool
 {
     fn drop(&mut self)
     {
-        drop(self.sender.take());
+        self.shutdown();
 
-        for worker in &mut self.workers
+        // Taken out of the lock so a worker that panics meanwhile cannot block on it
+        let workers = mem::take(&mut *self.shared.workers.lock().unwrap());
+
+        for mut worker in workers
         {
             Logger::printmsg(Logger::Worker, format!("Shutting down worker [{}]", worker.id));
 
             if let Some(thread) = worker.thread.take()
             {
-                thread.join().unwrap();
+                if thread.join().is_err()
+                {
+                    Logger::printmsg(Logger::WorkerErr, format!("Worker [{}] has panicked", worker.id));
+                }
             }
         }
     }
@@ -75,28 +270,376 @@ struct Worker
 
 impl Worker
 {
-    fn new(id: usize, reciever: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker
+    /// Spawn a new worker if the pool is below its maximum.
+    ///
+    /// Returns `false` when the maximum is already reached.
+    fn spawn(shared: &Arc<Shared>) -> io::Result<bool>
     {
-        let thread = thread::spawn(move || loop
+        let max = shared.max_workers.load(Ordering::SeqCst);
+        if shared.live.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| (live < max).then_some(live + 1)).is_err()
         {
-            let message = reciever.lock().unwrap().recv();
+            return Ok(false);
+        }
+
+        let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+        match Worker::new(id, Arc::clone(shared))
+        {
+            Ok(worker) =>
+            {
+                shared.workers.lock().unwrap().push(worker);
+                Ok(true)
+            },
+            Err(e) =>
+            {
+                shared.live.fetch_sub(1, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+
+    fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker>
+    {
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
+        let thread = builder.spawn(move || loop
+        {
+            shared.idle.fetch_add(1, Ordering::SeqCst);
+            let idle_since = Instant::now();
+            let message =
+            {
+                // Waiting for the lock counts as idle time, otherwise idle
+                // workers would only exit one per keep-alive period
+                let reciever = shared.reciever.lock().unwrap();
+                reciever.recv_timeout(shared.keep_alive.saturating_sub(idle_since.elapsed()))
+            };
+            shared.idle.fetch_sub(1, Ordering::SeqCst);
 
             match message
             {
                 Ok(job) =>
                 {
-                    Logger::printmsg(Logger::Worker, format!("Worker [{}] got a job, executing", id));
+                    let queued = shared.queued.fetch_sub(1, Ordering::SeqCst) - 1;
+                    Logger::printmsg(Logger::Worker, format!("Worker [{}] got a job, executing ({} queued)", id, queued));
+
+                    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job))
+                    {
+                        Logger::printmsg(Logger::WorkerErr, format!("Worker [{}] panicked: {}", id, panic_message(&payload)));
+                        Worker::replace(id, shared);
+                        break;
+                    }
+
+                    // The pool may have been shrunk while this job was running
+                    let max = shared.max_workers.load(Ordering::SeqCst);
+                    if Worker::retire(id, &shared, max)
+                    {
+                        Logger::printmsg(Logger::Worker, format!("Worker [{}] is above the maximum, shutting down", id));
+                        break;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) =>
+                {
+                    let min = shared.min_workers.load(Ordering::SeqCst);
+                    if Worker::retire(id, &shared, min)
+                    {
+                        Logger::printmsg(Logger::Worker, format!("Worker [{}] has been idle for {}s, shutting down", id, shared.keep_alive.as_secs()));
 
-                    job();
+                        // A job sent while this worker still counted as idle
+                        // did not get a worker spawned for it
+                        if shared.queued.load(Ordering::SeqCst) > 0
+                        {
+                            if let Err(e) = Worker::spawn(&shared)
+                            {
+                                Logger::printmsg(Logger::WorkerErr, format!("Cannot spawn a worker for the queued jobs: {}", e));
+                            }
+                        }
+                        break;
+                    }
                 }
-                Err(_) =>
+                Err(mpsc::RecvTimeoutError::Disconnected) =>
                 {
                     Logger::printmsg(Logger::Worker, format!("Worker [{}] disconnected, shutting down", id));
+                    shared.live.fetch_sub(1, Ordering::SeqCst);
                     break;
                 }
             }
-        });
+        })?;
+
+        Ok(Worker { id, thread: Some(thread) })
+    }
+
+    /// Remove the worker with `id` from the pool if more than `keep` are running.
+    fn retire(id: usize, shared: &Shared, keep: usize) -> bool
+    {
+        if shared.live.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| (live > keep).then(|| live - 1)).is_err()
+        {
+            return false;
+        }
+
+        // Dropping the handle detaches the exiting thread
+        shared.workers.lock().unwrap().retain(|worker| worker.id != id);
+        true
+    }
+
+    /// Spawn a fresh worker taking the place of the one with `id`, so the pool
+    /// keeps its size. The thread of the old worker exits right after.
+    fn replace(id: usize, shared: Arc<Shared>)
+    {
+        if shared.shutting_down.load(Ordering::SeqCst)
+        {
+            shared.live.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+
+        Logger::printmsg(Logger::Worker, format!("Respawning worker [{}]", id));
+
+        let replacement = match Worker::new(id, Arc::clone(&shared))
+        {
+            Ok(worker) => worker,
+            Err(e) =>
+            {
+                Logger::printmsg(Logger::WorkerErr, format!("Cannot respawn worker [{}]: {}", id, e));
+                shared.live.fetch_sub(1, Ordering::SeqCst);
+                return;
+            }
+        };
+
+        let mut workers = shared.workers.lock().unwrap();
+        match workers.iter_mut().find(|worker| worker.id == id)
+        {
+            // The old handle is dropped, which detaches the exiting thread
+            Some(worker) => *worker = replacement,
+            None => workers.push(replacement),
+        }
+    }
+}
+
+fn check_bounds(min: usize, max: usize) -> Result<(), PoolCreationError>
+{
+    if max == 0
+    {
+        return Err(PoolCreationError::ZeroSize);
+    }
+
+    if min > max
+    {
+        return Err(PoolCreationError::InvalidBounds { min, max });
+    }
+
+    Ok(())
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> String
+{
+    if let Some(message) = payload.downcast_ref::<&str>()
+    {
+        message.to_string()
+    }
+    else if let Some(message) = payload.downcast_ref::<String>()
+    {
+        message.clone()
+    }
+    else
+    {
+        String::from("unknown panic payload")
+    }
+}
+
+
+/// Handle to the result of a job passed to `ThreadPool::execute`.
+pub struct JobHandle<T>
+{
+    reciever: mpsc::Receiver<T>,
+}
 
-        Worker { id, thread: Some(thread) }
+impl<T> JobHandle<T>
+{
+    /// Block until the job has run and return its value.
+    pub fn join(self) -> Result<T, JobError>
+    {
+        self.reciever.recv().map_err(|_| JobError::Panicked)
+    }
+
+    /// Return the value if the job has already run, without blocking.
+    pub fn try_join(&self) -> Option<Result<T, JobError>>
+    {
+        match self.reciever.try_recv()
+        {
+            Ok(value) => Some(Ok(value)),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(JobError::Panicked)),
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum PoolCreationError
+{
+    ZeroSize,
+    ZeroQueueCapacity,
+    /// The minimum number of workers is above the maximum.
+    InvalidBounds { min: usize, max: usize },
+    /// The operating system refused to start a worker thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            PoolCreationError::ZeroSize => write!(f, "thread pool size must be greater than zero"),
+            PoolCreationError::ZeroQueueCapacity => write!(f, "job queue capacity must be greater than zero"),
+            PoolCreationError::InvalidBounds { min, max } => write!(f, "minimum of {} workers is above the maximum of {}", min, max),
+            PoolCreationError::Spawn(e) => write!(f, "cannot spawn worker thread: {}", e),
+        }
+    }
+}
+
+impl Error for PoolCreationError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError
+{
+    ShuttingDown,
+}
+
+impl fmt::Display for ExecuteError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            ExecuteError::ShuttingDown => write!(f, "thread pool is shutting down"),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobError
+{
+    /// The job panicked before returning a value.
+    Panicked,
+}
+
+impl fmt::Display for JobError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            JobError::Panicked => write!(f, "job panicked"),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const KEEP_ALIVE: Duration = Duration::from_millis(50);
+
+    /// Wait up to a few seconds for `condition` to hold.
+    fn eventually(condition: impl Fn() -> bool) -> bool
+    {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition()
+        {
+            if Instant::now() >= deadline
+            {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    /// Queue `count` jobs that block until the returned sender is dropped.
+    fn block(pool: &ThreadPool, count: usize) -> mpsc::Sender<()>
+    {
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+        for _ in 0..count
+        {
+            let blocked = Arc::clone(&blocked);
+            pool.execute(move || { let _ = blocked.lock().unwrap().recv(); }).unwrap();
+        }
+        release
+    }
+
+    #[test]
+    fn pool_grows_up_to_the_maximum()
+    {
+        let pool = ThreadPool::build_resizable(1, 3, 16, KEEP_ALIVE).unwrap();
+        assert_eq!(pool.size(), 1);
+
+        let release = block(&pool, 5);
+        assert!(eventually(|| pool.size() == 3 && pool.queue_depth() == 2));
+        assert_eq!(pool.idle_workers(), 0);
+
+        drop(release);
+        assert!(eventually(|| pool.queue_depth() == 0));
+    }
+
+    #[test]
+    fn idle_workers_exit_down_to_the_minimum()
+    {
+        let pool = ThreadPool::build_resizable(1, 3, 16, KEEP_ALIVE).unwrap();
+        drop(block(&pool, 3));
+        assert!(eventually(|| pool.size() == 1 && pool.idle_workers() == 1));
+
+        // The pool still serves jobs once shrunk
+        assert_eq!(pool.execute(|| 7).unwrap().join(), Ok(7));
+    }
+
+    #[test]
+    fn pool_is_resized_at_runtime()
+    {
+        let pool = ThreadPool::build_resizable(1, 2, 16, KEEP_ALIVE).unwrap();
+
+        pool.resize(4, 4).unwrap();
+        assert_eq!(pool.size(), 4);
+
+        pool.resize(0, 1).unwrap();
+        assert!(eventually(|| pool.size() == 0));
+        assert_eq!(pool.execute(|| 7).unwrap().join(), Ok(7));
+        assert!(pool.size() <= 1);
+
+        assert!(matches!(pool.resize(2, 1), Err(PoolCreationError::InvalidBounds { min: 2, max: 1 })));
+        assert!(matches!(pool.resize(0, 0), Err(PoolCreationError::ZeroSize)));
+    }
+
+    #[test]
+    fn panicking_jobs_do_not_shrink_the_pool()
+    {
+        let pool = ThreadPool::build_resizable(2, 2, 16, KEEP_ALIVE).unwrap();
+
+        let handle = pool.execute(|| panic!("job failed")).unwrap();
+        assert_eq!(handle.join(), Err(JobError::Panicked));
+        assert_eq!(pool.size(), 2);
+
+        let handles: Vec<JobHandle<usize>> = (0..4).map(|i| pool.execute(move || i).unwrap()).collect();
+        let results: Vec<usize> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(results, [0, 1, 2, 3]);
+        assert_eq!(pool.size(), 2);
+    }
+
+    #[test]
+    fn jobs_sent_to_a_retiring_worker_still_run()
+    {
+        // Jobs arrive around the moment the only worker gives up waiting
+        let pool = ThreadPool::build_resizable(0, 1, 16, Duration::from_millis(1)).unwrap();
+        for i in 0..200
+        {
+            thread::sleep(Duration::from_micros(900 + i % 5 * 50));
+            let handle = pool.execute(move || i).unwrap();
+            assert!(eventually(|| handle.try_join().is_some()), "job {} was never run", i);
+        }
     }
 }
@@ -1,129 +1,427 @@
 use std::
 {
-    collections::HashMap,
-    time::{Instant, Duration},
-    sync::{Arc, RwLock}, thread,
+    net::IpAddr,
+    time::Duration,
+    sync::{Arc, Condvar, Mutex, RwLock, atomic::{AtomicU8, Ordering}}, thread,
 };
 
+use crate::ban::BanList;
+use crate::cidr;
+use crate::config::{BanConfig, LimiterConfig, RuleConfig};
+use crate::fileutils::{normalize_path, path_matches};
 use crate::logger::Logger;
+use crate::ratelimit::{Algorithm, Capacity, ClientStore, Clock, SystemClock};
+use crate::request::Method;
 
 
-#[derive(Debug)]
-pub struct Limiter
+/// Outcome of `Limiter::check`, with what the client is told about its quota.
+#[derive(Debug, Clone, Copy)]
+pub struct Decision
+{
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the quota is full again.
+    pub reset: Duration,
+    /// Time until a denied request would be allowed, zero when allowed.
+    pub retry_after: Duration,
+}
+
+impl Decision
+{
+    pub fn allow(limit: u32, remaining: u32, reset: Duration) -> Decision
+    {
+        Decision { allowed: true, limit, remaining, reset, retry_after: Duration::ZERO }
+    }
+
+    pub fn deny(limit: u32, reset: Duration, retry_after: Duration) -> Decision
+    {
+        Decision { allowed: false, limit, remaining: 0, reset, retry_after }
+    }
+
+    /// `reset` in whole seconds, rounded up so a client waiting that long is
+    /// never early.
+    pub fn reset_secs(&self) -> u64
+    {
+        ceil_secs(self.reset)
+    }
+
+    /// `retry_after` in whole seconds, rounded up like `reset_secs`.
+    pub fn retry_after_secs(&self) -> u64
+    {
+        ceil_secs(self.retry_after)
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64
+{
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Settings the client states were built for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Settings
 {
-    request: Arc<RwLock<HashMap<String, (Instant, u32)>>>,
+    algorithm: Algorithm,
     max_requests: u32,
     window: Duration,
 }
 
+pub struct Limiter
+{
+    clients: RwLock<Box<dyn ClientStore>>,
+    settings: Mutex<Settings>,
+    /// IPv6 clients sharing this many leading bits share a quota.
+    ipv6_prefix: AtomicU8,
+    clock: Arc<dyn Clock>,
+}
+
 impl Limiter
 {
-    pub fn new(max_requests: u32, window: Duration) -> Limiter
+    /// `ipv6_prefix` is the length of the prefix IPv6 clients are grouped by,
+    /// 128 gives every address its own quota.
+    pub fn new(algorithm: Algorithm, max_requests: u32, window: Duration, ipv6_prefix: u8, capacity: Capacity) -> Limiter
+    {
+        Limiter::with_clock(algorithm, max_requests, window, ipv6_prefix, capacity, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(algorithm: Algorithm, max_requests: u32, window: Duration, ipv6_prefix: u8, capacity: Capacity, clock: Arc<dyn Clock>) -> Limiter
     {
         Limiter
         { 
-            request: Arc::new(RwLock::new(HashMap::new())),
-            max_requests,
-            window,
+            clients: RwLock::new(algorithm.build(max_requests, window, capacity)),
+            settings: Mutex::new(Settings { algorithm, max_requests, window }),
+            ipv6_prefix: AtomicU8::new(ipv6_prefix),
+            clock,
         }
     }
 
-    pub fn check(&self, address: &str) -> bool
+    /// Apply new settings. Client states only make sense for the settings
+    /// they were built for, so they are dropped when those change.
+    pub fn reconfigure(&self, algorithm: Algorithm, max_requests: u32, window: Duration, ipv6_prefix: u8, capacity: Capacity)
     {
-        let requests = Arc::clone(&self.request);
+        self.ipv6_prefix.store(ipv6_prefix, Ordering::Relaxed);
 
-        // Read phase: Lookup the entry
-        let entry = 
+        let settings = Settings { algorithm, max_requests, window };
+        let mut current = self.settings.lock().expect("Mutex poisoned");
+        if *current != settings
         {
-            let map_data = requests.read().expect("RwLock poisoned");
-            map_data.get(address).cloned()
-        };
+            *self.clients.write().expect("RwLock poisoned") = algorithm.build(max_requests, window, capacity);
+            *current = settings;
+            Logger::printmsg(Logger::Info, format!("Rate limiter now uses {} with {} requests per {}s, quotas were reset", algorithm.name(), max_requests, window.as_secs()));
+        }
+        else
+        {
+            self.clients.read().expect("RwLock poisoned").set_capacity(capacity);
+        }
+    }
 
-        if let Some(entry) = entry 
+    /// Count a request from `address` against its quota.
+    pub fn check(&self, address: IpAddr) -> Decision
+    {
+        let key = self.client_key(address);
+        self.clients.read().expect("RwLock poisoned").check(key, self.clock.now())
+    }
+
+    /// Address the quota of `address` is tracked under.
+    pub fn client_key(&self, address: IpAddr) -> IpAddr
+    {
+        cidr::client_key(address, self.ipv6_prefix.load(Ordering::Relaxed))
+    }
+
+    /// Forget idle clients, once there are at least `min_size`.
+    pub fn clean(&self, min_size: usize) -> usize
+    {
+        self.clients.read().expect("RwLock poisoned").clean(self.clock.now(), min_size)
+    }
+
+    /// Clients tracked right now.
+    pub fn tracked(&self) -> usize
+    {
+        self.clients.read().expect("RwLock poisoned").tracked()
+    }
+}
+
+
+/// A `[limiter.NAME]` rule and the limiter counting the requests it matches,
+/// `None` when they are not limited.
+struct Rule
+{
+    name: String,
+    path: String,
+    methods: Vec<Method>,
+    limiter: Option<Limiter>,
+}
+
+impl Rule
+{
+    fn matches(&self, method: Method, path: &str) -> bool
+    {
+        (self.methods.is_empty() || self.methods.contains(&method)) && path_matches(&self.path, path)
+    }
+}
+
+/// Every rate limit applied to requests: the rules, tried in order, and the
+/// `[limiter]` defaults for requests no rule matches.
+pub struct Policies
+{
+    default: Limiter,
+    rules: RwLock<Vec<Rule>>,
+    bans: BanList,
+}
+
+impl Policies
+{
+    pub fn new(config: &LimiterConfig, bans: &BanConfig) -> Policies
+    {
+        let default = Limiter::new(config.algorithm, config.max_requests, config.window, config.ipv6_prefix, capacity(config));
+        let rules = config.rules.iter().map(|rule| Policies::build_rule(rule, config, None)).collect();
+        Policies { default, rules: RwLock::new(rules), bans: BanList::new(bans, config.ipv6_prefix) }
+    }
+
+    /// Clients banned for repeated offenses.
+    pub fn bans(&self) -> &BanList
+    {
+        &self.bans
+    }
+
+    /// Apply a new configuration. Rules keep the state of their clients as
+    /// long as their name and limits stay the same.
+    pub fn reconfigure(&self, config: &LimiterConfig, bans: &BanConfig)
+    {
+        self.bans.reconfigure(bans, config.ipv6_prefix);
+        self.default.reconfigure(config.algorithm, config.max_requests, config.window, config.ipv6_prefix, capacity(config));
+
+        let mut rules = self.rules.write().expect("RwLock poisoned");
+        let mut previous: Vec<Rule> = rules.drain(..).collect();
+        for rule in config.rules.iter()
         {
-            // Get write lock if entry found
-            let mut map_data = requests.write().expect("RwLock poisoned");
+            let existing = previous.iter().position(|old| old.name == rule.name).and_then(|index| previous.swap_remove(index).limiter);
+            rules.push(Policies::build_rule(rule, config, existing));
+        }
+    }
 
-            if entry.0.elapsed() > self.window
+    fn build_rule(rule: &RuleConfig, config: &LimiterConfig, existing: Option<Limiter>) -> Rule
+    {
+        let limiter = rule.limit.map(|limit|
+        {
+            let algorithm = limit.algorithm.unwrap_or(config.algorithm);
+            match existing
             {
-                let entry = map_data.entry(address.to_string()).or_insert((Instant::now(), 0));
-                *entry = (Instant::now(), 1);
-                true
+                Some(limiter) =>
+                {
+                    limiter.reconfigure(algorithm, limit.max_requests, limit.window, config.ipv6_prefix, capacity(config));
+                    limiter
+                },
+                None => Limiter::new(algorithm, limit.max_requests, limit.window, config.ipv6_prefix, capacity(config)),
             }
-            else if entry.1 < self.max_requests
+        });
+
+        Rule { name: rule.name.clone(), path: rule.path.clone(), methods: rule.methods.clone(), limiter }
+    }
+
+    /// Count a request against the first rule matching it.
+    ///
+    /// `path` is the one the request is served from, see `served_path`.
+    /// Returns `None` when that rule does not limit the request.
+    pub fn check(&self, method: Method, path: &str, address: IpAddr) -> Option<Decision>
+    {
+        // "/api/../api/x" and "/%61pi/x" must not get around "/api/*"
+        let path = normalize_path(path).unwrap_or_else(|| path.to_string());
+
+        let rules = self.rules.read().expect("RwLock poisoned");
+        match rules.iter().find(|rule| rule.matches(method, &path))
+        {
+            Some(rule) => rule.limiter.as_ref().map(|limiter| limiter.check(address)),
+            None => Some(self.default.check(address)),
+        }
+    }
+
+    /// Drop idle clients every `delay` on a thread of its own, until the
+    /// returned `Cleaner` is stopped.
+    ///
+    /// Checks already drop idle clients and keep every limiter within
+    /// `max_clients`, this only frees memory once requests stop coming.
+    pub fn run_clean_cycle(&self, delay: Duration, max_size: usize, rate_limiter: Arc<Policies>) -> Cleaner
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let signal = Arc::clone(&stop);
+
+        let thread = thread::spawn(move ||
+        {
+            let (stopped, condvar) = &*signal;
+            loop
             {
-                let entry = map_data.entry(address.to_string()).or_insert((Instant::now(), 0));
-                *entry = (Instant::now(), entry.1 + 1);
-                true
+                let guard = stopped.lock().expect("Mutex poisoned");
+                let (guard, _) = condvar.wait_timeout_while(guard, delay, |stopped| !*stopped).expect("Mutex poisoned");
+                if *guard
+                {
+                    return;
+                }
+                drop(guard);
+
+                let rules = rate_limiter.rules.read().expect("RwLock poisoned");
+                let limiters: Vec<&Limiter> = std::iter::once(&rate_limiter.default).chain(rules.iter().filter_map(|rule| rule.limiter.as_ref())).collect();
+                let cleaned_count: usize = limiters.iter().map(|limiter| limiter.clean(max_size)).sum();
+                let tracked: usize = limiters.iter().map(|limiter| limiter.tracked()).sum();
+                Logger::printmsg(Logger::Info, format!("Rate limiter cleaning: dropped {cleaned_count} idle clients, {tracked} tracked"));
             }
-            else
+        });
+
+        Cleaner { stop, thread: Some(thread) }
+    }
+}
+
+fn capacity(config: &LimiterConfig) -> Capacity
+{
+    Capacity { max_clients: config.max_clients, idle: config.clean_elapsed }
+}
+
+
+/// Handle of the thread started by `Policies::run_clean_cycle`, stopping it
+/// when dropped.
+pub struct Cleaner
+{
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Cleaner
+{
+    /// Stop the thread and wait for it, a running cleanup is finished first.
+    pub fn stop(self)
+    {
+        drop(self);
+    }
+}
+
+impl Drop for Cleaner
+{
+    fn drop(&mut self)
+    {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock().expect("Mutex poisoned") = true;
+        condvar.notify_all();
+
+        if let Some(thread) = self.thread.take()
+        {
+            if thread.join().is_err()
             {
-                false
+                Logger::printmsg(Logger::ThreadErr, String::from("Rate limiter cleaner has panicked"));
             }
         }
-        else
-        {
-            let mut map_data = requests.write().expect("RwLock poisoned");
+    }
+}
 
-            map_data.entry(address.to_string()).or_insert((Instant::now(), 0));
-            true
-        }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::config::RuleLimit;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn rule(name: &str, path: &str, methods: &[Method], max_requests: Option<u32>) -> RuleConfig
+    {
+        let limit = max_requests.map(|max_requests| RuleLimit { algorithm: None, max_requests, window: WINDOW });
+        RuleConfig { name: name.to_string(), path: path.to_string(), methods: methods.to_vec(), limit }
     }
 
-    pub fn extract_address(mut peer: String) -> Option<String>
+    fn config(rules: Vec<RuleConfig>) -> LimiterConfig
     {
-        let port_offset = match peer.find(':')
+        LimiterConfig
         {
-            Some(result) => result,
-            _ => return None,
-        };
+            algorithm: Algorithm::FixedWindow,
+            max_requests: 100,
+            window: WINDOW,
+            clean_delay: WINDOW,
+            clean_elapsed: WINDOW,
+            clean_max_size: 150,
+            max_clients: 1000,
+            ipv6_prefix: 128,
+            rules,
+        }
+    }
 
-        peer.replace_range(port_offset.., "");
-        Some(peer)
+    fn no_bans() -> BanConfig
+    {
+        BanConfig { strikes: 0, find_time: WINDOW, ban_time: WINDOW, max_ban_time: WINDOW, file: None }
     }
 
-    pub fn run_clean_cycle(&self,
-                         delay: Duration,
-                         max_size: usize,
-                         clear_time: Duration,
-                         rate_limiter: Arc<Limiter>,
-                        )
+    /// Limit and remaining quota of the policy `path` falls under.
+    fn quota(policies: &Policies, method: Method, path: &str) -> Option<(u32, u32)>
     {
-        thread::spawn(move ||
+        policies.check(method, path, CLIENT).map(|decision| (decision.limit, decision.remaining))
+    }
+
+    #[test]
+    fn the_first_matching_rule_applies()
+    {
+        let rules = vec![rule("login", "/api/login", &[], Some(2)), rule("api", "/api", &[], Some(5))];
+        let policies = Policies::new(&config(rules), &no_bans());
+
+        assert_eq!(quota(&policies, Method::Get, "/api/login"), Some((2, 1)));
+        assert_eq!(quota(&policies, Method::Get, "/api/users"), Some((5, 4)));
+        assert_eq!(quota(&policies, Method::Get, "/index.html"), Some((100, 99)));
+
+        // Rules keep their own count
+        assert_eq!(quota(&policies, Method::Get, "/api/login"), Some((2, 0)));
+        assert!(!policies.check(Method::Get, "/api/login", CLIENT).unwrap().allowed);
+        assert_eq!(quota(&policies, Method::Get, "/api/users"), Some((5, 3)));
+    }
+
+    #[test]
+    fn rules_apply_to_their_methods_only()
+    {
+        let rules = vec![rule("uploads", "/files", &[Method::Post, Method::Put], Some(1))];
+        let policies = Policies::new(&config(rules), &no_bans());
+
+        assert_eq!(quota(&policies, Method::Post, "/files/a"), Some((1, 0)));
+        assert!(!policies.check(Method::Put, "/files/a", CLIENT).unwrap().allowed);
+        assert_eq!(quota(&policies, Method::Get, "/files/a"), Some((100, 99)));
+    }
+
+    #[test]
+    fn paths_are_normalized_before_matching()
+    {
+        let rules = vec![rule("admin", "/admin/*", &[], Some(1))];
+        let policies = Policies::new(&config(rules), &no_bans());
+
+        assert_eq!(quota(&policies, Method::Get, "/%61dmin/panel"), Some((1, 0)));
+        assert!(!policies.check(Method::Get, "/static/../admin/panel", CLIENT).unwrap().allowed);
+        assert!(!policies.check(Method::Get, "/admin/./panel", CLIENT).unwrap().allowed);
+    }
+
+    #[test]
+    fn unlimited_rules_are_not_counted()
+    {
+        let rules = vec![rule("health", "/health", &[], None), rule("everything", "/*", &[], Some(1))];
+        let policies = Policies::new(&config(rules), &no_bans());
+
+        for _ in 0..3
         {
-            loop 
-            {
-                thread::sleep(delay);
-                let mut cleaned_count = 0;
-                {
-                    Logger::printmsg(Logger::Info, format!("Trying to clean rate limiter hashmap..."));
-
-                    let requests = Arc::clone(&rate_limiter.request);
-                    let mut keys_to_remove = Vec::new();
-
-                    {
-                        let map_data = requests.read().expect("RwLock poisoned");
-                        if map_data.len() >= max_size
-                        {
-                            for (key, value) in map_data.iter()
-                            {
-                                if value.0.elapsed() > clear_time
-                                {
-                                    keys_to_remove.push(key.clone());
-                                }
-                            }
-                        }
-                    }
-
-                    let mut map_data = requests.write().expect("RwLock poisoned");
-                    for key in keys_to_remove
-                    {
-                        map_data.remove(&key);
-                        cleaned_count += 1;
-                    }
-                }
-                Logger::printmsg(Logger::Info, format!("Limiter hashmap cleaning: cleaned {cleaned_count} entries"));
-            }
-        });
+            assert!(policies.check(Method::Get, "/health", CLIENT).is_none());
+        }
+        assert_eq!(quota(&policies, Method::Get, "/other"), Some((1, 0)));
+    }
+
+    #[test]
+    fn reloading_keeps_client_state_by_rule_name()
+    {
+        let policies = Policies::new(&config(vec![rule("api", "/api", &[], Some(3)), rule("feed", "/feed", &[], Some(3))]), &no_bans());
+        quota(&policies, Method::Get, "/api/a");
+        quota(&policies, Method::Get, "/api/a");
+        quota(&policies, Method::Get, "/feed");
+
+        // "api" moves to another path and position, "feed" is replaced by "news"
+        let rules = vec![rule("news", "/feed", &[], Some(3)), rule("api", "/v2", &[], Some(3))];
+        policies.reconfigure(&config(rules), &no_bans());
+
+        assert_eq!(quota(&policies, Method::Get, "/v2/a"), Some((3, 0)));
+        assert_eq!(quota(&policies, Method::Get, "/feed"), Some((3, 2)));
+        assert_eq!(quota(&policies, Method::Get, "/api/a"), Some((100, 99)));
     }
 }
@@ -1,5 +1,35 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
 use chrono::Local;
 
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel
+{
+    /// Only errors.
+    Error = 0,
+    /// Errors, requests and general information.
+    Info = 1,
+    /// Everything, including thread and worker activity.
+    Debug = 2,
+}
+
+impl LogLevel
+{
+    pub fn parse(level: &str) -> Option<LogLevel>
+    {
+        match level
+        {
+            "error" => Some(LogLevel::Error),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub enum Logger
 {
@@ -24,8 +54,28 @@ enum Colors
 
 impl Logger
 {
+    pub fn set_level(level: LogLevel)
+    {
+        LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+    }
+
+    fn level(&self) -> LogLevel
+    {
+        match self
+        {
+            Logger::RequestErr | Logger::ThreadErr | Logger::WorkerErr | Logger::InfoErr => LogLevel::Error,
+            Logger::Request | Logger::Info => LogLevel::Info,
+            Logger::Thread | Logger::Worker => LogLevel::Debug,
+        }
+    }
+
     pub fn printmsg(self, msg: String)
     {
+        if self.level() as u8 > LOG_LEVEL.load(Ordering::Relaxed)
+        {
+            return;
+        }
+
         let mut timestamp = Local::now().to_string();
         timestamp = match timestamp.find(".")
         {
@@ -77,14 +127,14 @@ impl Logger
         let closing_chars = "m";
         
         chars.push_str(&(number as u32).to_string());
-        chars.push_str(&closing_chars);
+        chars.push_str(closing_chars);
 
         chars.push_str(msg);
         chars.push_str("\x1b[0m");
 
         let mut result = start_type_char.to_string();
         result.push_str(&chars);
-        result.push_str(&end_type_char.to_string());
+        result.push(end_type_char);
 
         result
     }
@@ -1,237 +1,880 @@
 use std::
 {
-  net::{TcpListener, TcpStream},
-  io::{BufReader, BufRead, Write, ErrorKind},
-  fs,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+  io::{self, ErrorKind},
   env,
   time::Duration, 
-  sync::Arc,
+  sync::{Arc, Weak, atomic::{AtomicBool, Ordering}},
+  process,
+  thread,
 };
 
+pub mod access;
+pub mod ban;
+use ban::Offense;
+pub mod cidr;
+pub mod connections;
+use connections::{ConnectionGuard, ConnectionLimits, Refusal};
 pub mod limiter;
-use crate::limiter::Limiter;
+pub mod proxy;
+pub mod ratelimit;
+use crate::limiter::{Decision, Policies};
 
-pub mod logger;
+use webserver::logger;
 use logger::*;
 
 pub mod fileutils;
-use fileutils::{get_filetype, get_filename};
+use fileutils::{escapes_root, get_filename, served_path, ResolveError};
 
 use webserver::ThreadPool;
 
+use socket2::{Domain, Protocol, Socket, Type};
+
+use signal_hook::{consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2}, iterator::Signals};
+
 pub mod config;
+pub mod configfile;
+use config::{Backend, Config, ConfigError, SharedConfig};
+
+pub mod event_loop;
+use event_loop::EventLoop;
+
+pub mod request;
+use request::{Method, Request, ParseError};
+
+pub mod response;
+use response::Response;
 
+pub mod status;
+use status::StatusCode;
 
-const BIND_ADDRESS: &str = "0.0.0.0:7878";
+pub mod throttle;
+use throttle::{Bandwidth, Pacer};
 
-const DEFAULT_RESPONSE_PAGE_NAME: &str = "index.html";
+pub mod timeout;
+use timeout::{TimedReader, TimedWriter};
 
-// Limiter settings
-const MAX_REQUESTS: u32 = 100;
-const MAX_REQUESTS_WINDOW_DURATION: Duration = Duration::from_secs(3600);
-const LIMITER_CLEAN_DELAY: Duration = Duration::from_secs(3600);
-const LIMITER_CLEAN_ELAPSED: Duration = Duration::from_secs(3600);
-const LIMITER_CLEAN_MAXSIZE: usize = 150;
+
+/// Sent in `Retry-After` when the job queue is full.
+const OVERLOAD_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Set once SIGTERM/SIGINT is received.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
 
 
 fn main()
 {
     let args: Vec<String> = env::args().collect();
-    let config = config::Config::build(&args);
+    let config = match Config::build(&args)
+    {
+        Ok(config) => config,
+        Err(e @ (ConfigError::HelpRequested | ConfigError::VersionRequested)) =>
+        {
+            println!("{}", e);
+            process::exit(0);
+        },
+        Err(e) =>
+        {
+            Logger::printmsg(Logger::InfoErr, format!("{}. Try \"--help\" for more information.", e));
+            process::exit(2);
+        }
+    };
+
+    Logger::set_level(config.log_level);
+
+    // Bind everything first so a bad address fails before anything is served
+    let mut listeners = Vec::with_capacity(config.listen.len());
+    for address in config.listen.iter()
+    {
+        match bind(address)
+        {
+            Ok(listener) => listeners.push((address.clone(), listener)),
+            Err(e) =>
+            {
+                Logger::printmsg(Logger::InfoErr, format!("Cannot start the server on {}: {}", address, e));
+                process::exit(1);
+            }
+        }
+    }
+
+    let limiter = &config.limiter;
+    let rate_limiter = Arc::new(Policies::new(limiter, &config.bans));
+    let cleaner = rate_limiter.run_clean_cycle(limiter.clean_delay, limiter.clean_max_size, Arc::clone(&rate_limiter));
+    let connections = Arc::new(ConnectionLimits::new(&config.connections, limiter.ipv6_prefix));
+    let sweeper = connections.run_sweeper();
+    let bandwidth = Arc::new(Bandwidth::new(&config.bandwidth, limiter.ipv6_prefix));
+
+    let pool = match ThreadPool::build_resizable(config.min_threads(), config.threads, config.queue_capacity, config.worker_keep_alive)
+    {
+        Ok(pool) => Arc::new(pool),
+        Err(e) =>
+        {
+            Logger::printmsg(Logger::InfoErr, format!("Cannot start the worker pool: {}", e));
+            process::exit(1);
+        }
+    };
+
+    let config = Arc::new(SharedConfig::new(config));
+
+    let mut wake_addresses = Vec::with_capacity(listeners.len());
+    for (address, listener) in listeners.iter()
+    {
+        if let Ok(local) = listener.local_addr()
+        {
+            wake_addresses.push(local);
+        }
+
+        Logger::printmsg(Logger::Info, format!("Server is started on {}, serving \"{}\"", address, config.get().file_path));
+    }
+
+    let listeners: Vec<TcpListener> = listeners.into_iter().map(|(_, listener)| listener).collect();
+    let mut accept_threads = Vec::with_capacity(listeners.len());
+    match config.get().backend
+    {
+        Backend::Threads => for listener in listeners
+        {
+            let pool = Arc::clone(&pool);
+            let config = Arc::clone(&config);
+            let rate_limiter = Arc::clone(&rate_limiter);
+            let connections = Arc::clone(&connections);
+            let bandwidth = Arc::clone(&bandwidth);
+
+            accept_threads.push(thread::spawn(move ||
+            {
+                accept_loop(listener, pool, config, rate_limiter, connections, bandwidth);
+            }));
+        },
+        Backend::Epoll =>
+        {
+            let event_loop = match EventLoop::new(listeners, Arc::clone(&pool), Arc::clone(&config), Arc::clone(&rate_limiter), Arc::clone(&connections), Arc::clone(&bandwidth))
+            {
+                Ok(event_loop) => event_loop,
+                Err(e) =>
+                {
+                    Logger::printmsg(Logger::InfoErr, format!("Cannot start the event loop: {}", e));
+                    process::exit(1);
+                }
+            };
+
+            accept_threads.push(thread::spawn(move || event_loop.run()));
+        },
+    }
+
+    watch_signals(args, Arc::clone(&config), Arc::clone(&rate_limiter), connections, bandwidth, Arc::downgrade(&pool), wake_addresses);
+
+    for accept_thread in accept_threads
+    {
+        if accept_thread.join().is_err()
+        {
+            Logger::printmsg(Logger::ThreadErr, String::from("Accept thread has panicked"));
+        }
+    }
+
+    // Only reached on shutdown: dropping the last handle of the pool waits
+    // for the queued and running connections
+    Logger::printmsg(Logger::Info, String::from("Stopped accepting connections, waiting for running ones to finish..."));
+    cleaner.stop();
+    drop(pool);
+    sweeper.stop();
+
+    Logger::printmsg(Logger::Info, String::from("All connections are finished, server is stopped"));
+}
+
+/// Bind a listener on `address`.
+///
+/// The IPv6 wildcard is bound dual-stack whatever the system default, so
+/// `[::]` accepts IPv4 clients too. Hosts without IPv6 get `0.0.0.0` instead.
+fn bind(address: &str) -> io::Result<TcpListener>
+{
+    let mut last_error = io::Error::new(ErrorKind::InvalidInput, "address resolved to nothing");
+
+    for address in address.to_socket_addrs()?
+    {
+        let dual_stack = address.ip() == Ipv6Addr::UNSPECIFIED;
+
+        match bind_socket(address, dual_stack)
+        {
+            Ok(listener) => return Ok(listener),
+            Err(e) if dual_stack && !matches!(e.kind(), ErrorKind::AddrInUse | ErrorKind::PermissionDenied) =>
+            {
+                let fallback = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), address.port());
+                Logger::printmsg(Logger::InfoErr, format!("Cannot listen on IPv6 ({}), using {} instead", e, fallback));
+                return bind_socket(fallback, false);
+            },
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+fn bind_socket(address: SocketAddr, dual_stack: bool) -> io::Result<TcpListener>
+{
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if dual_stack
+    {
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+/// Handle SIGHUP (reload), SIGUSR1 (status), SIGUSR2 (lift the bans) and
+/// SIGTERM/SIGINT (shutdown).
+///
+/// The pool is held weakly so the signal thread never keeps it alive on shutdown.
+fn watch_signals(args: Vec<String>, config: Arc<SharedConfig>, rate_limiter: Arc<Policies>, connections: Arc<ConnectionLimits>, bandwidth: Arc<Bandwidth>, pool: Weak<ThreadPool>, wake_addresses: Vec<SocketAddr>)
+{
+    let mut signals = match Signals::new([SIGHUP, SIGUSR1, SIGUSR2, SIGTERM, SIGINT])
+    {
+        Ok(signals) => signals,
+        Err(e) =>
+        {
+            Logger::printmsg(Logger::ThreadErr, format!("Cannot install signal handlers: {}", e));
+            return;
+        }
+    };
+
+    thread::spawn(move ||
+    {
+        for signal in signals.forever()
+        {
+            match signal
+            {
+                SIGHUP => reload_config(&args, &config, &rate_limiter, &connections, &bandwidth, &pool),
+                SIGUSR1 => if let Some(pool) = pool.upgrade()
+                {
+                    let bans = rate_limiter.bans().active();
+                    let (open, waiting) = connections.counts();
+                    Logger::printmsg(Logger::Info, format!("Status: {} workers ({} idle), {}/{} connections queued, {} connections open ({} waiting for a slot), {} clients banned",
+                        pool.size(), pool.idle_workers(), pool.queue_depth(), pool.queue_capacity(), open, waiting, bans.len()));
+                    for (address, left) in bans
+                    {
+                        Logger::printmsg(Logger::Info, format!("Banned: {} for {}s more", address, left.as_secs()));
+                    }
+                },
+                SIGUSR2 =>
+                {
+                    let lifted = rate_limiter.bans().lift_all();
+                    Logger::printmsg(Logger::Info, format!("Lifted {} bans", lifted));
+                },
+                _ if SHUTTING_DOWN.load(Ordering::SeqCst) =>
+                {
+                    Logger::printmsg(Logger::InfoErr, String::from("Received a second stop signal, exiting immediately"));
+                    process::exit(1);
+                },
+                _ =>
+                {
+                    // Before waking the listeners, so the upgraded handle is gone
+                    // by the time main drops the last one
+                    if let Some(pool) = pool.upgrade()
+                    {
+                        pool.shutdown();
+                    }
+                    begin_shutdown(config.get().shutdown_grace, &wake_addresses);
+                },
+            }
+        }
+    });
+}
+
+/// Stop accepting connections and force an exit once `grace` is over.
+fn begin_shutdown(grace: Duration, wake_addresses: &[SocketAddr])
+{
+    Logger::printmsg(Logger::Info, format!("Shutting down, running connections have {} seconds to finish...", grace.as_secs()));
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+
+    // The accept loops are blocked in accept(), a connection makes them
+    // see the flag
+    for address in wake_addresses
+    {
+        let mut address = *address;
+        if address.ip().is_unspecified()
+        {
+            match address
+            {
+                SocketAddr::V4(_) => address.set_ip(Ipv4Addr::LOCALHOST.into()),
+                SocketAddr::V6(_) => address.set_ip(Ipv6Addr::LOCALHOST.into()),
+            }
+        }
+
+        if let Err(e) = TcpStream::connect_timeout(&address, Duration::from_secs(1))
+        {
+            Logger::printmsg(Logger::ThreadErr, format!("Cannot wake up the listener on {}: {}", address, e));
+        }
+    }
+
+    thread::spawn(move ||
+    {
+        thread::sleep(grace);
+        Logger::printmsg(Logger::InfoErr, String::from("Grace period is over, closing the remaining connections"));
+        process::exit(1);
+    });
+}
 
-    let listener = TcpListener::bind(BIND_ADDRESS).expect(&format!("Cannot start the server on {}", BIND_ADDRESS));
-    Logger::printmsg(Logger::Info, format!("Server is started on {}", BIND_ADDRESS));
+/// Re-read the configuration and swap it in.
+///
+/// Connections pick the new configuration up on their next request, the ones
+/// in the middle of a request finish it with the previous one.
+fn reload_config(args: &[String], shared: &SharedConfig, rate_limiter: &Policies, connections: &Arc<ConnectionLimits>, bandwidth: &Bandwidth, pool: &Weak<ThreadPool>)
+{
+    Logger::printmsg(Logger::Info, String::from("Reloading configuration..."));
+
+    let mut config = match Config::build(args)
+    {
+        Ok(config) => config,
+        Err(e) =>
+        {
+            Logger::printmsg(Logger::InfoErr, format!("Configuration reload failed, keeping the current one: {}", e));
+            return;
+        }
+    };
+
+    let current = shared.get();
+    if config.listen != current.listen || config.backend != current.backend || config.queue_capacity != current.queue_capacity || config.worker_keep_alive != current.worker_keep_alive
+    {
+        Logger::printmsg(Logger::InfoErr, String::from("Changes to listeners, backend, queue capacity and worker keep-alive require a restart, ignoring them"));
+        config.listen = current.listen.clone();
+        config.backend = current.backend;
+        config.queue_capacity = current.queue_capacity;
+        config.worker_keep_alive = current.worker_keep_alive;
+    }
 
-    let rate_limiter = Arc::new(Limiter::new(MAX_REQUESTS, MAX_REQUESTS_WINDOW_DURATION));
-    rate_limiter.run_clean_cycle(LIMITER_CLEAN_DELAY, LIMITER_CLEAN_MAXSIZE, LIMITER_CLEAN_ELAPSED, Arc::clone(&rate_limiter));
+    if config.threads != current.threads || config.min_threads() != current.min_threads()
+    {
+        if let Some(pool) = pool.upgrade()
+        {
+            if let Err(e) = pool.resize(config.min_threads(), config.threads)
+            {
+                Logger::printmsg(Logger::InfoErr, format!("Cannot resize the worker pool: {}", e));
+            }
+        }
+    }
+
+    Logger::set_level(config.log_level);
+    rate_limiter.reconfigure(&config.limiter, &config.bans);
+    connections.reconfigure(&config.connections, config.limiter.ipv6_prefix);
+    bandwidth.reconfigure(&config.bandwidth, config.limiter.ipv6_prefix);
+    shared.replace(config);
 
-    let pool = ThreadPool::new(20);
+    Logger::printmsg(Logger::Info, String::from("Configuration reloaded"));
+}
 
+fn accept_loop(listener: TcpListener, pool: Arc<ThreadPool>, shared: Arc<SharedConfig>, rate_limiter: Arc<Policies>, connections: Arc<ConnectionLimits>, bandwidth: Arc<Bandwidth>)
+{
     for stream in listener.incoming()
     {
-        let stream = stream.unwrap();
+        if SHUTTING_DOWN.load(Ordering::SeqCst)
+        {
+            break;
+        }
+
+        let stream = match stream
+        {
+            Ok(stream) => stream,
+            Err(e) =>
+            {
+                Logger::printmsg(Logger::ThreadErr, format!("Failed to accept a connection: {}", e));
+                continue;
+            }
+        };
 
-        let mut stream_peer = stream.peer_addr().unwrap().to_string();
-        stream_peer = match Limiter::extract_address(stream_peer)
+        let stream_peer = match stream.peer_addr()
         {
-            Some(result) => result,
-            _ => {
-                Logger::printmsg(Logger::InfoErr, String::from("Couldn't extract the ip address."));
+            // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+            Ok(peer) => peer.ip().to_canonical(),
+            Err(e) =>
+            {
+                Logger::printmsg(Logger::InfoErr, format!("Couldn't read the peer address: {}", e));
                 continue;
             },
         };
 
-        let stream_peer_limit = rate_limiter.check(&stream_peer);
-        if stream_peer_limit == false
+        // Only a proxy can start with a PROXY header
+        let config = shared.get();
+        if config.proxy.protocol && !proxy::is_trusted(&config.proxy.trusted, stream_peer)
         {
-            Logger::printmsg(Logger::Info, format!("Request has been blocked from {}", stream.peer_addr().unwrap().to_string()));
+            Logger::printmsg(Logger::RequestErr, format!("Refusing the connection from {}, it is not a trusted proxy", stream_peer));
             continue;
-        };
+        }
 
-        let path = config.file_path.clone();
+        if let Err(response) = admit(stream_peer, &pool, &config, &rate_limiter)
+        {
+            if let Err(e) = response.write_to(&mut &stream)
+            {
+                Logger::printmsg(Logger::InfoErr, format!("Writing to stream is failed: {}", e));
+            }
+            continue;
+        }
 
-        pool.execute(|| 
+        // Held weakly, a waiting connection must not keep the pool alive on shutdown
+        let pool = Arc::downgrade(&pool);
+        let shared = Arc::clone(&shared);
+        let rate_limiter = Arc::clone(&rate_limiter);
+        let bandwidth = Arc::clone(&bandwidth);
+        connections.admit(capped_client(stream_peer, &config), move |slot| match slot
         {
-            handle_connection(stream, path);
+            Ok(slot) => start_connection(stream, slot, &pool, shared, rate_limiter, bandwidth, stream_peer),
+            Err(refusal) =>
+            {
+                Logger::printmsg(Logger::Info, format!("Refusing the connection from {}: {}", stream_peer, refusal));
+                if let Err(e) = refused(refusal, &shared.get()).write_to(&mut &stream)
+                {
+                    Logger::printmsg(Logger::InfoErr, format!("Writing to stream is failed: {}", e));
+                }
+            },
         });
     }
 }
 
-fn handle_connection(stream: TcpStream, path: String)
+/// Hand a connection that got its slot to the pool, the slot is given back
+/// once the connection is closed.
+///
+/// Runs on the worker that freed the slot when the connection had to wait.
+fn start_connection(stream: TcpStream, slot: ConnectionGuard, pool: &Weak<ThreadPool>, shared: Arc<SharedConfig>, rate_limiter: Arc<Policies>, bandwidth: Arc<Bandwidth>, stream_peer: IpAddr)
 {
-    let buf_reader = BufReader::new(&stream);
-    let full_request: Vec<_> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
-
+    let pool = match pool.upgrade()
+    {
+        Some(pool) => pool,
+        None => return,
+    };
 
-    if full_request.len() == 0
+    // A worker blocking on its own full queue could wait forever
+    if pool.is_saturated()
     {
-        Logger::printmsg(Logger::RequestErr, String::from("Got zero length request"));
+        Logger::printmsg(Logger::InfoErr, format!("Job queue is full ({}/{}), refusing connection from {}", pool.queue_depth(), pool.queue_capacity(), stream_peer));
+        if let Err(e) = overloaded(&shared.get()).write_to(&mut &stream)
+        {
+            Logger::printmsg(Logger::InfoErr, format!("Writing to stream is failed: {}", e));
+        }
         return;
     }
 
-    let request_method = &full_request[0];
+    let result = pool.execute(move ||
+    {
+        let _slot = slot;
+        handle_connection(&stream, shared, &rate_limiter, &bandwidth, stream_peer);
+    });
+
+    if let Err(e) = result
+    {
+        Logger::printmsg(Logger::InfoErr, format!("Dropping connection from {}: {}", stream_peer, e));
+    }
+}
+
+/// Client a new connection counts against, `None` for trusted proxies which
+/// carry many clients.
+fn capped_client(stream_peer: IpAddr, config: &Config) -> Option<IpAddr>
+{
+    match proxy::is_trusted(&config.proxy.trusted, stream_peer)
+    {
+        true => None,
+        false => Some(stream_peer),
+    }
+}
 
-    let mut request_referer: Option<String> = None;
-    for value in full_request.iter()
+/// Check the access list, the bans and the load of the pool before taking a
+/// new connection from `stream_peer`.
+///
+/// Returns the response to send before closing the connection when it is
+/// refused, which happens on the accepting thread, no worker is involved.
+fn admit(stream_peer: IpAddr, pool: &ThreadPool, config: &Config, rate_limiter: &Policies) -> Result<(), Response>
+{
+    // The clients of a proxy are only known once their requests are read
+    if !proxy::is_trusted(&config.proxy.trusted, stream_peer)
     {
-        if value.contains("Referer")
+        if !config.access.allows_connection(stream_peer)
         {
-            request_referer = Some(value.to_string());
-            break;
+            Logger::printmsg(Logger::Info, format!("Connection from {} is denied by the access list", stream_peer));
+            return Err(forbidden(config));
+        }
+
+        if let Some(left) = rate_limiter.bans().banned_for(stream_peer)
+        {
+            Logger::printmsg(Logger::Info, format!("Connection from {} is banned", stream_peer));
+            return Err(banned(left, config));
         }
     }
 
-    let (status_line, filename) = if request_method == "GET / HTTP/1.1"
+    // Shed load right away instead of piling connections up in memory
+    if pool.is_saturated()
     {
-        ("HTTP/1.1 200 OK", path + DEFAULT_RESPONSE_PAGE_NAME)
+        Logger::printmsg(Logger::InfoErr, format!("Job queue is full ({}/{}), refusing connection from {}", pool.queue_depth(), pool.queue_capacity(), stream_peer));
+        return Err(overloaded(config));
     }
-    else
+
+    Ok(())
+}
+
+fn forbidden(config: &Config) -> Response
+{
+    with_headers(Response::fallback(StatusCode::Forbidden), config).header("Connection", "close")
+}
+
+fn banned(left: Duration, config: &Config) -> Response
+{
+    forbidden(config).header("Retry-After", &(left.as_secs() + 1).to_string())
+}
+
+/// Response for a connection over one of the caps.
+fn refused(refusal: Refusal, config: &Config) -> Response
+{
+    match refusal
+    {
+        Refusal::TooManyFromClient => with_headers(Response::fallback(StatusCode::TooManyRequests), config).header("Connection", "close"),
+        Refusal::TooManyConnections => overloaded(config),
+    }
+}
+
+fn too_many_requests(decision: &Decision, config: &Config) -> Response
+{
+    with_rate_limit(with_headers(Response::fallback(StatusCode::TooManyRequests), config), Some(decision))
+        .header("Retry-After", &decision.retry_after_secs().to_string())
+        .header("Connection", "close")
+}
+
+/// Tell the client about its quota, when the request is limited.
+fn with_rate_limit(response: Response, decision: Option<&Decision>) -> Response
+{
+    match decision
+    {
+        Some(decision) => response
+            .header("RateLimit-Limit", &decision.limit.to_string())
+            .header("RateLimit-Remaining", &decision.remaining.to_string())
+            .header("RateLimit-Reset", &decision.reset_secs().to_string()),
+        None => response,
+    }
+}
+
+fn overloaded(config: &Config) -> Response
+{
+    with_headers(Response::fallback(StatusCode::ServiceUnavailable), config)
+        .header("Retry-After", &OVERLOAD_RETRY_AFTER.as_secs().to_string())
+        .header("Connection", "close")
+}
+
+/// Add the headers from the configuration to `response`.
+fn with_headers(mut response: Response, config: &Config) -> Response
+{
+    for (name, value) in config.headers.iter()
+    {
+        response = response.header(name, value);
+    }
+    response
+}
+
+/// Serve the requests of a connection until it is closed.
+///
+/// Every request is counted against the rate limit policy matching it once
+/// its head has been parsed.
+fn handle_connection(stream: &TcpStream, shared: Arc<SharedConfig>, rate_limiter: &Policies, bandwidth: &Arc<Bandwidth>, mut client: IpAddr)
+{
+    let mut peer = match stream.peer_addr()
     {
-        get_filename(request_method, request_referer, &path)
+        Ok(peer) => peer.to_string(),
+        Err(_) => String::from("unknown peer"),
     };
 
-    match get_filetype(&filename)
+    // Pipelined requests stay in the reader's buffer between iterations,
+    // so they are answered in the order they were sent
+    let mut reader = TimedReader::new(stream, &shared.get().timeouts);
+
+    if shared.get().proxy.protocol
     {
-        Ok(req_type) =>
+        match proxy::read_header(&mut reader)
         {
-            if req_type.contains("text") || req_type.contains("javascript")
+            Ok(proxy::Header::Proxied(source)) =>
             {
-                match text_to_stream(filename, status_line, &stream)
+                client = source.ip().to_canonical();
+                peer = source.to_string();
+            },
+            Ok(proxy::Header::Local) => (),
+            Err(e) =>
+            {
+                Logger::printmsg(Logger::RequestErr, format!("Closing the connection from {}: {}", peer, e));
+                return;
+            }
+        }
+    }
+    let mut served: u32 = 0;
+    let mut guard = PanicGuard { stream, pending: false };
+
+    loop
+    {
+        // Taken per request, so a reload applies to the next request on this connection
+        let config = shared.get();
+
+        reader.next_request(&config.timeouts);
+        let request = match Request::read_from(&mut reader, &config.request_limits)
+        {
+            Ok(request) =>
+            {
+                guard.pending = true;
+                request
+            },
+            Err(ParseError::ConnectionClosed) =>
+            {
+                if served == 0
                 {
-                    Ok(_) => (),
-                    Err(e) =>
-                    {
-                        Logger::printmsg(Logger::InfoErr, e);
-                        return;
-                    }
+                    Logger::printmsg(Logger::RequestErr, String::from("Got zero length request"));
                 }
-            }
-            else if req_type.contains("image")
+                return;
+            },
+            Err(ParseError::Idle) =>
+            {
+                Logger::printmsg(Logger::Request, format!("Connection to {} is idle, closing", peer));
+                return;
+            },
+            Err(e) =>
             {
-                match image_to_stream(filename, req_type, status_line, &stream)
+                Logger::printmsg(Logger::RequestErr, format!("Malformed request from {}: {}", peer, e));
+                if e.is_malformed()
                 {
-                    Ok(_) => (),
-                    Err(e) =>
-                    {
-                        Logger::printmsg(Logger::InfoErr, e);
-                        return;
-                    }
+                    rate_limiter.bans().offense(client, Offense::MalformedRequest);
                 }
+
+                if let Some(status) = e.status()
+                {
+                    let response = with_headers(Response::error(status, &config.file_path), &config).header("Connection", "close");
+                    send(stream, &response, &config, &peer, None);
+                }
+                return;
             }
-        },
+        };
 
-        Err(e) =>
+        let request_client = proxy::forwarded_client(&config.proxy.trusted, client, &request.headers);
+        let request_peer = if request_client == client { peer.clone() } else { request_client.to_string() };
+        let path = served_path(&request, &config.file_path, &config.index, config.symlinks);
+
+        if !config.access.allows(request_client, &path)
         {
-            match e
-            {
-                fileutils::FiletypeProcessError::NoExtensionFound => {
-                    Logger::printmsg(Logger::RequestErr, "No request acceptable extension found, trying to send data as text".to_string());
-                    match text_to_stream(filename, status_line, &stream)
-                    {
-                        Ok(_) => (),
-                        Err(e) =>
-                        {
-                            Logger::printmsg(Logger::InfoErr, e);
-                            return;
-                        }
-                    }
-                },
+            Logger::printmsg(Logger::Info, format!("Request to {} from {} is denied by the access list", path, request_peer));
+            guard.send(&forbidden(&config), &config, &peer, None);
+            return;
+        }
 
-                fileutils::FiletypeProcessError::UnsupportedFileType => {
-                    Logger::printmsg(Logger::RequestErr, "Requested file type is unsupported, trying to send data as text".to_string());
-                    match text_to_stream(filename, status_line, &stream)
-                    {
-                        Ok(_) => (),
-                        Err(e) =>
-                        {
-                            Logger::printmsg(Logger::InfoErr, e);
-                            return;
-                        }
-                    }
-                }
-            }
+        if let Some(left) = rate_limiter.bans().banned_for(request_client)
+        {
+            Logger::printmsg(Logger::Info, format!("Request to {} from {} is banned", request.path(), request_peer));
+            guard.send(&banned(left, &config), &config, &peer, None);
+            return;
+        }
+
+        if escapes_root(request.path())
+        {
+            rate_limiter.bans().offense(request_client, Offense::PathTraversal);
+        }
+
+        let decision = rate_limiter.check(request.method, &path, request_client);
+        if let Some(decision) = decision.filter(|decision| !decision.allowed)
+        {
+            Logger::printmsg(Logger::Info, format!("Request to {} has been blocked from {}", path, request_peer));
+            rate_limiter.bans().offense(request_client, Offense::RateLimited);
+            guard.send(&too_many_requests(&decision, &config), &config, &peer, None);
+            return;
+        }
+
+        served += 1;
+        let keep_alive = request.keep_alive() && served < config.timeouts.keep_alive_requests && !SHUTTING_DOWN.load(Ordering::SeqCst);
+
+        let response = with_rate_limit(respond(&request, &path, &config, keep_alive, served), decision.as_ref());
+
+        let pacer = bandwidth.pacer(request_client, &path);
+        if !guard.send(&response, &config, &peer, pacer)
+        {
+            return;
+        }
+
+        Logger::printmsg(Logger::Request, format!("{} {} from {}, responsed with \"{}\"", request.method, request.target, request_peer, response.status));
+
+        if !keep_alive
+        {
+            return;
         }
     }
+}
 
-    match status_line.find("200 OK")
+/// Write `response` within the write timeout.
+///
+/// Returns whether all of it was sent.
+fn send(stream: &TcpStream, response: &Response, config: &Config, peer: &str, pacer: Option<Pacer>) -> bool
+{
+    match response.write_to(&mut TimedWriter::new(stream, config.timeouts.write).paced(pacer))
     {
-        Some(_) => Logger::printmsg(Logger::Request, format!("Connection established to {}, responsed with \"200 OK\"", &stream.peer_addr().unwrap())),
-        None => Logger::printmsg(Logger::Request, format!("Connection established to {}, responsed with \"404 NOT FOUND\"", &stream.peer_addr().unwrap())),
+        Ok(()) => true,
+        Err(e) if timeout::is_timeout(&e) =>
+        {
+            Logger::printmsg(Logger::RequestErr, format!("Sending the response to {} timed out", peer));
+            false
+        },
+        Err(e) =>
+        {
+            Logger::printmsg(Logger::InfoErr, format!("Writing to stream is failed: {}", e));
+            false
+        }
     }
 }
 
-fn text_to_stream(filename: String, status_line: &str, mut stream: &TcpStream) -> Result<(), String>
+/// Answers with 500 when the connection handler panics between reading a
+/// request and starting its response, instead of leaving the client hanging.
+struct PanicGuard<'a>
 {
-    let content = match fs::read_to_string(&filename)
+    stream: &'a TcpStream,
+    /// A request was read and nothing was written for it yet.
+    pending: bool,
+}
+
+impl PanicGuard<'_>
+{
+    /// Send the answer to the pending request.
+    fn send(&mut self, response: &Response, config: &Config, peer: &str, pacer: Option<Pacer>) -> bool
     {
-        Ok(string) => string,
-        Err(error) => match error.kind()
+        self.pending = false;
+        send(self.stream, response, config, peer, pacer)
+    }
+}
+
+impl Drop for PanicGuard<'_>
+{
+    fn drop(&mut self)
+    {
+        if thread::panicking() && self.pending
         {
-            ErrorKind::NotFound => {
-                return Err(String::from(format!("The static file \"{}\" could not be found", filename)))
-            }
-            _ => return Err(String::from(format!("Cannot open the file \"{}\".", filename))),
+            let response = Response::fallback(StatusCode::InternalServerError).header("Connection", "close");
+            let _ = response.write_to(&mut self.stream);
         }
+    }
+}
+
+/// Build the complete answer to `request`, connection headers included.
+///
+/// `path` is the one from `served_path`. `served` is the number of requests
+/// answered on the connection so far, this one included.
+fn respond(request: &Request, path: &str, config: &Config, keep_alive: bool, served: u32) -> Response
+{
+    let response = with_headers(handle_request(request, path, config), config);
+    if keep_alive
+    {
+        response
+            .header("Connection", "keep-alive")
+            .header("Keep-Alive", &format!("timeout={}, max={}", config.timeouts.keep_alive.as_secs(), config.timeouts.keep_alive_requests - served))
+    }
+    else
+    {
+        response.header("Connection", "close")
+    }
+}
+
+fn handle_request(request: &Request, path: &str, config: &Config) -> Response
+{
+    let response = match request.method
+    {
+        Method::Get | Method::Head => serve_file(request, path, config),
+        _ => Response::error(StatusCode::MethodNotAllowed, &config.file_path).header("Allow", "GET, HEAD"),
     };
 
-    let length = content.len();
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{content}");
-    match stream.write_all(response.as_bytes())
+    match request.method
     {
-        Ok(_) => return Ok(()),
-        Err(e) => return Err(format!("Writing to stream is failed: {}", e)),
+        Method::Head => response.head_only(),
+        _ => response,
     }
 }
 
-fn image_to_stream(filename: String, req_type: String, status_line: &str, mut stream: &TcpStream) -> Result<(), String>
+fn serve_file(request: &Request, path: &str, config: &Config) -> Response
 {
-    let content = match fs::read(&filename)
+    match get_filename(path, &config.file_path, &config.index, config.symlinks)
     {
-        Ok(string) => string,
-        Err(error) => match error.kind()
+        Ok(filename) => match Response::from_file(StatusCode::Ok, &filename)
         {
-            ErrorKind::NotFound => {
-                return Err(String::from(format!("The static file \"{}\" could not be found", filename)))
+            Ok(response) => response,
+            Err(e) =>
+            {
+                Logger::printmsg(Logger::InfoErr, e);
+                Response::error(StatusCode::InternalServerError, &config.file_path)
             }
-            _ => return Err(String::from(format!("Cannot open the file \"{}\".", filename))),
-        }
-    };
+        },
+        Err(ResolveError::NotFound) => Response::error(StatusCode::NotFound, &config.file_path),
+        Err(ResolveError::Forbidden) =>
+        {
+            Logger::printmsg(Logger::RequestErr, format!("Refused to serve \"{}\" from outside of the root", request.target));
+            Response::error(StatusCode::Forbidden, &config.file_path)
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn config(options: &[&str]) -> Config
+    {
+        let args: Vec<String> = ["webserver", "."].iter().chain(options).map(|arg| arg.to_string()).collect();
+        Config::build(&args).unwrap()
+    }
+
+    fn head(response: &Response) -> String
+    {
+        String::from_utf8(response.head_bytes()).unwrap()
+    }
+
+    #[test]
+    fn limited_responses_carry_the_quota()
+    {
+        let decision = Decision::allow(10, 7, Duration::from_millis(2500));
+        let head = head(&with_rate_limit(Response::new(StatusCode::Ok), Some(&decision)));
+        assert!(head.contains("\r\nRateLimit-Limit: 10\r\n"));
+        assert!(head.contains("\r\nRateLimit-Remaining: 7\r\n"));
+        // Rounded up, a client waiting that long is never early
+        assert!(head.contains("\r\nRateLimit-Reset: 3\r\n"));
+
+        // Requests under an unlimited rule say nothing about a quota
+        assert!(!self::head(&with_rate_limit(Response::new(StatusCode::Ok), None)).contains("RateLimit-"));
+    }
 
-    let length = content.len();
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\nContent-Type: {req_type}\r\n\r\n");
-    match stream.write(response.as_bytes())
+    #[test]
+    fn denied_requests_are_told_when_to_retry()
     {
-        Ok(_) => (),
-        Err(e) => return Err(format!("Writing to stream is failed: {}", e)),
+        let decision = Decision::deny(10, Duration::from_secs(30), Duration::from_millis(1500));
+        let head = head(&too_many_requests(&decision, &config(&[])));
+        assert!(head.starts_with("HTTP/1.1 429 "));
+        assert!(head.contains("\r\nRetry-After: 2\r\n"));
+        assert!(head.contains("\r\nRateLimit-Remaining: 0\r\n"));
+        assert!(head.contains("\r\nRateLimit-Reset: 30\r\n"));
+        assert!(head.contains("\r\nConnection: close\r\n"));
     }
 
-    match stream.write(&content)
+    #[test]
+    fn connections_get_the_quota_headers()
     {
-        Ok(_) => return Ok(()),
-        Err(e) => return Err(format!("Writing to stream is failed: {}", e)),
+        let config = config(&["--max-requests", "2", "--window", "60"]);
+        let rate_limiter = Policies::new(&config.limiter, &config.bans);
+        let bandwidth = Arc::new(Bandwidth::new(&config.bandwidth, config.limiter.ipv6_prefix));
+        let shared = Arc::new(SharedConfig::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        let serving = thread::spawn(move || handle_connection(&stream, shared, &rate_limiter, &bandwidth, peer.ip()));
+
+        client.write_all("HEAD / HTTP/1.1\r\n\r\n".repeat(3).as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        serving.join().unwrap();
+
+        // The 429 comes with a body, every response starts with its status line
+        let heads: Vec<&str> = response.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(heads.len(), 3);
+        assert!(heads[0].contains("\r\nRateLimit-Remaining: 1\r\n"));
+        assert!(heads[1].contains("\r\nRateLimit-Remaining: 0\r\n"));
+        assert!(heads[2].starts_with("429 "));
+        assert!(heads[2].contains("\r\nRetry-After: "));
+        assert!(heads[2].contains("\r\nRateLimit-Limit: 2\r\n"));
     }
 }
@@ -0,0 +1,418 @@
+//! Finding the actual client behind trusted proxies.
+//!
+//! A proxy either adds `Forwarded` / `X-Forwarded-For` headers to the requests
+//! it passes on, or starts the connection with a PROXY protocol header (v1 text
+//! or v2 binary). Neither is believed unless it comes from a trusted address.
+
+use std::
+{
+    fmt,
+    io::{self, BufRead, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::cidr::Cidr;
+use crate::request::Headers;
+
+
+/// Longest v1 header, CRLF included.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+/// Address block and TLVs of a v2 header, more is refused.
+const V2_MAX_LENGTH: usize = 1024;
+
+/// What a PROXY protocol header says about the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Header
+{
+    /// Relayed from this client.
+    Proxied(SocketAddr),
+    /// Opened by the proxy itself (health checks) or for an unknown
+    /// protocol, the peer address stays.
+    Local,
+}
+
+#[derive(Debug)]
+pub enum ProxyError
+{
+    Io(io::Error),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for ProxyError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            ProxyError::Io(e) => write!(f, "cannot read the PROXY header: {}", e),
+            ProxyError::Malformed(reason) => write!(f, "malformed PROXY header: {}", reason),
+        }
+    }
+}
+
+pub fn is_trusted(trusted: &[Cidr], address: IpAddr) -> bool
+{
+    trusted.iter().any(|cidr| cidr.contains(address))
+}
+
+/// Client a request comes from, following its forwarding headers back from
+/// `peer` for as long as the hops were added by trusted proxies.
+///
+/// `Forwarded` is used when present, `X-Forwarded-For` otherwise.
+pub fn forwarded_client(trusted: &[Cidr], peer: IpAddr, headers: &Headers) -> IpAddr
+{
+    if !is_trusted(trusted, peer)
+    {
+        return peer;
+    }
+
+    let hops: Vec<Option<IpAddr>> = match (headers.get("Forwarded"), headers.get("X-Forwarded-For"))
+    {
+        (Some(forwarded), _) => forwarded.split(',').map(forwarded_for).collect(),
+        (None, Some(forwarded_for)) => forwarded_for.split(',').map(parse_node).collect(),
+        (None, None) => return peer,
+    };
+
+    let mut client = peer;
+    for hop in hops.iter().rev()
+    {
+        if !is_trusted(trusted, client)
+        {
+            break;
+        }
+
+        match hop
+        {
+            Some(address) => client = *address,
+            // "unknown" or an obfuscated name, nothing further to go on
+            None => break,
+        }
+    }
+    client
+}
+
+/// The `for=` parameter of one `Forwarded` element.
+fn forwarded_for(element: &str) -> Option<IpAddr>
+{
+    element.split(';').find_map(|pair|
+    {
+        let (name, value) = pair.split_once('=')?;
+        match name.trim().eq_ignore_ascii_case("for")
+        {
+            true => parse_node(value.trim().trim_matches('"')),
+            false => None,
+        }
+    })
+}
+
+/// An address with an optional port: `192.0.2.1`, `192.0.2.1:80`, `2001:db8::1`
+/// or `[2001:db8::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr>
+{
+    let node = node.trim();
+    if let Ok(address) = node.parse::<IpAddr>()
+    {
+        return Some(address.to_canonical());
+    }
+
+    if let Some(bracketed) = node.strip_prefix('[')
+    {
+        let (address, _) = bracketed.split_once(']')?;
+        return address.parse::<Ipv6Addr>().ok().map(|v6| IpAddr::V6(v6).to_canonical());
+    }
+
+    node.parse::<SocketAddr>().ok().map(|address| address.ip().to_canonical())
+}
+
+
+/// Read a PROXY header from the start of a connection.
+pub fn read_header<R: BufRead>(reader: &mut R) -> Result<Header, ProxyError>
+{
+    let mut received = Vec::new();
+    let mut byte = [0; 1];
+    loop
+    {
+        if let Some((header, _)) = parse_header(&received)?
+        {
+            return Ok(header);
+        }
+
+        match reader.read(&mut byte)
+        {
+            Ok(0) => return Err(ProxyError::Io(ErrorKind::UnexpectedEof.into())),
+            Ok(_) => received.push(byte[0]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ProxyError::Io(e)),
+        }
+    }
+}
+
+/// Parse a PROXY header at the start of `received`.
+///
+/// Returns the header and its length, or `None` when more bytes are needed.
+pub fn parse_header(received: &[u8]) -> Result<Option<(Header, usize)>, ProxyError>
+{
+    if received.is_empty()
+    {
+        return Ok(None);
+    }
+
+    if received[0] == b'P'
+    {
+        parse_v1(received)
+    }
+    else
+    {
+        parse_v2(received)
+    }
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+fn parse_v1(received: &[u8]) -> Result<Option<(Header, usize)>, ProxyError>
+{
+    let prefix = b"PROXY ";
+    if !received.starts_with(&prefix[..received.len().min(prefix.len())])
+    {
+        return Err(ProxyError::Malformed("missing signature"));
+    }
+
+    let end = match received.windows(2).position(|pair| pair == b"\r\n")
+    {
+        Some(end) if end + 2 <= V1_MAX_LENGTH => end,
+        Some(_) => return Err(ProxyError::Malformed("v1 header too long")),
+        None if received.len() >= V1_MAX_LENGTH => return Err(ProxyError::Malformed("v1 header too long")),
+        None => return Ok(None),
+    };
+
+    let line = match std::str::from_utf8(&received[prefix.len()..end])
+    {
+        Ok(line) => line,
+        Err(_) => return Err(ProxyError::Malformed("v1 header is not text")),
+    };
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    let source = match parts.as_slice()
+    {
+        ["UNKNOWN", ..] => return Ok(Some((Header::Local, end + 2))),
+        ["TCP4", source, _, port, _] => source.parse::<Ipv4Addr>().ok().map(IpAddr::V4).zip(port.parse::<u16>().ok()),
+        ["TCP6", source, _, port, _] => source.parse::<Ipv6Addr>().ok().map(IpAddr::V6).zip(port.parse::<u16>().ok()),
+        _ => return Err(ProxyError::Malformed("unexpected v1 fields")),
+    };
+
+    match source
+    {
+        Some((address, port)) => Ok(Some((Header::Proxied(SocketAddr::new(address, port)), end + 2))),
+        None => Err(ProxyError::Malformed("invalid v1 source address")),
+    }
+}
+
+/// 12 byte signature, version and command, address family, length of the
+/// rest, then the addresses and optional TLVs.
+fn parse_v2(received: &[u8]) -> Result<Option<(Header, usize)>, ProxyError>
+{
+    let signature = &received[..received.len().min(V2_SIGNATURE.len())];
+    if signature != &V2_SIGNATURE[..signature.len()]
+    {
+        return Err(ProxyError::Malformed("missing signature"));
+    }
+
+    if received.len() < 16
+    {
+        return Ok(None);
+    }
+
+    if received[12] >> 4 != 2
+    {
+        return Err(ProxyError::Malformed("unsupported version"));
+    }
+
+    let length = usize::from(u16::from_be_bytes([received[14], received[15]]));
+    if length > V2_MAX_LENGTH
+    {
+        return Err(ProxyError::Malformed("v2 header too long"));
+    }
+
+    let total = 16 + length;
+    if received.len() < total
+    {
+        return Ok(None);
+    }
+
+    let addresses = &received[16..total];
+    let header = match (received[12] & 0x0F, received[13] >> 4)
+    {
+        // LOCAL
+        (0x0, _) => Header::Local,
+        // PROXY over IPv4
+        (0x1, 0x1) if addresses.len() >= 12 =>
+        {
+            let source = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Header::Proxied(SocketAddr::new(IpAddr::V4(source), port))
+        },
+        // PROXY over IPv6
+        (0x1, 0x2) if addresses.len() >= 36 =>
+        {
+            let mut source = [0; 16];
+            source.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Header::Proxied(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(source)), port))
+        },
+        (0x1, 0x1 | 0x2) => return Err(ProxyError::Malformed("v2 address block too short")),
+        // Unix sockets or unspecified, nothing to use
+        (0x1, _) => Header::Local,
+        _ => return Err(ProxyError::Malformed("unsupported command")),
+    };
+
+    Ok(Some((header, total)))
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn trusted() -> Vec<Cidr>
+    {
+        vec![Cidr::parse("10.0.0.0/8").unwrap(), Cidr::parse("2001:db8::/32").unwrap()]
+    }
+
+    fn address(value: &str) -> IpAddr
+    {
+        value.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers
+    {
+        let mut headers = Headers::new();
+        for (name, value) in pairs
+        {
+            headers.insert(name, value);
+        }
+        headers
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8>
+    {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family << 4 | 0x1]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client()
+    {
+        let headers = headers(&[("X-Forwarded-For", "192.0.2.1")]);
+        assert_eq!(forwarded_client(&trusted(), address("198.51.100.1"), &headers), address("198.51.100.1"));
+    }
+
+    #[test]
+    fn hops_are_followed_while_trusted()
+    {
+        let headers = headers(&[("X-Forwarded-For", "192.0.2.1, 198.51.100.7, 10.0.0.2")]);
+        assert_eq!(forwarded_client(&trusted(), address("10.0.0.1"), &headers), address("198.51.100.7"));
+
+        let headers = self::headers(&[("X-Forwarded-For", "192.0.2.1, 10.0.0.3"), ("X-Forwarded-For", "10.0.0.2")]);
+        assert_eq!(forwarded_client(&trusted(), address("10.0.0.1"), &headers), address("192.0.2.1"));
+    }
+
+    #[test]
+    fn forwarded_wins_over_x_forwarded_for()
+    {
+        let headers = headers(&[
+            ("Forwarded", "for=192.0.2.60;proto=http, For=\"[2001:db8::1]:4711\""),
+            ("X-Forwarded-For", "198.51.100.9"),
+        ]);
+        assert_eq!(forwarded_client(&trusted(), address("10.0.0.1"), &headers), address("192.0.2.60"));
+    }
+
+    #[test]
+    fn unknown_hops_stop_the_walk()
+    {
+        let headers = headers(&[("Forwarded", "for=192.0.2.60, for=unknown")]);
+        assert_eq!(forwarded_client(&trusted(), address("10.0.0.1"), &headers), address("10.0.0.1"));
+
+        let headers = self::headers(&[("X-Forwarded-For", "192.0.2.1, _hidden")]);
+        assert_eq!(forwarded_client(&trusted(), address("10.0.0.1"), &headers), address("10.0.0.1"));
+    }
+
+    #[test]
+    fn nodes_with_ports_and_mapped_addresses_are_parsed()
+    {
+        assert_eq!(parse_node("192.0.2.1:8080"), Some(address("192.0.2.1")));
+        assert_eq!(parse_node("[2001:db8::1]:80"), Some(address("2001:db8::1")));
+        assert_eq!(parse_node(" ::ffff:192.0.2.1 "), Some(address("192.0.2.1")));
+        assert_eq!(parse_node("host.example"), None);
+    }
+
+    #[test]
+    fn v1_headers_are_parsed()
+    {
+        let received = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET";
+        assert_eq!(parse_header(received).unwrap(), Some((Header::Proxied("192.0.2.1:56324".parse().unwrap()), 45)));
+
+        let received = b"PROXY TCP6 2001:db8::1 2001:db8::2 1000 443\r\n";
+        assert_eq!(parse_header(received).unwrap(), Some((Header::Proxied("[2001:db8::1]:1000".parse().unwrap()), received.len())));
+
+        assert_eq!(parse_header(b"PROXY UNKNOWN\r\n").unwrap(), Some((Header::Local, 15)));
+        assert_eq!(parse_header(b"PROXY TCP4 192.0.2.1").unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_v1_headers_are_refused()
+    {
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 2001:db8::1 198.51.100.1 1 2\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.1 198.51.100.1 70000 443\r\n").is_err());
+        assert!(parse_header(&[b'P'; V1_MAX_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn v2_headers_are_parsed()
+    {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend(56324u16.to_be_bytes());
+        addresses.extend(443u16.to_be_bytes());
+        let header = v2(0x1, 0x1, &addresses);
+        assert_eq!(parse_header(&header).unwrap(), Some((Header::Proxied("192.0.2.1:56324".parse().unwrap()), 28)));
+        assert_eq!(parse_header(&header[..20]).unwrap(), None);
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend([0; 16]);
+        addresses.extend(1000u16.to_be_bytes());
+        addresses.extend(443u16.to_be_bytes());
+        assert_eq!(parse_header(&v2(0x1, 0x2, &addresses)).unwrap(), Some((Header::Proxied("[2001:db8::1]:1000".parse().unwrap()), 52)));
+
+        assert_eq!(parse_header(&v2(0x0, 0x0, &[])).unwrap(), Some((Header::Local, 16)));
+    }
+
+    #[test]
+    fn malformed_v2_headers_are_refused()
+    {
+        assert!(parse_header(&v2(0x1, 0x1, &[192, 0, 2, 1])).is_err());
+        assert!(parse_header(&v2(0x2, 0x1, &[0; 12])).is_err());
+
+        let mut header = v2(0x1, 0x1, &[0; 12]);
+        header[12] = 0x11;
+        assert!(parse_header(&header).is_err());
+
+        let mut header = v2(0x1, 0x1, &[]);
+        header[14..16].copy_from_slice(&(V2_MAX_LENGTH as u16 + 1).to_be_bytes());
+        assert!(parse_header(&header).is_err());
+    }
+
+    #[test]
+    fn headers_are_read_byte_by_byte()
+    {
+        let mut reader = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+        assert_eq!(read_header(&mut reader).unwrap(), Header::Proxied("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+
+        assert!(read_header(&mut &b"PROXY TCP4"[..]).is_err());
+    }
+}
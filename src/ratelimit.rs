@@ -0,0 +1,815 @@
+//! Rate limiting algorithms.
+//!
+//! Every algorithm lets a client make `limit` requests per `window` and keeps
+//! some state per client. None of them reads the time itself, the caller
+//! passes it in, so they behave the same under a fake clock.
+
+use std::
+{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::limiter::Decision;
+
+
+/// Source of the current time, replaced in tests.
+pub trait Clock: Send + Sync
+{
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock
+{
+    fn now(&self) -> Instant
+    {
+        Instant::now()
+    }
+}
+
+
+/// How many clients a store keeps, and for how long after their last request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capacity
+{
+    /// Past this, the least recently seen client is forgotten.
+    pub max_clients: usize,
+    pub idle: Duration,
+}
+
+/// Algorithm a limiter uses, as named in the configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm
+{
+    FixedWindow,
+    TokenBucket,
+    SlidingLog,
+    SlidingWindow,
+    Gcra,
+}
+
+impl Algorithm
+{
+    pub const NAMES: &'static str = "fixed_window, token_bucket, sliding_log, sliding_window or gcra";
+
+    pub fn parse(name: &str) -> Option<Algorithm>
+    {
+        match name
+        {
+            "fixed_window" => Some(Algorithm::FixedWindow),
+            "token_bucket" => Some(Algorithm::TokenBucket),
+            "sliding_log" => Some(Algorithm::SlidingLog),
+            "sliding_window" => Some(Algorithm::SlidingWindow),
+            "gcra" => Some(Algorithm::Gcra),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Algorithm::FixedWindow => "fixed_window",
+            Algorithm::TokenBucket => "token_bucket",
+            Algorithm::SlidingLog => "sliding_log",
+            Algorithm::SlidingWindow => "sliding_window",
+            Algorithm::Gcra => "gcra",
+        }
+    }
+
+    /// Empty store of client states for this algorithm.
+    pub fn build(&self, limit: u32, window: Duration, capacity: Capacity) -> Box<dyn ClientStore>
+    {
+        // Until then, forgetting a client would reset its quota early
+        let min_idle = match self
+        {
+            Algorithm::SlidingWindow => window.saturating_mul(2),
+            _ => window,
+        };
+
+        match self
+        {
+            Algorithm::FixedWindow => Box::new(Clients::new(FixedWindow { limit, window }, capacity, min_idle)),
+            Algorithm::TokenBucket => Box::new(Clients::new(TokenBucket { limit, window }, capacity, min_idle)),
+            Algorithm::SlidingLog => Box::new(Clients::new(SlidingLog { limit, window }, capacity, min_idle)),
+            Algorithm::SlidingWindow => Box::new(Clients::new(SlidingWindowCounter { limit, window }, capacity, min_idle)),
+            Algorithm::Gcra => Box::new(Clients::new(Gcra { limit, window }, capacity, min_idle)),
+        }
+    }
+}
+
+
+/// A way of deciding whether a client may make one more request.
+pub trait RateLimitAlgorithm: Send + Sync
+{
+    /// What is remembered about a client.
+    type State: Send;
+
+    /// State of a client making its first request at `now`.
+    fn new_state(&self, now: Instant) -> Self::State;
+
+    /// Count a request made at `now` if it is allowed.
+    fn check(&self, state: &mut Self::State, now: Instant) -> Decision;
+}
+
+/// Per-client states of one algorithm, behind a lock.
+///
+/// A store never holds more than `max_clients` clients. Clients idle for
+/// longer than `idle` are dropped on every check, a new client past the
+/// capacity replaces the least recently seen one.
+pub trait ClientStore: Send + Sync
+{
+    fn check(&self, client: IpAddr, now: Instant) -> Decision;
+
+    /// Forget idle clients, only once there are at least `min_size` of them.
+    /// Returns how many were removed.
+    fn clean(&self, now: Instant, min_size: usize) -> usize;
+
+    /// Apply a new capacity, dropping the least recently seen clients that
+    /// no longer fit.
+    fn set_capacity(&self, capacity: Capacity);
+
+    /// Clients tracked right now.
+    fn tracked(&self) -> usize;
+}
+
+struct Entry<S>
+{
+    seen: Instant,
+    /// Position in `Table::recency`.
+    order: u64,
+    state: S,
+}
+
+/// Per-client states kept for `idle` after the client was last seen, and
+/// never more than `max_clients` of them: past it, the least recently seen
+/// client is forgotten.
+pub struct Table<S>
+{
+    max_clients: usize,
+    idle: Duration,
+    entries: HashMap<IpAddr, Entry<S>>,
+    /// Clients from the least to the most recently seen.
+    recency: BTreeMap<u64, IpAddr>,
+    next_order: u64,
+}
+
+impl<S> Table<S>
+{
+    pub fn new(capacity: Capacity) -> Table<S>
+    {
+        Table
+        {
+            max_clients: capacity.max_clients.max(1),
+            idle: capacity.idle,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_order: 0,
+        }
+    }
+
+    /// State of `client`, seen at `now`, created with `new_state` when it is
+    /// not tracked. Idle clients are dropped first.
+    pub fn touch(&mut self, client: IpAddr, now: Instant, new_state: impl FnOnce() -> S) -> &mut S
+    {
+        self.expire(now);
+
+        self.next_order += 1;
+        let order = self.next_order;
+        let previous = match self.entries.get_mut(&client)
+        {
+            Some(entry) =>
+            {
+                entry.seen = now;
+                Some(std::mem::replace(&mut entry.order, order))
+            },
+            None =>
+            {
+                while self.entries.len() >= self.max_clients
+                {
+                    self.evict_oldest();
+                }
+                self.entries.insert(client, Entry { seen: now, order, state: new_state() });
+                None
+            }
+        };
+
+        if let Some(previous) = previous
+        {
+            self.recency.remove(&previous);
+        }
+        self.recency.insert(order, client);
+
+        &mut self.entries.get_mut(&client).expect("client was just inserted").state
+    }
+
+    pub fn remove(&mut self, client: IpAddr) -> Option<S>
+    {
+        let entry = self.entries.remove(&client)?;
+        self.recency.remove(&entry.order);
+        Some(entry.state)
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    /// Drop the clients not seen for `idle`, they are the least recent ones.
+    pub fn expire(&mut self, now: Instant) -> usize
+    {
+        let mut removed = 0;
+        while let Some((_, client)) = self.recency.first_key_value()
+        {
+            let seen = self.entries[client].seen;
+            if now.saturating_duration_since(seen) <= self.idle
+            {
+                break;
+            }
+            self.evict_oldest();
+            removed += 1;
+        }
+        removed
+    }
+
+    /// Apply a new capacity, dropping the least recently seen clients that
+    /// no longer fit.
+    pub fn set_capacity(&mut self, capacity: Capacity)
+    {
+        self.max_clients = capacity.max_clients.max(1);
+        self.idle = capacity.idle;
+        while self.entries.len() > self.max_clients
+        {
+            self.evict_oldest();
+        }
+    }
+
+    /// Clients tracked right now.
+    pub fn tracked(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    fn evict_oldest(&mut self)
+    {
+        if let Some((_, client)) = self.recency.pop_first()
+        {
+            self.entries.remove(&client);
+        }
+    }
+}
+
+struct Clients<A: RateLimitAlgorithm>
+{
+    algorithm: A,
+    /// Shortest `idle` that keeps the quotas right.
+    min_idle: Duration,
+    table: Mutex<Table<A::State>>,
+}
+
+impl<A: RateLimitAlgorithm> Clients<A>
+{
+    fn new(algorithm: A, capacity: Capacity, min_idle: Duration) -> Clients<A>
+    {
+        let table = Table::new(Capacity { idle: capacity.idle.max(min_idle), ..capacity });
+        Clients { algorithm, min_idle, table: Mutex::new(table) }
+    }
+}
+
+impl<A: RateLimitAlgorithm> ClientStore for Clients<A>
+{
+    fn check(&self, client: IpAddr, now: Instant) -> Decision
+    {
+        let mut table = self.table.lock().expect("Mutex poisoned");
+        let state = table.touch(client, now, || self.algorithm.new_state(now));
+        self.algorithm.check(state, now)
+    }
+
+    fn clean(&self, now: Instant, min_size: usize) -> usize
+    {
+        let mut table = self.table.lock().expect("Mutex poisoned");
+        if table.tracked() < min_size
+        {
+            return 0;
+        }
+        table.expire(now)
+    }
+
+    fn set_capacity(&self, capacity: Capacity)
+    {
+        let mut table = self.table.lock().expect("Mutex poisoned");
+        table.set_capacity(Capacity { idle: capacity.idle.max(self.min_idle), ..capacity });
+    }
+
+    fn tracked(&self) -> usize
+    {
+        self.table.lock().expect("Mutex poisoned").tracked()
+    }
+}
+
+
+/// `limit` requests per window, the window starts with the first request of
+/// a client and the count resets once it is over.
+pub struct FixedWindow
+{
+    pub limit: u32,
+    pub window: Duration,
+}
+
+impl RateLimitAlgorithm for FixedWindow
+{
+    /// Start of the window and requests counted in it.
+    type State = (Instant, u32);
+
+    fn new_state(&self, now: Instant) -> (Instant, u32)
+    {
+        (now, 0)
+    }
+
+    fn check(&self, state: &mut (Instant, u32), now: Instant) -> Decision
+    {
+        if now.saturating_duration_since(state.0) >= self.window
+        {
+            *state = (now, 0);
+        }
+
+        let reset = self.window.saturating_sub(now.saturating_duration_since(state.0));
+        if state.1 < self.limit
+        {
+            state.1 += 1;
+            Decision::allow(self.limit, self.limit - state.1, reset)
+        }
+        else
+        {
+            Decision::deny(self.limit, reset, reset)
+        }
+    }
+}
+
+
+/// Bucket of `limit` tokens refilled evenly over `window`, every request takes
+/// one. Allows bursts of up to `limit` requests.
+pub struct TokenBucket
+{
+    pub limit: u32,
+    pub window: Duration,
+}
+
+pub struct Bucket
+{
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket
+{
+    /// Tokens added per second.
+    fn rate(&self) -> f64
+    {
+        f64::from(self.limit) / self.window.as_secs_f64()
+    }
+}
+
+impl RateLimitAlgorithm for TokenBucket
+{
+    type State = Bucket;
+
+    fn new_state(&self, now: Instant) -> Bucket
+    {
+        Bucket { tokens: f64::from(self.limit), last: now }
+    }
+
+    fn check(&self, bucket: &mut Bucket, now: Instant) -> Decision
+    {
+        let rate = self.rate();
+        let limit = f64::from(self.limit);
+        bucket.tokens = (bucket.tokens + now.saturating_duration_since(bucket.last).as_secs_f64() * rate).min(limit);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0
+        {
+            bucket.tokens -= 1.0;
+            let reset = Duration::from_secs_f64((limit - bucket.tokens) / rate);
+            Decision::allow(self.limit, bucket.tokens as u32, reset)
+        }
+        else
+        {
+            let reset = Duration::from_secs_f64((limit - bucket.tokens) / rate);
+            let retry_after = Duration::from_secs_f64((1.0 - bucket.tokens) / rate);
+            Decision::deny(self.limit, reset, retry_after)
+        }
+    }
+}
+
+
+/// Remembers the time of every allowed request, at most `limit` of them may
+/// fall in any `window`. Exact, but uses memory in proportion to `limit`.
+pub struct SlidingLog
+{
+    pub limit: u32,
+    pub window: Duration,
+}
+
+impl RateLimitAlgorithm for SlidingLog
+{
+    type State = VecDeque<Instant>;
+
+    fn new_state(&self, _now: Instant) -> VecDeque<Instant>
+    {
+        VecDeque::new()
+    }
+
+    fn check(&self, log: &mut VecDeque<Instant>, now: Instant) -> Decision
+    {
+        while log.front().is_some_and(|first| now.saturating_duration_since(*first) >= self.window)
+        {
+            log.pop_front();
+        }
+
+        let expires = |at: Instant| (at + self.window).saturating_duration_since(now);
+        if log.len() < self.limit as usize
+        {
+            log.push_back(now);
+            Decision::allow(self.limit, self.limit - log.len() as u32, self.window)
+        }
+        else
+        {
+            let reset = log.back().map_or(Duration::ZERO, |last| expires(*last));
+            let retry_after = log.front().map_or(Duration::ZERO, |first| expires(*first));
+            Decision::deny(self.limit, reset, retry_after)
+        }
+    }
+}
+
+
+/// Counts requests in consecutive windows and weighs the previous count by
+/// how much of the previous window still overlaps the last `window`.
+/// Approximates a sliding log with two counters.
+pub struct SlidingWindowCounter
+{
+    pub limit: u32,
+    pub window: Duration,
+}
+
+pub struct Counters
+{
+    /// Start of the current window.
+    start: Instant,
+    previous: u32,
+    current: u32,
+}
+
+impl RateLimitAlgorithm for SlidingWindowCounter
+{
+    type State = Counters;
+
+    fn new_state(&self, now: Instant) -> Counters
+    {
+        Counters { start: now, previous: 0, current: 0 }
+    }
+
+    fn check(&self, counters: &mut Counters, now: Instant) -> Decision
+    {
+        let passed = (now.saturating_duration_since(counters.start).as_nanos() / self.window.as_nanos()) as u32;
+        if passed > 0
+        {
+            counters.previous = if passed == 1 { counters.current } else { 0 };
+            counters.current = 0;
+            counters.start += self.window * passed;
+        }
+
+        let window = self.window.as_secs_f64();
+        let elapsed = now.saturating_duration_since(counters.start).as_secs_f64();
+        let limit = f64::from(self.limit);
+        let previous = f64::from(counters.previous);
+        let current = f64::from(counters.current);
+        let estimate = previous * (1.0 - elapsed / window) + current;
+        let reset = Duration::from_secs_f64(window - elapsed);
+
+        if estimate < limit
+        {
+            counters.current += 1;
+            let remaining = (limit - estimate - 1.0).max(0.0) as u32;
+            Decision::allow(self.limit, remaining, reset)
+        }
+        else
+        {
+            let wait = if current < limit
+            {
+                // The previous window has to weigh less
+                window * (1.0 - (limit - current) / previous) - elapsed
+            }
+            else
+            {
+                // Only the next window has room, once this one weighs less
+                window - elapsed + window * (1.0 - limit / current)
+            };
+            Decision::deny(self.limit, reset, Duration::from_secs_f64(wait.max(0.0)))
+        }
+    }
+}
+
+
+/// Generic cell rate algorithm: requests are expected one every
+/// `window / limit`, a client may be up to `limit` requests ahead of that.
+/// Behaves like a token bucket but only stores a single instant.
+pub struct Gcra
+{
+    pub limit: u32,
+    pub window: Duration,
+}
+
+impl RateLimitAlgorithm for Gcra
+{
+    /// Theoretical arrival time: when the client is back to a full quota.
+    type State = Instant;
+
+    fn new_state(&self, now: Instant) -> Instant
+    {
+        now
+    }
+
+    fn check(&self, tat: &mut Instant, now: Instant) -> Decision
+    {
+        let interval = (self.window / self.limit).max(Duration::from_nanos(1));
+        let next = (*tat).max(now) + interval;
+        let ahead = next.saturating_duration_since(now);
+
+        if ahead <= self.window
+        {
+            *tat = next;
+            let remaining = ((self.window - ahead).as_nanos() / interval.as_nanos()) as u32;
+            Decision::allow(self.limit, remaining, ahead)
+        }
+        else
+        {
+            let reset = tat.saturating_duration_since(now);
+            Decision::deny(self.limit, reset, ahead - self.window)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::limiter::Limiter;
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    /// Clock that only moves when told to.
+    struct ManualClock
+    {
+        now: Mutex<Instant>,
+    }
+
+    impl ManualClock
+    {
+        fn new() -> Arc<ManualClock>
+        {
+            Arc::new(ManualClock { now: Mutex::new(Instant::now()) })
+        }
+
+        fn advance(&self, by: Duration)
+        {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock
+    {
+        fn now(&self) -> Instant
+        {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    const THIRD: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3));
+
+    const ROOMY: Capacity = Capacity { max_clients: 100, idle: Duration::from_secs(3600) };
+
+    fn limiter(algorithm: Algorithm, limit: u32, window_secs: u64) -> (Limiter, Arc<ManualClock>)
+    {
+        let clock = ManualClock::new();
+        let limiter = Limiter::with_clock(algorithm, limit, Duration::from_secs(window_secs), 128, ROOMY, clock.clone());
+        (limiter, clock)
+    }
+
+    /// Make `count` requests at the current time, returning how many passed.
+    fn burst(limiter: &Limiter, count: u32) -> u32
+    {
+        (0..count).filter(|_| limiter.check(CLIENT).allowed).count() as u32
+    }
+
+    #[test]
+    fn fixed_window_resets_after_the_window()
+    {
+        let (limiter, clock) = limiter(Algorithm::FixedWindow, 3, 10);
+        assert_eq!(burst(&limiter, 5), 3);
+
+        clock.advance(Duration::from_secs(4));
+        let denied = limiter.check(CLIENT);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_secs(6));
+
+        clock.advance(Duration::from_secs(6));
+        let allowed = limiter.check(CLIENT);
+        assert!(allowed.allowed);
+        assert_eq!(allowed.remaining, 2);
+        assert_eq!(allowed.reset, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn fixed_window_recovers_under_steady_traffic()
+    {
+        let (limiter, clock) = limiter(Algorithm::FixedWindow, 2, 10);
+        let mut allowed = 0;
+        for _ in 0..30
+        {
+            allowed += burst(&limiter, 1);
+            clock.advance(Duration::from_secs(1));
+        }
+        assert_eq!(allowed, 6);
+    }
+
+    #[test]
+    fn token_bucket_allows_a_burst_then_refills()
+    {
+        let (limiter, clock) = limiter(Algorithm::TokenBucket, 10, 10);
+        assert_eq!(burst(&limiter, 15), 10);
+
+        let denied = limiter.check(CLIENT);
+        assert_eq!(denied.retry_after, Duration::from_secs(1));
+        assert_eq!(denied.reset, Duration::from_secs(10));
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(burst(&limiter, 5), 3);
+
+        clock.advance(Duration::from_secs(60));
+        let full = limiter.check(CLIENT);
+        assert_eq!(full.remaining, 9);
+        assert_eq!(full.reset, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn sliding_log_frees_slots_as_requests_age()
+    {
+        let (limiter, clock) = limiter(Algorithm::SlidingLog, 3, 10);
+        assert_eq!(burst(&limiter, 1), 1);
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(burst(&limiter, 3), 2);
+
+        let denied = limiter.check(CLIENT);
+        assert_eq!(denied.retry_after, Duration::from_secs(6));
+        assert_eq!(denied.reset, Duration::from_secs(10));
+
+        // Only the first request has left the window
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(burst(&limiter, 3), 1);
+    }
+
+    #[test]
+    fn sliding_window_weighs_the_previous_window()
+    {
+        let (limiter, clock) = limiter(Algorithm::SlidingWindow, 10, 10);
+        assert_eq!(burst(&limiter, 10), 10);
+
+        // Half of the previous window still counts: 10 * 0.5 = 5
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(burst(&limiter, 10), 5);
+
+        // The estimate is exactly the limit, it drops below as soon as time
+        // moves on
+        let denied = limiter.check(CLIENT);
+        assert!(!denied.allowed);
+        assert_eq!(denied.reset, Duration::from_secs(5));
+        assert_eq!(denied.retry_after, Duration::ZERO);
+
+        // 10 * 0.4 + 5 = 9
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(burst(&limiter, 3), 1);
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(burst(&limiter, 20), 10);
+    }
+
+    #[test]
+    fn sliding_window_retry_after_waits_for_the_previous_window()
+    {
+        let (limiter, clock) = limiter(Algorithm::SlidingWindow, 4, 10);
+        assert_eq!(burst(&limiter, 4), 4);
+
+        // 4 * 0.8 + 0 = 3.2, one more request fits
+        clock.advance(Duration::from_secs(12));
+        assert_eq!(burst(&limiter, 2), 1);
+
+        // 4 * (1 - e / 10) + 1 < 4 once e > 2.5
+        let denied = limiter.check(CLIENT);
+        assert_eq!(denied.retry_after, Duration::from_millis(500));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(burst(&limiter, 1), 1);
+    }
+
+    #[test]
+    fn gcra_spaces_requests_after_a_burst()
+    {
+        let (limiter, clock) = limiter(Algorithm::Gcra, 10, 10);
+        let first = limiter.check(CLIENT);
+        assert_eq!(first.remaining, 9);
+        assert_eq!(burst(&limiter, 20), 9);
+
+        let denied = limiter.check(CLIENT);
+        assert_eq!(denied.retry_after, Duration::from_secs(1));
+        assert_eq!(denied.reset, Duration::from_secs(10));
+
+        clock.advance(Duration::from_millis(2500));
+        assert_eq!(burst(&limiter, 5), 2);
+
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(burst(&limiter, 20), 10);
+    }
+
+    #[test]
+    fn steady_traffic_under_the_rate_is_never_denied()
+    {
+        for algorithm in [Algorithm::FixedWindow, Algorithm::TokenBucket, Algorithm::SlidingLog, Algorithm::SlidingWindow, Algorithm::Gcra]
+        {
+            let (limiter, clock) = limiter(algorithm, 5, 10);
+            for _ in 0..50
+            {
+                assert!(limiter.check(CLIENT).allowed, "{} denied steady traffic", algorithm.name());
+                clock.advance(Duration::from_secs(3));
+            }
+        }
+    }
+
+    #[test]
+    fn clients_have_separate_quotas()
+    {
+        let (limiter, _clock) = limiter(Algorithm::Gcra, 1, 10);
+        assert!(limiter.check(CLIENT).allowed);
+        assert!(!limiter.check(CLIENT).allowed);
+        assert!(limiter.check(OTHER).allowed);
+    }
+
+    #[test]
+    fn idle_clients_are_cleaned()
+    {
+        let clock = ManualClock::new();
+        let store = Algorithm::TokenBucket.build(1, Duration::from_secs(1), Capacity { max_clients: 10, idle: Duration::from_secs(1) });
+        store.check(CLIENT, clock.now());
+        clock.advance(Duration::from_millis(500));
+        store.check(OTHER, clock.now());
+        clock.advance(Duration::from_millis(700));
+
+        assert_eq!(store.clean(clock.now(), 3), 0);
+        assert_eq!(store.clean(clock.now(), 2), 1);
+        assert!(!store.check(OTHER, clock.now()).allowed);
+        assert!(store.check(CLIENT, clock.now()).allowed);
+    }
+
+    #[test]
+    fn idle_clients_expire_on_check()
+    {
+        let clock = ManualClock::new();
+        let store = Algorithm::FixedWindow.build(1, Duration::from_secs(2), Capacity { max_clients: 10, idle: Duration::ZERO });
+        store.check(CLIENT, clock.now());
+
+        // Not before the window is over, whatever `idle` says
+        clock.advance(Duration::from_secs(1));
+        store.check(OTHER, clock.now());
+        assert_eq!(store.tracked(), 2);
+
+        clock.advance(Duration::from_secs(2));
+        store.check(OTHER, clock.now());
+        assert_eq!(store.tracked(), 1);
+    }
+
+    #[test]
+    fn least_recently_seen_client_is_evicted_at_capacity()
+    {
+        let clock = ManualClock::new();
+        let store = Algorithm::FixedWindow.build(1, Duration::from_secs(10), Capacity { max_clients: 2, idle: Duration::ZERO });
+        assert!(store.check(CLIENT, clock.now()).allowed);
+        assert!(store.check(OTHER, clock.now()).allowed);
+        assert!(!store.check(CLIENT, clock.now()).allowed);
+
+        // OTHER is the least recently seen, it makes room
+        assert!(store.check(THIRD, clock.now()).allowed);
+        assert_eq!(store.tracked(), 2);
+        assert!(!store.check(CLIENT, clock.now()).allowed);
+        assert!(!store.check(THIRD, clock.now()).allowed);
+
+        store.set_capacity(Capacity { max_clients: 1, idle: Duration::ZERO });
+        assert_eq!(store.tracked(), 1);
+        assert!(!store.check(THIRD, clock.now()).allowed);
+        assert!(store.check(CLIENT, clock.now()).allowed);
+    }
+}
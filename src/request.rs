@@ -0,0 +1,755 @@
+use std::
+{
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, Read},
+};
+
+use chunked_transfer::Decoder;
+
+use crate::status::StatusCode;
+
+
+/// Largest request body accepted, bigger ones are answered with 413.
+pub const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+
+/// Caps on the head of a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits
+{
+    /// Longest request line, longer ones are answered with 414.
+    pub max_request_line: usize,
+    /// Most bytes in all header lines together, more are answered with 431.
+    pub max_header_bytes: usize,
+    /// Most header lines, more are answered with 431.
+    pub max_headers: usize,
+}
+
+/// How the end of a request body is found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing
+{
+    /// `Content-Length` bytes, zero without the header.
+    Length(u64),
+    /// `Transfer-Encoding: chunked`, up to the last chunk.
+    Chunked,
+}
+
+/// Where requests are read from.
+///
+/// The parser tells the source when the head is complete, so that it can
+/// switch from the head timeout to the body timeout.
+pub trait Source: BufRead
+{
+    fn head_complete(&mut self) {}
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method
+{
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Trace,
+    Connect,
+    Patch,
+}
+
+impl Method
+{
+    pub fn parse(method: &str) -> Option<Method>
+    {
+        match method
+        {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "OPTIONS" => Some(Method::Options),
+            "TRACE" => Some(Method::Trace),
+            "CONNECT" => Some(Method::Connect),
+            "PATCH" => Some(Method::Patch),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl fmt::Display for Method
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(self.as_str())
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version
+{
+    Http10,
+    Http11,
+}
+
+impl Version
+{
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for Version
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(self.as_str())
+    }
+}
+
+
+/// Header map with case-insensitive names.
+///
+/// Names are stored lowercased, repeated headers are joined with `", "`.
+#[derive(Debug, Clone, Default)]
+pub struct Headers
+{
+    map: HashMap<String, String>,
+}
+
+impl Headers
+{
+    pub fn new() -> Headers
+    {
+        Headers { map: HashMap::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str>
+    {
+        self.map.get(&name.to_ascii_lowercase()).map(|value| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool
+    {
+        self.map.contains_key(&name.to_ascii_lowercase())
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str)
+    {
+        self.map
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|existing|
+            {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.map.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)>
+    {
+        self.map.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Request
+{
+    pub method: Method,
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Option<Vec<u8>>,
+}
+
+impl Request
+{
+    /// Read one request from `reader`.
+    ///
+    /// Blocks until the head and the body (if any) have been received.
+    pub fn read_from<R: Source>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError>
+    {
+        let mut request = Request::read_head(reader, limits)?;
+        request.body = read_body(reader, request.framing()?)?;
+        Ok(request)
+    }
+
+    /// Read the head of one request, leaving the body in `reader`.
+    pub fn read_head<R: Source>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError>
+    {
+        // Empty lines before the request line are ignored (RFC 9112, 2.2)
+        let request_line = loop
+        {
+            match read_line(reader, limits.max_request_line, ParseError::RequestLineTooLong)?
+            {
+                Some(line) if line.is_empty() => continue,
+                Some(line) => break line,
+                None => return Err(ParseError::ConnectionClosed),
+            }
+        };
+
+        let (method, target, version) = parse_request_line(&request_line)?;
+
+        let mut headers = Headers::new();
+        let mut header_bytes = 0;
+        let mut header_count = 0;
+        loop
+        {
+            let budget = limits.max_header_bytes.saturating_sub(header_bytes);
+            let line = match read_line(reader, budget, ParseError::HeadersTooLarge)
+            {
+                Ok(Some(line)) => line,
+                Ok(None) => return Err(ParseError::UnexpectedEof),
+                Err(ParseError::Idle) => return Err(ParseError::Timeout),
+                Err(e) => return Err(e),
+            };
+
+            if line.is_empty()
+            {
+                break;
+            }
+
+            header_bytes += line.len();
+            header_count += 1;
+            if header_count > limits.max_headers
+            {
+                return Err(ParseError::TooManyHeaders);
+            }
+
+            let (name, value) = parse_header_line(&line)?;
+            headers.insert(name, value);
+        }
+
+        reader.head_complete();
+
+        Ok(Request { method, target, version, headers, body: None })
+    }
+
+    /// How the end of the body is found, from the head.
+    pub fn framing(&self) -> Result<Framing, ParseError>
+    {
+        if let Some(encoding) = self.headers.get("Transfer-Encoding")
+        {
+            // Peers would disagree on where the body ends (RFC 9112, 6.3)
+            if self.headers.contains("Content-Length")
+            {
+                return Err(ParseError::ConflictingLength);
+            }
+
+            if !encoding.eq_ignore_ascii_case("chunked")
+            {
+                return Err(ParseError::UnsupportedTransferEncoding(encoding.to_string()));
+            }
+
+            return Ok(Framing::Chunked);
+        }
+
+        // Digits only, parse() would take "+5" as well (RFC 9110, 8.6)
+        let length = match self.headers.get("Content-Length")
+        {
+            Some(value) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => match value.parse::<u64>()
+            {
+                Ok(length) => length,
+                Err(_) => return Err(ParseError::PayloadTooLarge),
+            },
+            Some(_) => return Err(ParseError::InvalidContentLength),
+            None => return Ok(Framing::Length(0)),
+        };
+
+        if length > MAX_BODY_SIZE
+        {
+            return Err(ParseError::PayloadTooLarge);
+        }
+
+        Ok(Framing::Length(length))
+    }
+
+    /// Request target without the query string.
+    pub fn path(&self) -> &str
+    {
+        match self.target.find('?')
+        {
+            Some(offset) => &self.target[..offset],
+            None => &self.target,
+        }
+    }
+
+    pub fn query(&self) -> Option<&str>
+    {
+        self.target.find('?').map(|offset| &self.target[offset + 1..])
+    }
+
+    pub fn referer(&self) -> Option<&str>
+    {
+        self.headers.get("Referer")
+    }
+
+    /// Whether the client wants the connection to stay open after this request.
+    ///
+    /// HTTP/1.1 connections are persistent unless `Connection: close` is sent,
+    /// HTTP/1.0 ones only with an explicit `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool
+    {
+        let has_option = |option: &str| match self.headers.get("Connection")
+        {
+            Some(value) => value.split(',').any(|token| token.trim().eq_ignore_ascii_case(option)),
+            None => false,
+        };
+
+        match self.version
+        {
+            Version::Http11 => !has_option("close"),
+            Version::Http10 => has_option("keep-alive"),
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum ParseError
+{
+    /// The peer closed the connection before sending anything.
+    ConnectionClosed,
+    /// The peer closed the connection in the middle of a request.
+    UnexpectedEof,
+    /// Nothing was received before the read timeout.
+    Idle,
+    /// The read timeout expired in the middle of a request.
+    Timeout,
+    Io(io::Error),
+    InvalidRequestLine(String),
+    UnknownMethod(String),
+    UnsupportedVersion(String),
+    RequestLineTooLong,
+    InvalidHeader(String),
+    HeadersTooLarge,
+    TooManyHeaders,
+    InvalidContentLength,
+    /// The chunked body does not follow the chunk syntax.
+    InvalidChunk,
+    /// Both `Transfer-Encoding` and `Content-Length` are set.
+    ConflictingLength,
+    UnsupportedTransferEncoding(String),
+    PayloadTooLarge,
+}
+
+impl ParseError
+{
+    /// Status to answer with, `None` when the connection should just be closed.
+    pub fn status(&self) -> Option<StatusCode>
+    {
+        match self
+        {
+            ParseError::ConnectionClosed | ParseError::UnexpectedEof | ParseError::Idle | ParseError::Io(_) => None,
+            ParseError::Timeout => Some(StatusCode::RequestTimeout),
+            ParseError::InvalidRequestLine(_) | ParseError::InvalidHeader(_) | ParseError::InvalidContentLength | ParseError::InvalidChunk
+            | ParseError::ConflictingLength => Some(StatusCode::BadRequest),
+            ParseError::UnknownMethod(_) | ParseError::UnsupportedTransferEncoding(_) => Some(StatusCode::NotImplemented),
+            ParseError::UnsupportedVersion(_) => Some(StatusCode::HttpVersionNotSupported),
+            ParseError::RequestLineTooLong => Some(StatusCode::UriTooLong),
+            ParseError::HeadersTooLarge | ParseError::TooManyHeaders => Some(StatusCode::RequestHeaderFieldsTooLarge),
+            ParseError::PayloadTooLarge => Some(StatusCode::PayloadTooLarge),
+        }
+    }
+
+    /// Whether the client sent something that is not a valid request, as
+    /// opposed to going quiet or away.
+    pub fn is_malformed(&self) -> bool
+    {
+        !matches!(self, ParseError::ConnectionClosed | ParseError::UnexpectedEof | ParseError::Idle | ParseError::Timeout | ParseError::Io(_))
+    }
+}
+
+impl fmt::Display for ParseError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            ParseError::ConnectionClosed => write!(f, "connection closed by peer"),
+            ParseError::UnexpectedEof => write!(f, "connection closed in the middle of a request"),
+            ParseError::Idle => write!(f, "connection is idle"),
+            ParseError::Timeout => write!(f, "timed out in the middle of a request"),
+            ParseError::Io(e) => write!(f, "failed to read request: {}", e),
+            ParseError::InvalidRequestLine(line) => write!(f, "invalid request line \"{}\"", line),
+            ParseError::UnknownMethod(method) => write!(f, "unknown method \"{}\"", method),
+            ParseError::UnsupportedVersion(version) => write!(f, "unsupported protocol version \"{}\"", version),
+            ParseError::RequestLineTooLong => write!(f, "request line is too long"),
+            ParseError::InvalidHeader(line) => write!(f, "invalid header line \"{}\"", line),
+            ParseError::HeadersTooLarge => write!(f, "header section is too large"),
+            ParseError::TooManyHeaders => write!(f, "too many header lines"),
+            ParseError::InvalidContentLength => write!(f, "invalid Content-Length header"),
+            ParseError::InvalidChunk => write!(f, "malformed chunked body"),
+            ParseError::ConflictingLength => write!(f, "both Transfer-Encoding and Content-Length headers"),
+            ParseError::UnsupportedTransferEncoding(encoding) => write!(f, "unsupported transfer encoding \"{}\"", encoding),
+            ParseError::PayloadTooLarge => write!(f, "request body is larger than {} bytes", MAX_BODY_SIZE),
+        }
+    }
+}
+
+impl From<io::Error> for ParseError
+{
+    fn from(e: io::Error) -> ParseError
+    {
+        match e.kind()
+        {
+            io::ErrorKind::UnexpectedEof => ParseError::UnexpectedEof,
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ParseError::Timeout,
+            _ => ParseError::Io(e),
+        }
+    }
+}
+
+
+/// Read a single CRLF (or bare LF) terminated line.
+///
+/// Returns `None` on a clean EOF before any byte was read. Lines longer than
+/// `limit` bytes, line ending excluded, fail with `too_long` without being
+/// buffered whole.
+fn read_line<R: BufRead>(reader: &mut R, limit: usize, too_long: ParseError) -> Result<Option<String>, ParseError>
+{
+    let mut buf = Vec::new();
+    let read = match reader.by_ref().take(limit as u64 + 2).read_until(b'\n', &mut buf)
+    {
+        Ok(read) => read,
+        Err(e) => return match ParseError::from(e)
+        {
+            ParseError::Timeout if buf.is_empty() => Err(ParseError::Idle),
+            e => Err(e),
+        },
+    };
+
+    if read == 0
+    {
+        return Ok(None);
+    }
+
+    if buf.last() != Some(&b'\n')
+    {
+        if read == limit + 2
+        {
+            return Err(too_long);
+        }
+        return Err(ParseError::UnexpectedEof);
+    }
+
+    buf.pop();
+    if buf.last() == Some(&b'\r')
+    {
+        buf.pop();
+    }
+
+    if buf.len() > limit
+    {
+        return Err(too_long);
+    }
+
+    match String::from_utf8(buf)
+    {
+        Ok(line) => Ok(Some(line)),
+        Err(e) => Err(ParseError::InvalidHeader(String::from_utf8_lossy(e.as_bytes()).to_string())),
+    }
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, String, Version), ParseError>
+{
+    let mut parts = line.split(' ');
+
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next())
+    {
+        (Some(method), Some(target), Some(version), None) if !method.is_empty() && !target.is_empty() =>
+        {
+            (method, target, version)
+        },
+        _ => return Err(ParseError::InvalidRequestLine(line.to_string())),
+    };
+
+    let method = match Method::parse(method)
+    {
+        Some(method) => method,
+        None => return Err(ParseError::UnknownMethod(method.to_string())),
+    };
+
+    let version = match version
+    {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ if version.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion(version.to_string())),
+        _ => return Err(ParseError::InvalidRequestLine(line.to_string())),
+    };
+
+    if !(target.starts_with('/') || target == "*" || target.contains("://"))
+    {
+        return Err(ParseError::InvalidRequestLine(line.to_string()));
+    }
+
+    Ok((method, target.to_string(), version))
+}
+
+fn parse_header_line(line: &str) -> Result<(&str, &str), ParseError>
+{
+    // Obsolete line folding is rejected (RFC 9112, 5.2)
+    if line.starts_with(' ') || line.starts_with('\t')
+    {
+        return Err(ParseError::InvalidHeader(line.to_string()));
+    }
+
+    let (name, value) = match line.split_once(':')
+    {
+        Some(result) => result,
+        None => return Err(ParseError::InvalidHeader(line.to_string())),
+    };
+
+    if name.is_empty() || !name.bytes().all(is_token_char)
+    {
+        return Err(ParseError::InvalidHeader(line.to_string()));
+    }
+
+    let value = value.trim_matches(|c| c == ' ' || c == '\t');
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t')
+    {
+        return Err(ParseError::InvalidHeader(line.to_string()));
+    }
+
+    Ok((name, value))
+}
+
+fn is_token_char(b: u8) -> bool
+{
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Read the body that follows a head, as framed by `framing`.
+pub fn read_body<R: BufRead>(reader: &mut R, framing: Framing) -> Result<Option<Vec<u8>>, ParseError>
+{
+    let mut body = Vec::new();
+    match framing
+    {
+        Framing::Length(0) => return Ok(None),
+        Framing::Length(length) =>
+        {
+            reader.take(length).read_to_end(&mut body)?;
+            if (body.len() as u64) < length
+            {
+                return Err(ParseError::UnexpectedEof);
+            }
+        },
+        Framing::Chunked =>
+        {
+            let mut source = Watched { inner: reader, ended: None };
+            let decoded = Decoder::new(&mut source).take(MAX_BODY_SIZE + 1).read_to_end(&mut body);
+
+            // The decoder reports a timeout or a closed connection as
+            // malformed chunks, and a body cut inside a chunk as complete
+            if let Some(kind) = source.ended
+            {
+                return Err(io::Error::from(kind).into());
+            }
+            if decoded.is_err()
+            {
+                return Err(ParseError::InvalidChunk);
+            }
+            if body.len() as u64 > MAX_BODY_SIZE
+            {
+                return Err(ParseError::PayloadTooLarge);
+            }
+        },
+    }
+
+    Ok(Some(body))
+}
+
+/// Remembers why reading stopped short, an error or the end of the stream.
+struct Watched<R>
+{
+    inner: R,
+    ended: Option<io::ErrorKind>,
+}
+
+impl<R: Read> Read for Watched<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        match self.inner.read(buf)
+        {
+            Ok(0) if !buf.is_empty() =>
+            {
+                self.ended = Some(io::ErrorKind::UnexpectedEof);
+                Ok(0)
+            },
+            Ok(read) => Ok(read),
+            Err(e) =>
+            {
+                self.ended = Some(e.kind());
+                Err(e)
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    impl Source for &[u8] {}
+
+    const LIMITS: Limits = Limits { max_request_line: 64, max_header_bytes: 128, max_headers: 4 };
+
+    fn parse(raw: &str) -> Result<Request, ParseError>
+    {
+        Request::read_from(&mut raw.as_bytes(), &LIMITS)
+    }
+
+    fn status(raw: &str) -> Option<StatusCode>
+    {
+        parse(raw).expect_err("the request is refused").status()
+    }
+
+    #[test]
+    fn parses_a_request()
+    {
+        let request = parse("\r\nGET /a/b.html?x=1 HTTP/1.1\r\nHost: example\r\nAccept: a\r\naccept: b\r\n\r\n").unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.path(), "/a/b.html");
+        assert_eq!(request.query(), Some("x=1"));
+        assert_eq!(request.headers.get("HOST"), Some("example"));
+        assert_eq!(request.headers.get("Accept"), Some("a, b"));
+        assert!(request.body.is_none());
+        assert!(request.keep_alive());
+    }
+
+    #[test]
+    fn bare_line_feeds_end_lines()
+    {
+        let request = parse("GET / HTTP/1.0\nConnection: keep-alive\n\n").unwrap();
+        assert!(request.keep_alive());
+    }
+
+    #[test]
+    fn malformed_request_lines_are_refused()
+    {
+        assert_eq!(status("GET /\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("GET  / HTTP/1.1\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("GET / HTTP/1.1 extra\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("GET relative HTTP/1.1\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("GET / FTP/1.0\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("BREW / HTTP/1.1\r\n\r\n"), Some(StatusCode::NotImplemented));
+        assert_eq!(status("GET / HTTP/2.0\r\n\r\n"), Some(StatusCode::HttpVersionNotSupported));
+        assert_eq!(status(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64))), Some(StatusCode::UriTooLong));
+    }
+
+    #[test]
+    fn malformed_headers_are_refused()
+    {
+        assert_eq!(status("GET / HTTP/1.1\r\nNo colon\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("GET / HTTP/1.1\r\nBad name: x\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("GET / HTTP/1.1\r\n: x\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("GET / HTTP/1.1\r\nA: b\r\n folded\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("GET / HTTP/1.1\r\nA: b\x01c\r\n\r\n"), Some(StatusCode::BadRequest));
+    }
+
+    #[test]
+    fn oversized_heads_are_refused()
+    {
+        let many = "A: b\r\n".repeat(5);
+        assert_eq!(status(&format!("GET / HTTP/1.1\r\n{}\r\n", many)), Some(StatusCode::RequestHeaderFieldsTooLarge));
+
+        let large = format!("A: {}\r\n", "b".repeat(128));
+        assert_eq!(status(&format!("GET / HTTP/1.1\r\n{}\r\n", large)), Some(StatusCode::RequestHeaderFieldsTooLarge));
+    }
+
+    #[test]
+    fn truncated_requests_are_not_answered()
+    {
+        assert!(matches!(parse(""), Err(ParseError::ConnectionClosed)));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: x\r\n"), Err(ParseError::UnexpectedEof)));
+        assert!(matches!(parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"), Err(ParseError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn bodies_are_read_by_length_or_chunks()
+    {
+        let request = parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET").unwrap();
+        assert_eq!(request.body.as_deref(), Some(&b"hello"[..]));
+
+        let request = parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n").unwrap();
+        assert_eq!(request.body.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn malformed_bodies_are_refused()
+    {
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello"), Some(StatusCode::BadRequest));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 0x5\r\n\r\nhello"), Some(StatusCode::BadRequest));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length:\r\n\r\n"), Some(StatusCode::BadRequest));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n"), Some(StatusCode::PayloadTooLarge));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab"), Some(StatusCode::BadRequest));
+        assert_eq!(status("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"), Some(StatusCode::NotImplemented));
+        assert_eq!(status(&format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1)), Some(StatusCode::PayloadTooLarge));
+    }
+
+    #[test]
+    fn malformed_chunks_are_refused()
+    {
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for body in ["zz\r\nhello\r\n0\r\n\r\n", "5\r\nhelloXX0\r\n\r\n", "5\nhello\r\n0\r\n\r\n", "0\r\nX\r\n"]
+        {
+            let raw = format!("{}{}", chunked, body);
+            assert!(matches!(parse(&raw), Err(ParseError::InvalidChunk)), "{:?}", body);
+            assert_eq!(status(&raw), Some(StatusCode::BadRequest));
+        }
+
+        // Cut short, nobody is left to answer
+        assert!(matches!(parse(&format!("{}5\r\nhel", chunked)), Err(ParseError::UnexpectedEof)));
+        assert!(matches!(parse(&format!("{}5\r\nhello", chunked)), Err(ParseError::UnexpectedEof)));
+        assert!(matches!(parse(&format!("{}5\r\nhello\r\n", chunked)), Err(ParseError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn transfer_encoding_with_content_length_is_refused()
+    {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::ConflictingLength)));
+        assert_eq!(status(raw), Some(StatusCode::BadRequest));
+    }
+}
@@ -0,0 +1,178 @@
+use std::
+{
+    fs::File,
+    io::{self, Read, Write, ErrorKind},
+    path::Path,
+};
+
+use crate::fileutils::{get_filetype, resolve_path, SymlinkPolicy};
+use crate::status::StatusCode;
+
+
+pub struct Response
+{
+    pub status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Body,
+    head_only: bool,
+}
+
+enum Body
+{
+    Bytes(Vec<u8>),
+    /// Read from the file while it is sent, so that big files are never
+    /// held in memory.
+    File { file: File, length: u64 },
+}
+
+impl Body
+{
+    fn len(&self) -> u64
+    {
+        match self
+        {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File { length, .. } => *length,
+        }
+    }
+}
+
+/// The rest of a file body, see `Response::into_bytes`.
+pub type FileBody = io::Take<File>;
+
+impl Response
+{
+    pub fn new(status: StatusCode) -> Response
+    {
+        Response { status, headers: Vec::new(), body: Body::Bytes(Vec::new()), head_only: false }
+    }
+
+    /// Build a response whose body is the content of `filename`.
+    ///
+    /// The file is sent as raw bytes, read in chunks as it goes out.
+    /// `Content-Type` is guessed from the extension.
+    pub fn from_file(status: StatusCode, filename: &Path) -> Result<Response, String>
+    {
+        let opened = File::open(filename).and_then(|file| Ok((file.metadata()?.len(), file)));
+        let (length, file) = match opened
+        {
+            Ok(opened) => opened,
+            Err(error) => match error.kind()
+            {
+                ErrorKind::NotFound => return Err(format!("The static file \"{}\" could not be found", filename.display())),
+                _ => return Err(format!("Cannot open the file \"{}\".", filename.display())),
+            }
+        };
+
+        let response = Response { body: Body::File { file, length }, ..Response::new(status) };
+        Ok(response.header("Content-Type", get_filetype(&filename.to_string_lossy())))
+    }
+
+    /// Build an error response for `status`.
+    ///
+    /// A custom page named after the code (`404.html`, `500.html`, ...) is looked up
+    /// in `root`, a built-in page is used when there is none.
+    pub fn error(status: StatusCode, root: &str) -> Response
+    {
+        let page_name = format!("/{}.html", status.code());
+
+        if let Ok(filename) = resolve_path(root, &page_name, "", SymlinkPolicy::WithinRoot)
+        {
+            if let Ok(response) = Response::from_file(status, &filename)
+            {
+                return response;
+            }
+        }
+
+        Response::fallback(status)
+    }
+
+    /// Built-in error page, no file system access involved.
+    pub fn fallback(status: StatusCode) -> Response
+    {
+        let body = format!("<!DOCTYPE html>\n<html>\n  <head><title>{status}</title></head>\n  <body><h1>{status}</h1></body>\n</html>\n");
+        Response::new(status).body(body.into_bytes(), "text/html; charset=utf-8")
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response
+    {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>, content_type: &str) -> Response
+    {
+        self.body = Body::Bytes(body);
+        self.header("Content-Type", content_type)
+    }
+
+    /// Send only the head, as an answer to `HEAD` requests.
+    pub fn head_only(mut self) -> Response
+    {
+        self.head_only = true;
+        self
+    }
+
+    /// Serialize the status line and headers, `Content-Length` included.
+    pub fn head_bytes(&self) -> Vec<u8>
+    {
+        let mut head = format!("{}\r\nContent-Length: {}\r\n", self.status.status_line(), self.body.len());
+        for (name, value) in self.headers.iter()
+        {
+            head.push_str(name);
+            head.push_str(": ");
+            head.push_str(value);
+            head.push_str("\r\n");
+        }
+        head.push_str("\r\n");
+
+        head.into_bytes()
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()>
+    {
+        stream.write_all(&self.head_bytes())?;
+        if !self.head_only
+        {
+            match &self.body
+            {
+                Body::Bytes(bytes) => stream.write_all(bytes)?,
+                Body::File { file, length } =>
+                {
+                    if io::copy(&mut file.take(*length), stream)? < *length
+                    {
+                        return Err(shrunk());
+                    }
+                },
+            }
+        }
+        stream.flush()
+    }
+
+    /// Serialize the response, except for a file body which is handed back
+    /// to be read and sent after the bytes.
+    pub fn into_bytes(self) -> (Vec<u8>, Option<FileBody>)
+    {
+        let mut bytes = self.head_bytes();
+        if self.head_only
+        {
+            return (bytes, None);
+        }
+
+        match self.body
+        {
+            Body::Bytes(body) =>
+            {
+                bytes.extend_from_slice(&body);
+                (bytes, None)
+            },
+            Body::File { file, length } => (bytes, Some(file.take(length))),
+        }
+    }
+}
+
+/// The file ended before the `Content-Length` announced for it.
+pub fn shrunk() -> io::Error
+{
+    io::Error::new(ErrorKind::UnexpectedEof, "the file shrank while being sent")
+}
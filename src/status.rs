@@ -0,0 +1,85 @@
+use std::fmt;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode
+{
+    Ok,
+    BadRequest,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    UriTooLong,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
+}
+
+impl StatusCode
+{
+    pub fn code(&self) -> u16
+    {
+        match self
+        {
+            StatusCode::Ok => 200,
+            StatusCode::BadRequest => 400,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::UriTooLong => 414,
+            StatusCode::TooManyRequests => 429,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::HttpVersionNotSupported => 505,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str
+    {
+        match self
+        {
+            StatusCode::Ok => "OK",
+            StatusCode::BadRequest => "BAD REQUEST",
+            StatusCode::Forbidden => "FORBIDDEN",
+            StatusCode::NotFound => "NOT FOUND",
+            StatusCode::MethodNotAllowed => "METHOD NOT ALLOWED",
+            StatusCode::RequestTimeout => "REQUEST TIMEOUT",
+            StatusCode::PayloadTooLarge => "PAYLOAD TOO LARGE",
+            StatusCode::UriTooLong => "URI TOO LONG",
+            StatusCode::TooManyRequests => "TOO MANY REQUESTS",
+            StatusCode::RequestHeaderFieldsTooLarge => "REQUEST HEADER FIELDS TOO LARGE",
+            StatusCode::InternalServerError => "INTERNAL SERVER ERROR",
+            StatusCode::NotImplemented => "NOT IMPLEMENTED",
+            StatusCode::ServiceUnavailable => "SERVICE UNAVAILABLE",
+            StatusCode::HttpVersionNotSupported => "HTTP VERSION NOT SUPPORTED",
+        }
+    }
+
+    pub fn is_error(&self) -> bool
+    {
+        self.code() >= 400
+    }
+
+    /// Full status line, e.g. `HTTP/1.1 404 NOT FOUND`.
+    pub fn status_line(&self) -> String
+    {
+        format!("HTTP/1.1 {}", self)
+    }
+}
+
+impl fmt::Display for StatusCode
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
@@ -0,0 +1,325 @@
+//! Outgoing bandwidth caps.
+//!
+//! Responses are sent in chunks paced by token buckets: one for the response
+//! itself (`per_connection`, or the rate of the `[bandwidth.NAME]` rule
+//! matching its path), one shared by the connections of a client and one for
+//! the whole server. A chunk goes out once every bucket has the bytes for it.
+
+use std::
+{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::cidr;
+use crate::config::BandwidthConfig;
+use crate::fileutils::{normalize_path, path_matches};
+use crate::ratelimit::{Capacity, Table};
+
+
+/// Most bytes written at once by a paced response.
+const CHUNK: usize = 16 * 1024;
+
+/// Clients with a bucket, past it the least recently served is forgotten.
+const MAX_TRACKED: usize = 4096;
+
+/// Buckets unused for that long are forgotten, they are full again by then.
+const IDLE: Duration = Duration::from_secs(60);
+
+/// Holds up to one second worth of bytes.
+#[derive(Debug, Clone, Copy)]
+struct Bucket
+{
+    /// Bytes per second.
+    rate: u64,
+    /// Below zero when more was sent than allowed, the debt is paid first.
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket
+{
+    fn new(rate: u64, now: Instant) -> Bucket
+    {
+        Bucket { rate, tokens: rate as f64, last: now }
+    }
+
+    fn refill(&mut self, now: Instant)
+    {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+
+    /// Time until `wanted` bytes may be sent, zero when they may now.
+    ///
+    /// A bucket smaller than `wanted` only waits until it is full.
+    fn wait(&self, wanted: usize) -> Duration
+    {
+        let wanted = (wanted as f64).min(self.rate as f64);
+        match wanted - self.tokens
+        {
+            missing if missing > 0.0 => Duration::from_secs_f64(missing / self.rate as f64),
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn available(&self) -> usize
+    {
+        self.tokens.max(0.0) as usize
+    }
+}
+
+struct State
+{
+    settings: BandwidthConfig,
+    ipv6_prefix: u8,
+    total: Option<Bucket>,
+    clients: Table<Bucket>,
+}
+
+impl State
+{
+    /// The shared buckets of `key`, refilled, created when missing.
+    fn buckets(&mut self, key: IpAddr, now: Instant) -> Vec<&mut Bucket>
+    {
+        let per_client = self.settings.per_client;
+        let client = match per_client
+        {
+            0 => None,
+            _ => Some(self.clients.touch(key, now, || Bucket::new(per_client, now))),
+        };
+
+        let mut buckets: Vec<&mut Bucket> = client.into_iter().chain(self.total.as_mut()).collect();
+        for bucket in buckets.iter_mut()
+        {
+            bucket.refill(now);
+        }
+        buckets
+    }
+}
+
+pub struct Bandwidth
+{
+    state: Mutex<State>,
+}
+
+impl Bandwidth
+{
+    /// `ipv6_prefix` groups IPv6 clients the way the rate limiter does.
+    pub fn new(settings: &BandwidthConfig, ipv6_prefix: u8) -> Bandwidth
+    {
+        let state = State
+        {
+            settings: settings.clone(),
+            ipv6_prefix,
+            total: total_bucket(settings),
+            clients: Table::new(Capacity { max_clients: MAX_TRACKED, idle: IDLE }),
+        };
+        Bandwidth { state: Mutex::new(state) }
+    }
+
+    /// Apply new caps, the buckets start over full.
+    pub fn reconfigure(&self, settings: &BandwidthConfig, ipv6_prefix: u8)
+    {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        state.settings = settings.clone();
+        state.ipv6_prefix = ipv6_prefix;
+        state.total = total_bucket(settings);
+        state.clients.clear();
+    }
+
+    /// Pacer for a response to `client` for `path`, `None` when nothing caps
+    /// it.
+    pub fn pacer(self: &Arc<Self>, client: IpAddr, path: &str) -> Option<Pacer>
+    {
+        let state = self.state.lock().expect("Mutex poisoned");
+        let settings = &state.settings;
+
+        // "/%64ownloads/" is "/downloads/" as well
+        let path = normalize_path(path).unwrap_or_else(|| path.to_string());
+        let rate = match settings.rules.iter().find(|rule| path_matches(&rule.path, &path))
+        {
+            Some(rule) => rule.per_connection,
+            None => settings.per_connection,
+        };
+
+        if rate == 0 && settings.per_client == 0 && settings.total == 0
+        {
+            return None;
+        }
+
+        let now = Instant::now();
+        Some(Pacer
+        {
+            bandwidth: Arc::clone(self),
+            key: cidr::client_key(client, state.ipv6_prefix),
+            own: (rate > 0).then(|| Bucket::new(rate, now)),
+        })
+    }
+}
+
+fn total_bucket(settings: &BandwidthConfig) -> Option<Bucket>
+{
+    (settings.total > 0).then(|| Bucket::new(settings.total, Instant::now()))
+}
+
+/// Paces one response.
+pub struct Pacer
+{
+    bandwidth: Arc<Bandwidth>,
+    key: IpAddr,
+    own: Option<Bucket>,
+}
+
+impl Pacer
+{
+    /// How many of the `wanted` bytes may be sent now, or how long to wait
+    /// before asking again.
+    pub fn allowance(&mut self, wanted: usize) -> Result<usize, Duration>
+    {
+        let wanted = wanted.min(CHUNK);
+        let now = Instant::now();
+        let mut state = self.bandwidth.state.lock().expect("Mutex poisoned");
+
+        if let Some(own) = self.own.as_mut()
+        {
+            own.refill(now);
+        }
+
+        let mut wait = Duration::ZERO;
+        let mut allowed = wanted;
+        for bucket in self.own.iter().chain(state.buckets(self.key, now).into_iter().map(|bucket| &*bucket))
+        {
+            wait = wait.max(bucket.wait(wanted));
+            allowed = allowed.min(bucket.available());
+        }
+
+        match wait.is_zero() && allowed > 0
+        {
+            true => Ok(allowed),
+            false => Err(wait.max(Duration::from_millis(1))),
+        }
+    }
+
+    /// Take `sent` bytes out of the buckets.
+    pub fn spend(&mut self, sent: usize)
+    {
+        let now = Instant::now();
+        let mut state = self.bandwidth.state.lock().expect("Mutex poisoned");
+        for bucket in self.own.iter_mut().chain(state.buckets(self.key, now))
+        {
+            bucket.tokens -= sent as f64;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::config::BandwidthRule;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+
+    fn bandwidth(per_connection: u64, per_client: u64, total: u64, rules: &[(&str, u64)]) -> Arc<Bandwidth>
+    {
+        let rules = rules.iter().map(|(path, per_connection)| BandwidthRule { path: path.to_string(), per_connection: *per_connection }).collect();
+        let settings = BandwidthConfig { per_connection, per_client, total, rules };
+        Arc::new(Bandwidth::new(&settings, 128))
+    }
+
+    fn per_client(rate: u64) -> Arc<Bandwidth>
+    {
+        bandwidth(0, rate, 0, &[])
+    }
+
+    /// Rate of the bucket of the response itself.
+    fn own_rate(pacer: &Pacer) -> Option<u64>
+    {
+        pacer.own.map(|bucket| bucket.rate)
+    }
+
+    #[test]
+    fn clients_share_their_bucket()
+    {
+        let bandwidth = per_client(CHUNK as u64);
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let mut first = bandwidth.pacer(client, "/").unwrap();
+        assert_eq!(first.allowance(CHUNK), Ok(CHUNK));
+        first.spend(CHUNK);
+
+        let mut second = bandwidth.pacer(client, "/").unwrap();
+        assert!(second.allowance(CHUNK).is_err());
+    }
+
+    #[test]
+    fn client_buckets_are_bounded()
+    {
+        let bandwidth = per_client(1024);
+        for address in 0..MAX_TRACKED as u32 * 2
+        {
+            let mut pacer = bandwidth.pacer(IpAddr::V4(Ipv4Addr::from(address)), "/").unwrap();
+            pacer.spend(1024);
+        }
+
+        assert_eq!(bandwidth.state.lock().unwrap().clients.tracked(), MAX_TRACKED);
+    }
+
+    #[test]
+    fn the_first_matching_rule_replaces_the_connection_rate()
+    {
+        let bandwidth = bandwidth(1000, 0, 0, &[("/downloads/big", 10), ("/downloads", 100), ("/free", 0)]);
+
+        assert_eq!(own_rate(&bandwidth.pacer(CLIENT, "/downloads/big/file.iso").unwrap()), Some(10));
+        assert_eq!(own_rate(&bandwidth.pacer(CLIENT, "/downloads/small.zip").unwrap()), Some(100));
+        assert_eq!(own_rate(&bandwidth.pacer(CLIENT, "/%64ownloads/small.zip").unwrap()), Some(100));
+        assert_eq!(own_rate(&bandwidth.pacer(CLIENT, "/downloadsx").unwrap()), Some(1000));
+        assert_eq!(own_rate(&bandwidth.pacer(CLIENT, "/index.html").unwrap()), Some(1000));
+
+        // A rule of 0 lifts the cap, nothing else is capped either
+        assert!(bandwidth.pacer(CLIENT, "/free/file").is_none());
+    }
+
+    #[test]
+    fn each_response_has_its_own_bucket()
+    {
+        let bandwidth = bandwidth(CHUNK as u64, 0, 0, &[]);
+
+        let mut first = bandwidth.pacer(CLIENT, "/").unwrap();
+        assert_eq!(first.allowance(CHUNK * 2), Ok(CHUNK));
+        first.spend(CHUNK);
+        let wait = first.allowance(CHUNK).unwrap_err();
+        assert!(wait > Duration::from_millis(500) && wait <= Duration::from_secs(1));
+
+        // Another response of the same client is not slowed down
+        let mut second = bandwidth.pacer(CLIENT, "/").unwrap();
+        assert_eq!(second.allowance(CHUNK), Ok(CHUNK));
+    }
+
+    #[test]
+    fn the_total_bucket_is_shared_by_every_client()
+    {
+        let bandwidth = bandwidth(0, 0, CHUNK as u64, &[]);
+
+        let mut first = bandwidth.pacer(CLIENT, "/").unwrap();
+        assert_eq!(first.allowance(CHUNK), Ok(CHUNK));
+        first.spend(CHUNK / 2);
+
+        let mut second = bandwidth.pacer(OTHER, "/").unwrap();
+        assert_eq!(second.allowance(CHUNK / 4), Ok(CHUNK / 4));
+        assert!(second.allowance(CHUNK).is_err());
+
+        second.spend(CHUNK / 2);
+        assert!(first.allowance(1).is_err());
+
+        // Reconfiguring starts the buckets over
+        bandwidth.reconfigure(&BandwidthConfig { per_connection: 0, per_client: 0, total: CHUNK as u64, rules: Vec::new() }, 128);
+        assert_eq!(first.allowance(CHUNK), Ok(CHUNK));
+    }
+}
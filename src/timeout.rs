@@ -0,0 +1,342 @@
+//! Deadlines for blocking sockets.
+//!
+//! A socket timeout only bounds a single read or write, so a client sending
+//! one byte at a time never hits it. These wrappers shrink the timeout before
+//! every call, a whole request head, body or response has to fit in its
+//! deadline.
+
+use std::
+{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::config::TimeoutConfig;
+use crate::request::Source;
+use crate::throttle::Pacer;
+
+
+enum Stage
+{
+    /// Waiting for the first byte of a request.
+    Idle,
+    Head(Instant),
+    Body(Instant),
+}
+
+/// Buffered reader over a connection, pipelined requests stay in its buffer.
+pub struct TimedReader<'a>
+{
+    inner: BufReader<&'a TcpStream>,
+    stage: Stage,
+    idle_timeout: Duration,
+    header_timeout: Duration,
+    body_timeout: Duration,
+}
+
+impl<'a> TimedReader<'a>
+{
+    pub fn new(stream: &'a TcpStream, timeouts: &TimeoutConfig) -> TimedReader<'a>
+    {
+        TimedReader
+        {
+            inner: BufReader::new(stream),
+            stage: Stage::Idle,
+            idle_timeout: timeouts.keep_alive,
+            header_timeout: timeouts.header,
+            body_timeout: timeouts.body,
+        }
+    }
+
+    /// Wait for a new request, with the timeouts of the current configuration.
+    pub fn next_request(&mut self, timeouts: &TimeoutConfig)
+    {
+        self.stage = Stage::Idle;
+        self.idle_timeout = timeouts.keep_alive;
+        self.header_timeout = timeouts.header;
+        self.body_timeout = timeouts.body;
+    }
+}
+
+impl Read for TimedReader<'_>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for TimedReader<'_>
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]>
+    {
+        if self.inner.buffer().is_empty()
+        {
+            let timeout = match self.stage
+            {
+                Stage::Idle => self.idle_timeout,
+                Stage::Head(deadline) | Stage::Body(deadline) => remaining(deadline)?,
+            };
+            self.inner.get_ref().set_read_timeout(Some(timeout))?;
+        }
+
+        let idle = matches!(self.stage, Stage::Idle);
+        let buf = self.inner.fill_buf()?;
+        if idle && !buf.is_empty()
+        {
+            self.stage = Stage::Head(Instant::now() + self.header_timeout);
+        }
+        Ok(buf)
+    }
+
+    fn consume(&mut self, amount: usize)
+    {
+        self.inner.consume(amount);
+    }
+}
+
+impl Source for TimedReader<'_>
+{
+    fn head_complete(&mut self)
+    {
+        self.stage = Stage::Body(Instant::now() + self.body_timeout);
+    }
+}
+
+
+/// Writer that fails once `timeout` has passed since it was created.
+///
+/// With a pacer, writes wait for bandwidth first. That wait does not count
+/// against the timeout.
+pub struct TimedWriter<'a>
+{
+    stream: &'a TcpStream,
+    deadline: Instant,
+    pacer: Option<Pacer>,
+}
+
+impl<'a> TimedWriter<'a>
+{
+    pub fn new(stream: &'a TcpStream, timeout: Duration) -> TimedWriter<'a>
+    {
+        TimedWriter { stream, deadline: Instant::now() + timeout, pacer: None }
+    }
+
+    pub fn paced(mut self, pacer: Option<Pacer>) -> TimedWriter<'a>
+    {
+        self.pacer = pacer;
+        self
+    }
+}
+
+impl Write for TimedWriter<'_>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        let mut allowed = buf.len();
+        if let Some(pacer) = self.pacer.as_mut().filter(|_| !buf.is_empty())
+        {
+            allowed = loop
+            {
+                match pacer.allowance(buf.len())
+                {
+                    Ok(allowed) => break allowed,
+                    Err(wait) =>
+                    {
+                        thread::sleep(wait);
+                        self.deadline += wait;
+                    }
+                }
+            };
+        }
+
+        self.stream.set_write_timeout(Some(remaining(self.deadline)?))?;
+        let mut stream = self.stream;
+        let written = stream.write(&buf[..allowed])?;
+
+        if let Some(pacer) = self.pacer.as_mut()
+        {
+            pacer.spend(written);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        let mut stream = self.stream;
+        stream.flush()
+    }
+}
+
+/// Whether `e` comes from an expired socket timeout.
+pub fn is_timeout(e: &io::Error) -> bool
+{
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn remaining(deadline: Instant) -> io::Result<Duration>
+{
+    match deadline.checked_duration_since(Instant::now())
+    {
+        Some(left) if !left.is_zero() => Ok(left),
+        _ => Err(ErrorKind::TimedOut.into()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use crate::config::BandwidthConfig;
+    use crate::request::{Limits, ParseError, Request};
+    use crate::throttle::Bandwidth;
+
+    const SHORT: Duration = Duration::from_millis(300);
+    const LIMITS: Limits = Limits { max_request_line: 64, max_header_bytes: 128, max_headers: 4 };
+
+    fn timeouts() -> TimeoutConfig
+    {
+        TimeoutConfig { header: SHORT, body: SHORT, write: SHORT, process: SHORT, keep_alive: SHORT, keep_alive_requests: 100 }
+    }
+
+    /// A connected client and server socket.
+    fn pair() -> (TcpStream, TcpStream)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    /// Send `data` a byte at a time, each well within a socket timeout.
+    fn trickle(mut client: TcpStream, data: &'static [u8])
+    {
+        thread::spawn(move ||
+        {
+            for byte in data
+            {
+                if client.write_all(&[*byte]).is_err()
+                {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+    }
+
+    /// Read a request, returns the outcome and how long it took.
+    fn read_request(reader: &mut TimedReader) -> (Result<Request, ParseError>, Duration)
+    {
+        let start = Instant::now();
+        let result = Request::read_from(reader, &LIMITS);
+        (result, start.elapsed())
+    }
+
+    #[test]
+    fn idle_connections_time_out()
+    {
+        let (_client, server) = pair();
+        let mut reader = TimedReader::new(&server, &timeouts());
+
+        let start = Instant::now();
+        let e = reader.fill_buf().unwrap_err();
+        assert!(is_timeout(&e));
+        assert!(start.elapsed() >= SHORT && start.elapsed() < SHORT * 3);
+    }
+
+    #[test]
+    fn heads_sent_a_byte_at_a_time_time_out()
+    {
+        let (client, server) = pair();
+        trickle(client, b"GET / HTTP/1.1\r\nX-Slow: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n");
+        let mut reader = TimedReader::new(&server, &timeouts());
+
+        let (result, elapsed) = read_request(&mut reader);
+        assert!(matches!(result, Err(ParseError::Timeout)));
+        assert!(elapsed >= SHORT && elapsed < SHORT * 3);
+    }
+
+    #[test]
+    fn bodies_have_their_own_deadline()
+    {
+        let (mut client, server) = pair();
+        client.write_all(b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\n").unwrap();
+        trickle(client, b"0123456789012345678901234567890123456789");
+
+        // The head went through at once, the body alone times out
+        let mut reader = TimedReader::new(&server, &TimeoutConfig { header: Duration::from_secs(10), ..timeouts() });
+        let (result, elapsed) = read_request(&mut reader);
+        assert!(matches!(result, Err(ParseError::Timeout)));
+        assert!(elapsed >= SHORT && elapsed < SHORT * 3);
+    }
+
+    #[test]
+    fn a_new_request_starts_over()
+    {
+        let (mut client, server) = pair();
+        let mut reader = TimedReader::new(&server, &timeouts());
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_request(&mut reader).0.is_ok());
+
+        // The previous head deadline is long gone by then
+        thread::sleep(SHORT + Duration::from_millis(100));
+        reader.next_request(&timeouts());
+        client.write_all(b"GET /next HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(read_request(&mut reader).0.unwrap().target, "/next");
+    }
+
+    #[test]
+    fn responses_not_read_in_time_fail()
+    {
+        let (_client, server) = pair();
+        let mut writer = TimedWriter::new(&server, SHORT);
+
+        // The client never reads, the socket buffers fill up
+        let start = Instant::now();
+        let chunk = vec![0u8; 64 * 1024];
+        let e = loop
+        {
+            if let Err(e) = writer.write_all(&chunk)
+            {
+                break e;
+            }
+        };
+        assert!(is_timeout(&e));
+        assert!(start.elapsed() < SHORT * 3);
+    }
+
+    #[test]
+    fn waiting_for_bandwidth_does_not_count()
+    {
+        let (mut client, server) = pair();
+        let reading = thread::spawn(move ||
+        {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).unwrap();
+            received.len()
+        });
+
+        let settings = BandwidthConfig { per_connection: 32 * 1024, per_client: 0, total: 0, rules: Vec::new() };
+        let pacer = Arc::new(Bandwidth::new(&settings, 128)).pacer(server.peer_addr().unwrap().ip(), "/");
+        let mut writer = TimedWriter::new(&server, SHORT).paced(pacer);
+
+        // Twice the rate, half of it has to wait for a whole second
+        let start = Instant::now();
+        writer.write_all(&[0u8; 64 * 1024]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+
+        drop(writer);
+        server.shutdown(std::net::Shutdown::Both).unwrap();
+        assert_eq!(reading.join().unwrap(), 64 * 1024);
+    }
+}